@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+//! On-disk memory of what was negotiated through \<configure\> with each Device, so that
+//! the send buffer doesn't have to be grown with a second round-trip on every connection
+use anyhow::Result;
+use qdl::types::FirehoseConfiguration;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedConfig {
+    protocol_version: Option<u32>,
+    send_buffer_size: usize,
+    xml_buf_size: usize,
+}
+
+/// Negotiated Firehose parameters, keyed by the chip serial number
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ConfigCache {
+    devices: BTreeMap<String, CachedConfig>,
+}
+
+/// Where per-user configuration lives, following the conventions of each platform
+fn user_config_dir() -> Option<PathBuf> {
+    let home = || env::var_os("HOME").map(PathBuf::from);
+
+    if cfg!(target_os = "windows") {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|h| h.join("Library/Application Support"))
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute())
+            .or_else(|| home().map(|h| h.join(".config")))
+    }
+}
+
+fn cache_path() -> Option<PathBuf> {
+    user_config_dir().map(|d| d.join(env!("CARGO_PKG_NAME")).join("configure-cache.json"))
+}
+
+impl ConfigCache {
+    /// Read the cache, starting afresh if it's missing or unreadable
+    pub fn load() -> Self {
+        cache_path()
+            .and_then(|p| fs::read(p).ok())
+            .and_then(|buf| serde_json::from_slice(&buf).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = cache_path() else {
+            return Ok(());
+        };
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        // Don't leave a truncated file behind if several instances race
+        let tmp = path.with_extension(format!("json.{}", std::process::id()));
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, &path)?;
+
+        Ok(())
+    }
+
+    /// Start off with the buffer sizes negotiated last time (if any), so that the Device
+    /// accepts the initial \<configure\> as-is. Returns whether an entry was applied.
+    pub fn apply(&self, chip_sn: u32, cfg: &mut FirehoseConfiguration) -> bool {
+        let Some(entry) = self.devices.get(&format!("{chip_sn:x}")) else {
+            return false;
+        };
+        let send_buffer_size = entry
+            .send_buffer_size
+            .min(cfg.max_payload_size.unwrap_or(usize::MAX));
+        if send_buffer_size == 0 || !send_buffer_size.is_multiple_of(cfg.storage_sector_size) {
+            return false;
+        }
+
+        cfg.send_buffer_size = send_buffer_size;
+        cfg.xml_buf_size = entry.xml_buf_size;
+        true
+    }
+
+    /// Record the outcome of the \<configure\> handshake. An entry left behind by a
+    /// different protocol version is dropped and replaced.
+    pub fn update(&mut self, chip_sn: u32, cfg: &FirehoseConfiguration) -> Result<()> {
+        // A user-imposed cap says nothing about what the Device can do
+        if cfg.max_payload_size.is_some() {
+            return Ok(());
+        }
+
+        let key = format!("{chip_sn:x}");
+        let new = CachedConfig {
+            protocol_version: cfg.protocol_version,
+            send_buffer_size: cfg.send_buffer_size,
+            xml_buf_size: cfg.xml_buf_size,
+        };
+
+        if self.devices.get(&key) == Some(&new) {
+            return Ok(());
+        }
+        if let Some(old) = self.devices.get(&key)
+            && old.protocol_version != new.protocol_version
+            && !cfg.quiet
+        {
+            println!("Protocol version changed, dropping the cached <configure> parameters");
+        }
+
+        self.devices.insert(key, new);
+        self.save()
+    }
+}
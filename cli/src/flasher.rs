@@ -1,33 +1,82 @@
 // SPDX-License-Identifier: BSD-3-Clause
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
 use anyhow::{Result, bail};
-use programfile::parse_program_xml;
-use qdl::firehose_set_bootable;
-use qdl::types::QdlChan;
+use itertools::Itertools;
+use owo_colors::OwoColorize;
+use programfile::{FlashReport, ProgramXmlOptions, parse_program_xml};
+use qdl::types::{FirehoseConfiguration, QdlChan};
+use qdl::{firehose_set_active_slot, firehose_set_bootable};
 
 use std::fs::{self};
+use std::io::{self, BufRead, Read, Write};
 use std::path::Path;
 
 use crate::programfile;
+use crate::zipfile::ZipPackage;
+
+/// Stand-in for a Device, used to plan out the flasher's actions without one attached
+pub(crate) struct DryRunChannel {
+    pub fh_cfg: FirehoseConfiguration,
+}
+
+fn dry_run_violation() -> io::Error {
+    io::Error::other("Attempted to talk to the Device during a dry run")
+}
+
+impl Read for DryRunChannel {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(dry_run_violation())
+    }
+}
+
+impl BufRead for DryRunChannel {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Err(dry_run_violation())
+    }
+
+    fn consume(&mut self, _amt: usize) {}
+}
+
+impl Write for DryRunChannel {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(dry_run_violation())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl QdlChan for DryRunChannel {
+    fn fh_config(&self) -> &FirehoseConfiguration {
+        &self.fh_cfg
+    }
+
+    fn mut_fh_config(&mut self) -> &mut FirehoseConfiguration {
+        &mut self.fh_cfg
+    }
+}
 
 /// Iterates through program/patch files and executes the instructions therein.
+/// With a slot suffix set, the bootable physical partition is picked based on that slot's
+/// copy of the bootloader, which is also marked active if the loader supports it.
 pub(crate) fn run_flash<T: QdlChan>(
     channel: &mut T,
     program_file_paths: Vec<String>,
     patch_file_paths: Vec<String>,
-    verbose: bool,
+    read_output_dir: Option<&str>,
+    opts: &ProgramXmlOptions,
+    supported_functions: &[String],
 ) -> Result<()> {
     // Check if the required files are present
     let file_paths = [&program_file_paths[..], &patch_file_paths[..]].concat();
     if let Some(f) = file_paths.iter().find(|f| !Path::new(f).is_file()) {
         bail!("{} doesn't exist", f);
     }
-    let tmp_path_string = match cfg!(target_os = "windows") {
-        true => "C:\\Temp\\",
-        false => "/tmp/out/",
-    };
+    let out_dir = get_read_output_dir(read_output_dir);
 
     let mut bootable_part_idx: Option<u8> = None;
+    let mut report = FlashReport::default();
     for program_file_path in file_paths {
         let path = Path::new(&program_file_path);
         if !path.is_file() {
@@ -35,28 +84,192 @@ pub(crate) fn run_flash<T: QdlChan>(
         }
 
         // Get the program files that we need
-        let program_file_dir = path.parent().unwrap();
+        let mut program_file_dir = path.parent().unwrap();
         let program_file = fs::read(path)?;
         let xml = xmltree::Element::parse(&program_file[..])?;
 
         // Parse the program/patch XMLs and flash away
-        if let Some(n) = parse_program_xml(
+        parse_program_xml(
             channel,
             &xml,
-            program_file_dir,
-            Path::new(tmp_path_string), // TODO
-            true,                       // TODO
-            verbose,
-        )? {
-            bootable_part_idx = Some(n)
-        };
+            &mut program_file_dir,
+            out_dir,
+            opts,
+            &mut bootable_part_idx,
+            &mut report,
+        )?;
+    }
+
+    finish_flash(
+        channel,
+        bootable_part_idx,
+        report,
+        opts,
+        supported_functions,
+    )
+}
+
+/// Like [run_flash], but with the rawprogramN.xml/patchN.xml files (and the images they
+/// reference) taken straight out of a factory ZIP package, without extracting it
+pub(crate) fn run_flash_zip<T: QdlChan>(
+    channel: &mut T,
+    zip_path: &Path,
+    read_output_dir: Option<&str>,
+    opts: &ProgramXmlOptions,
+    supported_functions: &[String],
+) -> Result<()> {
+    let mut pkg = ZipPackage::open(zip_path)?;
+    let program_files = pkg.numbered_xmls("rawprogram");
+    if program_files.is_empty() {
+        bail!("{} doesn't contain any rawprogram XMLs", zip_path.display());
+    }
+    let out_dir = get_read_output_dir(read_output_dir);
+
+    let mut bootable_part_idx: Option<u8> = None;
+    let mut report = FlashReport::default();
+    for name in [program_files, pkg.numbered_xmls("patch")].concat() {
+        if !channel.fh_config().quiet {
+            println!("Executing {name}");
+        }
+        let xml = xmltree::Element::parse(&pkg.read(&name)?[..])?;
+        let dir = name.rsplit_once('/').map_or("", |(dir, _)| dir);
+
+        parse_program_xml(
+            channel,
+            &xml,
+            &mut pkg.dir(dir),
+            out_dir,
+            opts,
+            &mut bootable_part_idx,
+            &mut report,
+        )?;
+    }
+
+    finish_flash(
+        channel,
+        bootable_part_idx,
+        report,
+        opts,
+        supported_functions,
+    )
+}
+
+fn get_read_output_dir(read_output_dir: Option<&str>) -> &Path {
+    Path::new(
+        read_output_dir.unwrap_or(match cfg!(target_os = "windows") {
+            true => "C:\\Temp\\",
+            false => "/tmp/out/",
+        }),
+    )
+}
+
+/// Once all the program files are done, mark the bootable partition and the active slot
+/// (unless the bootloader failed to be written) and report whatever failed along the way
+fn finish_flash<T: QdlChan>(
+    channel: &mut T,
+    bootable_part_idx: Option<u8>,
+    report: FlashReport,
+    opts: &ProgramXmlOptions,
+    supported_functions: &[String],
+) -> Result<()> {
+    if report.bootable_failed {
+        println!(
+            "{}",
+            "The bootloader couldn't be written, not touching the bootable partition or the active slot"
+                .bright_yellow()
+        );
+    } else {
+        mark_bootable(channel, bootable_part_idx, opts, supported_functions)?;
+    }
+
+    if !report.failed.is_empty() {
+        bail!(
+            "{} instruction(s) failed:\n{}",
+            report.failed.len(),
+            report
+                .failed
+                .iter()
+                .map(|(label, e)| format!("  {label}: {e}"))
+                .join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// Mark the bootable partition and the active slot
+fn mark_bootable<T: QdlChan>(
+    channel: &mut T,
+    bootable_part_idx: Option<u8>,
+    opts: &ProgramXmlOptions,
+    supported_functions: &[String],
+) -> Result<()> {
+    if opts.dry_run {
+        if let Some(bootable_part_idx) = bootable_part_idx {
+            println!("Would set partition {} as bootable", bootable_part_idx);
+        }
+        if let Some(slot) = opts.slot_suffix.map(|s| s.trim_start_matches('_')) {
+            println!("Would set slot {} as active", slot);
+        }
+        return Ok(());
     }
 
     // Mark the correct LUN (or any other kind of physical partition) as bootable
-    if let Some(bootable_part_idx) = bootable_part_idx {
-        println!("Setting partition {} as bootable!", bootable_part_idx);
-        firehose_set_bootable(channel, bootable_part_idx)?;
+    match bootable_part_idx {
+        Some(bootable_part_idx) => {
+            if !channel.fh_config().quiet {
+                println!("Setting partition {} as bootable!", bootable_part_idx);
+            }
+            firehose_set_bootable(channel, bootable_part_idx)?;
+        }
+        None if !channel.fh_config().quiet => println!(
+            "{}",
+            "No primary bootloader (xbl/sbl1) was flashed, leaving the bootable partition as-is"
+                .bright_yellow()
+        ),
+        None => (),
+    }
+
+    if let Some(slot) = opts.slot_suffix.map(|s| s.trim_start_matches('_')) {
+        if supported_functions.iter().any(|f| f == "setactiveslot") {
+            if !channel.fh_config().quiet {
+                println!("Setting slot {} as active!", slot);
+            }
+            firehose_set_active_slot(channel, slot)?;
+        } else {
+            println!(
+                "{}",
+                "The loader doesn't advertise <setactiveslot>, not changing the active slot"
+                    .bright_yellow()
+            );
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdl::mock::QdlMockDevice;
+
+    #[test]
+    fn mark_bootable_xml() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_ack().push_ack();
+        let mut dev = mock.into_device();
+        let opts = ProgramXmlOptions {
+            slot_suffix: Some("_b"),
+            ..Default::default()
+        };
+
+        mark_bootable(&mut dev, Some(1), &opts, &["setactiveslot".to_owned()]).unwrap();
+        assert_eq!(
+            dev.rw.written_xml(),
+            [
+                r#"<?xml version="1.0" encoding="UTF-8"?><data><setbootablestoragedrive value="1" /></data>"#,
+                r#"<?xml version="1.0" encoding="UTF-8"?><data><setactiveslot slot="b" /></data>"#,
+            ]
+        );
+    }
+}
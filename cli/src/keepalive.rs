@@ -0,0 +1,152 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+//! Periodic \<nop\>s, for programmers that give up on the host after a while of silence
+use owo_colors::OwoColorize;
+use qdl::firehose_nop;
+use qdl::types::{ChannelActivity, FirehoseConfiguration, QdlDevice, QdlReadWrite};
+use std::io::{self, BufRead, Read, Write};
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use std::thread::{self, JoinHandle, sleep};
+use std::time::{Duration, Instant};
+
+/// How often to check whether the Device has been idle for long enough
+const KEEPALIVE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const SHARED_CHANNEL_BUF_SIZE: usize = 64 * 1024;
+
+/// A Device channel that can be used from multiple threads, one read/write at a time.
+/// Every handle buffers incoming data separately.
+pub struct SharedChannel {
+    inner: Arc<Mutex<Box<dyn QdlReadWrite>>>,
+    buf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+}
+
+impl SharedChannel {
+    pub fn new(rw: Box<dyn QdlReadWrite>) -> Self {
+        SharedChannel {
+            inner: Arc::new(Mutex::new(rw)),
+            buf: vec![0; SHARED_CHANNEL_BUF_SIZE],
+            pos: 0,
+            cap: 0,
+        }
+    }
+
+    /// Another handle to the same channel
+    pub fn handle(&self) -> Self {
+        SharedChannel {
+            inner: self.inner.clone(),
+            buf: vec![0; SHARED_CHANNEL_BUF_SIZE],
+            pos: 0,
+            cap: 0,
+        }
+    }
+}
+
+impl Read for SharedChannel {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.cap {
+            let n = out.len().min(self.cap - self.pos);
+            out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+
+        self.inner.lock().unwrap().read(out)
+    }
+}
+
+impl BufRead for SharedChannel {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.cap {
+            self.cap = self.inner.lock().unwrap().read(&mut self.buf)?;
+            self.pos = 0;
+        }
+
+        Ok(&self.buf[self.pos..self.cap])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.cap);
+    }
+}
+
+impl Write for SharedChannel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().flush()
+    }
+}
+
+impl QdlReadWrite for SharedChannel {}
+
+/// Sends a \<nop\> whenever the Device has been idle (see [qdl::types::QdlChan::set_idle])
+/// for `interval`, until dropped
+pub struct Keepalive {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Keepalive {
+    pub fn start(
+        rw: SharedChannel,
+        activity: Arc<Mutex<ChannelActivity>>,
+        mut fh_cfg: FirehoseConfiguration,
+        interval: Duration,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+        fh_cfg.skip_firehose_log = true;
+
+        let thread = thread::spawn(move || {
+            let mut dev = QdlDevice {
+                rw: Box::new(rw),
+                fh_cfg,
+                fh_log: None,
+                progress: None,
+                bypassed: Default::default(),
+                interrupt: None,
+                activity: None,
+                reset_on_drop: false,
+            };
+
+            while !stop_thread.load(Ordering::Relaxed) {
+                sleep(KEEPALIVE_POLL_INTERVAL);
+
+                // Holding onto this keeps the main thread from sending the next command
+                let mut a = activity.lock().unwrap();
+                if !a.idle || a.since.elapsed() < interval {
+                    continue;
+                }
+
+                if let Err(e) = firehose_nop(&mut dev) {
+                    if !dev.fh_cfg.quiet {
+                        eprintln!("{} {}", "Keepalive failed, stopping it:".bright_yellow(), e);
+                    }
+                    break;
+                }
+                a.since = Instant::now();
+            }
+        });
+
+        Keepalive {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+use anyhow::{Result, bail};
+use owo_colors::OwoColorize;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+/// What to do about images that don't look like what the target partition expects
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MagicCheck {
+    #[default]
+    Off,
+    Warn,
+    Strict,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ImageKind {
+    Elf,
+    AndroidBoot,
+    VendorBoot,
+    Filesystem,
+}
+
+impl ImageKind {
+    fn describe(&self) -> &'static str {
+        match self {
+            ImageKind::Elf => "an ELF",
+            ImageKind::AndroidBoot => "an Android boot image",
+            ImageKind::VendorBoot => "an Android vendor_boot image",
+            ImageKind::Filesystem => "an ext4/EROFS filesystem",
+        }
+    }
+
+    /// Partitions whose contents are well-known, by their (slot-less) name
+    fn expected_for(label: &str) -> Option<Self> {
+        let base = label
+            .strip_suffix("_a")
+            .or_else(|| label.strip_suffix("_b"))
+            .unwrap_or(label);
+
+        match base {
+            "xbl" | "xbl_config" | "abl" | "aop" | "tz" | "hyp" | "devcfg" => Some(ImageKind::Elf),
+            "boot" | "recovery" | "init_boot" => Some(ImageKind::AndroidBoot),
+            "vendor_boot" => Some(ImageKind::VendorBoot),
+            "system" | "vendor" | "product" | "system_ext" | "odm" => Some(ImageKind::Filesystem),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, header: &[u8]) -> bool {
+        match self {
+            ImageKind::Elf => header.starts_with(b"\x7fELF"),
+            ImageKind::AndroidBoot => header.starts_with(b"ANDROID!"),
+            ImageKind::VendorBoot => header.starts_with(b"VNDRBOOT"),
+            // Both superblocks live 1024 bytes in
+            ImageKind::Filesystem => {
+                header.get(1024 + 0x38..1024 + 0x3a) == Some(&0xef53u16.to_le_bytes())
+                    || header.get(1024..1028) == Some(&0xe0f5e1e2u32.to_le_bytes())
+            }
+        }
+    }
+}
+
+/// Sniff the start of a (non-sparse) image about to be written to `label`, and complain
+/// if it's obviously not what the partition holds (e.g. boot.img destined for system).
+/// The stream position is left untouched.
+pub fn check_image_magic(
+    image: &mut (impl Read + Seek),
+    filename: &str,
+    label: &str,
+    mode: MagicCheck,
+) -> Result<()> {
+    if mode == MagicCheck::Off {
+        return Ok(());
+    }
+    let Some(kind) = ImageKind::expected_for(label) else {
+        return Ok(());
+    };
+
+    let pos = image.stream_position()?;
+    let mut header = vec![0u8; 2048];
+    let mut len = 0;
+    while len < header.len() {
+        match image.read(&mut header[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    header.truncate(len);
+    image.seek(SeekFrom::Start(pos))?;
+
+    if kind.matches(&header) {
+        return Ok(());
+    }
+
+    let msg = format!(
+        "{} doesn't look like {}, which is what {} is expected to contain",
+        filename,
+        kind.describe(),
+        label
+    );
+    match mode {
+        MagicCheck::Strict => bail!("{msg} (drop --strict-magic to flash it anyway)"),
+        _ => println!("{}", msg.bright_yellow()),
+    }
+
+    Ok(())
+}
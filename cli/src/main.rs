@@ -1,35 +1,147 @@
 // SPDX-License-Identifier: BSD-3-Clause
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
 use clap::{Parser, Subcommand};
 use clap_num::maybe_hex;
 use itertools::Itertools;
 use owo_colors::OwoColorize;
-use qdl::parsers::{firehose_parser_ack_nak, firehose_parser_configure_response};
-use qdl::sahara::{SaharaCmdModeCmd, SaharaMode, sahara_run, sahara_send_hello_rsp};
-use qdl::types::{FirehoseResetMode, FirehoseStorageType, QdlBackend, QdlDevice};
-use qdl::{firehose_configure, firehose_read, firehose_reset, types::FirehoseConfiguration};
+#[cfg(feature = "simulate")]
+use qdl::mock::QdlMockDevice;
+use qdl::parsers::{
+    firehose_parser_ack_nak, firehose_parser_configure_response,
+    firehose_parser_supported_functions,
+};
+use qdl::sahara::{
+    RamdumpOptions, SaharaCmdModeCmd, SaharaError, SaharaMode, sahara_run, sahara_send_hello_rsp,
+};
+use qdl::types::{
+    FirehoseLogLevel, FirehoseResetMode, FirehoseStatus, FirehoseStorageType, QdlBackend, QdlChan,
+    QdlDevice, QdlReadWrite, TransferStats, UsbIdFilter, UsbOptions,
+};
+use qdl::usb::list_edl_devices;
+use qdl::{
+    autodetect_backend, firehose_checksum_storage, firehose_erase,
+    firehose_get_default_sector_size, firehose_get_storage_info, firehose_nak_error, firehose_nop,
+    firehose_peek, firehose_poke, firehose_program_storage, firehose_raw_xml,
+    firehose_read_storage, firehose_set_active_slot, firehose_set_bootable, load_programmer_images,
+    progress_bar_reporter, setup_target_device,
+};
 use qdl::{
-    firehose_get_default_sector_size, firehose_nop, firehose_peek, firehose_program_storage,
-    firehose_set_bootable, load_programmer_images, setup_target_device,
+    firehose_configure, firehose_read, firehose_read_logs, firehose_reset,
+    types::FirehoseConfiguration,
 };
+use sha2::{Digest, Sha256};
 use util::{
-    find_part, print_partition_table, read_gpt_from_storage, read_storage_logical_partition,
+    dump_logical_partition, dump_physical_partition, find_part, find_part_by_idx, fix_gpt,
+    flash_disk_image, json_progress_reporter, open_progress_fd, pipe_logical_partition,
+    print_edl_devices, print_partition_table, print_partition_table_from_image,
+    read_gpt_from_storage, reset_device, write_logical_partition,
 };
 
 use std::fs::{self, File};
+use std::io;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use std::thread;
+use std::time::Duration;
 use std::{path::Path, str::FromStr};
 
+use configcache::ConfigCache;
+use flasher::DryRunChannel;
+use keepalive::{Keepalive, SharedChannel};
+use magic::MagicCheck;
+use programfile::{ProgramXmlOptions, parse_provision_xml};
+
+mod configcache;
 mod flasher;
+mod keepalive;
+mod magic;
 mod programfile;
 mod util;
+mod zipfile;
+
+/// Options for executing program/patch XMLs, shared by the flasher commands
+#[derive(Clone, Debug, clap::Args, PartialEq)]
+struct FlashOptions {
+    #[arg(long, value_name = "_a/_b", value_parser = ["_a", "_b"])]
+    slot_suffix: Option<String>,
+
+    #[arg(long, help = "Execute <erase> instructions found in the program files")]
+    allow_erase: bool,
+
+    #[arg(
+        long,
+        help = "Fail on <program> instructions whose files are missing, instead of skipping them"
+    )]
+    fail_on_missing_files: bool,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Where to store the data of <read> tags [default: /tmp/out/, C:\\Temp\\ on Windows]"
+    )]
+    read_output_dir: Option<String>,
+
+    #[arg(
+        long,
+        help = "Carry on past failed instructions, and list them all at the end"
+    )]
+    keep_going: bool,
+
+    #[arg(long, default_value = "false")]
+    verbose_flasher: bool,
+}
 
-#[derive(Debug, Subcommand, PartialEq)]
+impl FlashOptions {
+    /// `supported_functions` is what the loader advertised, or `None` for a dry run
+    fn program_xml_options(
+        &self,
+        default_phys_part_idx: u8,
+        magic_check: MagicCheck,
+        supported_functions: Option<&[String]>,
+    ) -> ProgramXmlOptions<'_> {
+        ProgramXmlOptions {
+            default_phys_part_idx,
+            allow_missing_files: !self.fail_on_missing_files,
+            allow_erase: self.allow_erase,
+            slot_suffix: self.slot_suffix.as_deref(),
+            // An empty list means the loader didn't tell us either way
+            crc32_digests: supported_functions
+                .is_some_and(|f| !f.is_empty() && !f.iter().any(|f| f == "getsha256digest")),
+            dry_run: supported_functions.is_none(),
+            magic_check,
+            keep_going: self.keep_going,
+            verbose: self.verbose_flasher,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Subcommand, PartialEq)]
 enum Command {
+    /// Measure the throughput of the channel (requires --bypass-storage)
+    Benchmark {
+        #[arg(default_value = "65536", value_parser=maybe_hex::<usize>)]
+        num_sectors: usize,
+    },
+
     /// Dump the entire storage
     Dump {
         #[arg(short, default_value = "out/")]
         outdir: String,
+
+        #[arg(long, help = "Continue previously interrupted dumps")]
+        resume: bool,
+
+        #[arg(long, value_parser = ["gzip"], help = "Compress the partition images")]
+        compress: Option<String>,
+
+        #[arg(
+            long,
+            help = "Dump every LUN (into a lunN subdirectory each), skipping those without a GPT"
+        )]
+        all_luns: bool,
     },
 
     /// Dump a single partition
@@ -39,6 +151,54 @@ enum Command {
 
         #[arg(short, default_value = "out/")]
         outdir: String,
+
+        #[arg(long, help = "Continue a previously interrupted dump")]
+        resume: bool,
+
+        #[arg(long, value_parser = ["gzip"], help = "Compress the partition image")]
+        compress: Option<String>,
+
+        #[arg(
+            long,
+            value_name = "COMMAND",
+            conflicts_with_all = ["resume", "compress"],
+            help = "Stream the partition into a shell command (e.g. \"gzip > out.gz\") instead of a file"
+        )]
+        pipe: Option<String>,
+    },
+
+    /// Dump a raw range of sectors, regardless of the partition layout
+    DumpRange {
+        #[arg(value_parser=maybe_hex::<u32>)]
+        start_sector: u32,
+
+        #[arg(value_parser=maybe_hex::<usize>)]
+        num_sectors: usize,
+
+        #[arg(short, default_value = "out.bin")]
+        outfile: String,
+    },
+
+    /// Check that the backup GPT is where the size of the physical partition says it should
+    /// be and matches the primary one, optionally rewriting it
+    FixGpt {
+        #[arg()]
+        phys_part_idx: u8,
+
+        /// Write the corrected backup GPT, instead of only reporting what's wrong
+        #[arg(long)]
+        apply: bool,
+    },
+
+    /// Write a whole-disk image with an embedded GPT (e.g. a full dump) to the physical
+    /// partition, after checking that it's valid and fits
+    FlashDisk {
+        #[arg()]
+        image: String,
+
+        /// Move the backup GPT to the actual end of the device, if it's larger than the image
+        #[arg(long)]
+        relocate_backup_gpt: bool,
     },
 
     /// Invoke the flasher
@@ -49,16 +209,55 @@ enum Command {
         #[arg(short = 'x', long, num_args = 0..=128, value_name = "FILE")]
         patch_file_paths: Vec<String>,
 
-        #[arg(long, default_value = "false")]
-        verbose_flasher: bool,
+        #[arg(
+            long,
+            help = "Only validate the program files and print what would be done, no device required"
+        )]
+        dry_run: bool,
+
+        #[command(flatten)]
+        flash: FlashOptions,
+    },
+
+    /// Invoke the flasher on a factory ZIP package, as-is (rawprogramN.xml and patchN.xml
+    /// files are picked up automatically)
+    FlashZip {
+        #[arg()]
+        zip_path: String,
+
+        #[command(flatten)]
+        flash: FlashOptions,
     },
 
     /// Erase a partition
     Erase {
         #[arg()]
         name: String,
+
+        #[arg(
+            long,
+            help = "Overwrite the partition with zeroes, for devices lacking a native <erase>"
+        )]
+        zero_fill: bool,
+    },
+
+    /// Erase an entire physical partition (e.g. LUN), GPT included
+    EraseLun {
+        #[arg()]
+        phys_part_idx: u8,
+
+        /// Confirm wiping everything on the physical partition
+        #[arg(long)]
+        yes: bool,
     },
 
+    /// Print everything that can be learned about the device without altering it
+    /// (serial number, OEM key hash, protocol version, storage info, partitions per LUN)
+    Info,
+
+    /// List the devices in EDL (or Ramdump) mode, no other arguments required
+    ListDevices,
+
     /// Ask the device to do nothing, hopefully successfully
     Nop,
 
@@ -79,21 +278,88 @@ enum Command {
         len: u64,
     },
 
+    /// Poke at memory (this can lead to lock-ups and resets)
+    Poke {
+        #[arg(value_parser=maybe_hex::<u64>)]
+        base: u64,
+
+        #[arg(value_parser=maybe_hex::<u64>)]
+        value: u64,
+
+        #[arg(default_value = "4", value_name = "1/2/4/8", value_parser=maybe_hex::<u8>)]
+        width: u8,
+    },
+
     /// Print the GPT table
-    PrintGpt,
+    PrintGpt {
+        #[arg(long, default_value = "text", value_parser = ["text", "json"])]
+        format: String,
+    },
+
+    /// Print the GPT table of a local storage image, no other arguments required
+    PrintGptFile {
+        #[arg()]
+        path: String,
+
+        #[arg(long, default_value = "text", value_parser = ["text", "json"])]
+        format: String,
+    },
+
+    /// Provision the storage medium (e.g. configure UFS LUNs) based on a provisioning XML.
+    /// Unprovisioned media usually require --skip-storage-init
+    Provision {
+        #[arg()]
+        config_file: String,
+    },
+
+    /// Send a hand-crafted Firehose XML packet as-is and print the response.
+    /// Nothing but well-formedness is checked, this can brick the device!
+    RawXml {
+        #[arg()]
+        xml_file: String,
+
+        /// Acknowledge that the packet is sent without any safety checks
+        #[arg(long)]
+        i_know_what_im_doing: bool,
+    },
 
     /// Restart the device
     Reset {
-        #[arg(default_value = "system", value_name = "edl/off/system")]
+        #[arg(
+            default_value = "system",
+            value_name = "edl/off/system/fastboot/recovery"
+        )]
         reset_mode: String,
     },
 
+    /// Query the device for everything it tells about itself over Sahara (serial number,
+    /// HW ID, OEM key hash, SBL version, debug log...), no programmer required
+    SaharaInfo,
+
+    /// Mark an A/B slot as active
+    SetActiveSlot {
+        #[arg(value_parser = ["a", "b"])]
+        slot: String,
+    },
+
     /// Mark physical storage partition as bootable
     SetBootablePart {
         #[arg()]
         idx: u8,
     },
 
+    /// Print the SHA256 digest of a range of sectors, as computed by the Device
+    Sha256 {
+        #[arg(value_parser=maybe_hex::<u32>)]
+        start_sector: u32,
+
+        #[arg(value_parser=maybe_hex::<usize>)]
+        num_sectors: usize,
+    },
+
+    /// Print information about the storage physical partition
+    StorageInfo,
+
     /// Write a partition
     Write {
         #[arg()]
@@ -102,14 +368,31 @@ enum Command {
         #[arg()]
         file_path: String,
     },
+
+    /// Write a partition, picked by its GPT entry index (as shown by print-gpt)
+    WriteIdx {
+        #[arg()]
+        idx: u32,
+
+        #[arg()]
+        file_path: String,
+    },
 }
 
-#[derive(Parser, Debug)]
+#[derive(Clone, Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(long, value_name = "usb/serial")]
     backend: Option<String>,
 
+    // Only applies to the serial backend
+    #[arg(
+        long,
+        value_name = "RATE",
+        help = "Serial port baud rate [default: 115200]"
+    )]
+    baud: Option<u32>,
+
     /// Accept storage r/w operations, but make them never actually execute (useful for testing USB throughput)
     #[arg(long, default_value = "false")]
     bypass_storage: bool,
@@ -117,10 +400,26 @@ struct Args {
     #[arg(short, long, help = "E.g. COM4 on Windows")]
     dev_path: Option<String>,
 
-    #[arg(short, long, value_name = "FILE")]
-    loader_path: String,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Save every (timestamped) Firehose packet received from the device"
+    )]
+    firehose_log_file: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        help = "Programmer image, repeat to chain e.g. a DDR and a storage programmer"
+    )]
+    loader_path: Vec<String>,
 
-    #[arg(long, default_value = "false", help = "Validate every packet. Slow.")]
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Validate every packet (and checksum every read). Slow."
+    )]
     hash_packets: bool,
 
     #[arg(
@@ -134,6 +433,36 @@ struct Args {
     #[arg(long, default_value = "false")]
     print_firehose_log: bool,
 
+    #[arg(
+        long,
+        value_name = "info/warn/error",
+        value_parser = ["info", "warn", "error"],
+        help = "Only print device log messages at least this severe [implies --print-firehose-log]"
+    )]
+    firehose_log_level: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        default_value = "false",
+        help = "Only print errors and the results of the command"
+    )]
+    quiet: bool,
+
+    #[arg(
+        long,
+        value_name = "FD",
+        help = "Write newline-delimited JSON progress records to this (inherited) file descriptor"
+    )]
+    progress_fd: Option<u32>,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Print the SHA256 digest of the programmer image(s) before sending them [implied by --verbose-sahara]"
+    )]
+    print_loader_hash: bool,
+
     #[arg(
         long,
         default_value = "false",
@@ -141,14 +470,178 @@ struct Args {
     )]
     read_back_verify: bool,
 
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Verify every <program> operation using a SHA256 digest computed by the device"
+    )]
+    verify_sha256: bool,
+
+    #[arg(
+        long,
+        value_name = "BYTE",
+        default_value = "0",
+        value_parser = maybe_hex::<u8>,
+        help = "Pad the last sector of images that don't end on a sector boundary with this byte"
+    )]
+    fill_byte: u8,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Warn about images not matching the partition they're written to (e.g. boot.img into system)"
+    )]
+    check_magic: bool,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Refuse to write images not matching their partition [implies --check-magic]"
+    )]
+    strict_magic: bool,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Re-attempt a NAKed <program> operation up to N times"
+    )]
+    program_retries: u32,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser=maybe_hex::<usize>,
+        help = "Cap the size of data packets sent to the device, trading throughput for reliability"
+    )]
+    max_payload_size: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Sectors to receive at a time while reading storage [default: based on the negotiated payload size]"
+    )]
+    read_chunk_sectors: Option<u32>,
+
+    #[arg(
+        long,
+        default_value = "0",
+        value_name = "SECS",
+        help = "Delay before a reset takes effect, in seconds"
+    )]
+    reset_delay: u32,
+
     /// WARNING: Will be deprecated in release v1.0.0
-    #[arg(long, default_value = "edl", value_name = "edl/off/system")]
+    #[arg(
+        long,
+        default_value = "edl",
+        value_name = "edl/off/system/fastboot/recovery"
+    )]
     reset_mode: String,
 
+    #[arg(
+        long,
+        conflicts_with_all = ["reset_mode", "reset_delay"],
+        help = "Leave the device in Firehose mode once done, instead of resetting it"
+    )]
+    no_reset: bool,
+
+    #[arg(
+        long,
+        help = "Don't reuse (or remember) the parameters negotiated with the device last time"
+    )]
+    no_config_cache: bool,
+
+    #[cfg(feature = "simulate")]
+    #[arg(
+        long,
+        value_name = "LOG",
+        help = "Replay the device responses captured with --firehose-log-file instead of talking to a device, to debug failures offline"
+    )]
+    simulate_device: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Send a <nop> whenever the device has been idle for this long, so that it doesn't give up on the host in between commands"
+    )]
+    #[cfg_attr(feature = "simulate", arg(conflicts_with = "simulate_device"))]
+    keepalive: Option<u64>,
+
+    #[arg(long, help = "Read the backup GPT instead of the primary one")]
+    use_backup_gpt: bool,
+
     // Only applies to the USB backend
-    #[arg(long)]
+    #[arg(long, help = "Serial number of the device, or a unique part of it")]
     serial_no: Option<String>,
 
+    // Only applies to the USB backend
+    #[arg(
+        long,
+        conflicts_with = "serial_no",
+        help = "Run the flasher on every device in EDL mode concurrently"
+    )]
+    all_devices: bool,
+
+    // Only applies to the USB backend
+    #[arg(
+        long,
+        value_name = "VID",
+        value_parser=maybe_hex::<u16>,
+        help = "Match devices with this USB vendor ID [default: 0x05c6]"
+    )]
+    usb_vid: Option<u16>,
+
+    // Only applies to the USB backend
+    #[arg(
+        long,
+        value_name = "PID",
+        value_parser=maybe_hex::<u16>,
+        help = "Match devices with this USB product ID [default: 0x9008/0x900e]"
+    )]
+    usb_pid: Option<u16>,
+
+    // Only applies to the USB backend
+    #[arg(
+        long,
+        help = "Split writes into whole wMaxPacketSize packets and a short tail, works around transfer stalls on some USB controllers"
+    )]
+    usb_align_writes: bool,
+
+    // Only applies to the USB backend
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(u32).range(4096..),
+        help = "Size of the USB transfer buffers, larger ones trade memory for throughput [default: 1048576]"
+    )]
+    usb_buffer_size: Option<u32>,
+
+    // Only applies to the USB backend
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Claim this USB interface number instead of the autodetected one"
+    )]
+    usb_interface: Option<u8>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Read/write timeout of the device channel [default: backend-specific]"
+    )]
+    timeout: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        num_args = 0..=1,
+        default_missing_value = "60",
+        help = "Wait for the device to show up (USB only) [default deadline: 60]"
+    )]
+    wait: Option<u64>,
+
     #[arg(
         short = 'A',
         long,
@@ -157,8 +650,8 @@ struct Args {
     )]
     skip_hello_wait: bool,
 
-    #[arg(short, long, value_name = "emmc/ufs/nvme/nand")]
-    storage_type: String,
+    #[arg(short, long, value_name = "emmc/ufs/nvme/nand/spinor")]
+    storage_type: Option<String>,
 
     #[arg(
         short = 'S',
@@ -184,178 +677,970 @@ struct Args {
     #[arg(long, default_value = "false")]
     verbose_firehose: bool,
 
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Log the USB interfaces on offer and the endpoints picked"
+    )]
+    verbose_usb: bool,
+
     #[command(subcommand)]
     command: Command,
 }
 
+impl Args {
+    fn magic_check(&self) -> MagicCheck {
+        match (self.check_magic, self.strict_magic) {
+            (_, true) => MagicCheck::Strict,
+            (true, false) => MagicCheck::Warn,
+            (false, false) => MagicCheck::Off,
+        }
+    }
+
+    /// The Firehose log to replay instead of talking to a device, if any
+    fn simulated_log(&self) -> Option<&str> {
+        #[cfg(feature = "simulate")]
+        return self.simulate_device.as_deref();
+        #[cfg(not(feature = "simulate"))]
+        None
+    }
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
-    let backend = match args.backend {
-        Some(b) => QdlBackend::from_str(&b)?,
-        None => QdlBackend::default(),
-    };
-    let reset_mode = FirehoseResetMode::from_str(&args.reset_mode)?;
 
-    // Get the MBN loader binary
-    let mut mbn_loader = match load_programmer_images(&args.loader_path) {
-        Ok(m) => m,
-        Err(e) => bail!("Couldn't open the programmer binary: {}", e.to_string()),
-    };
+    // Commands that don't require a Sahara/Firehose session
+    match &args.command {
+        Command::ListDevices => {
+            return print_edl_devices(UsbIdFilter {
+                vid: args.usb_vid,
+                pid: args.usb_pid,
+            });
+        }
+        Command::PrintGptFile { path, format } => {
+            return print_partition_table_from_image(
+                Path::new(path),
+                args.sector_size,
+                format == "json",
+            );
+        }
+        _ => (),
+    }
 
-    println!(
-        "{} {}",
-        env!("CARGO_PKG_NAME").green(),
-        env!("CARGO_PKG_VERSION").yellow()
-    );
+    if let Command::RawXml {
+        i_know_what_im_doing: false,
+        ..
+    } = args.command
+    {
+        bail!(
+            "raw-xml sends arbitrary commands to the programmer, which may irreversibly damage the device. Pass --i-know-what-im-doing to proceed"
+        );
+    }
 
-    // Set up the device
-    let rw_channel = match setup_target_device(backend, args.serial_no, args.dev_path) {
-        Ok(c) => c,
-        Err(e) => bail!("Couldn't set up device: {}", e.to_string()),
-    };
-    let mut qdl_dev = QdlDevice {
-        rw: rw_channel,
-        fh_cfg: FirehoseConfiguration {
-            hash_packets: args.hash_packets,
-            read_back_verify: args.read_back_verify,
-            storage_type: FirehoseStorageType::from_str(&args.storage_type)?,
-            storage_sector_size: match args.sector_size {
-                Some(n) => n,
-                None => {
-                    let sector_size = firehose_get_default_sector_size(&args.storage_type);
-                    if let Some(m) = sector_size {
-                        println!("{} {}", "Using a default sector size of".bright_black(), m);
-                        m
-                    } else {
-                        bail!("Specify storage sector size with --sector-size <n>");
-                    }
-                }
-            },
-            bypass_storage: args.bypass_storage,
-            backend,
-            skip_firehose_log: !args.print_firehose_log,
-            verbose_firehose: args.verbose_firehose,
-            // The remaining values are overwritten at runtime through a <configure> handshake
+    if let Command::EraseLun {
+        phys_part_idx,
+        yes: false,
+    } = args.command
+    {
+        bail!(
+            "erase-lun wipes all data on physical partition {phys_part_idx}, including the GPT. Pass --yes to proceed"
+        );
+    }
+
+    if let Command::Flasher { dry_run: true, .. } = args.command {
+        return run_flash_dry(args);
+    }
+
+    install_interrupt_handler()?;
+
+    if args.all_devices {
+        return run_on_all_devices(args);
+    }
+
+    run(args)
+}
+
+/// Interrupt flags of all the Devices being operated on, see [QdlChan::take_interrupt]
+static INTERRUPT_FLAGS: Mutex<Vec<Arc<AtomicBool>>> = Mutex::new(Vec::new());
+
+fn new_interrupt_flag() -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    INTERRUPT_FLAGS.lock().unwrap().push(flag.clone());
+    flag
+}
+
+/// The first Ctrl-C stops every Device before its next Firehose command, so that it can be
+/// reset back to EDL cleanly. The second one gives up right away.
+/// The handler runs on its own thread and never touches the Devices, so an interrupted
+/// USB transfer can't deadlock it.
+fn install_interrupt_handler() -> Result<()> {
+    let mut interrupted = false;
+    ctrlc::set_handler(move || {
+        if interrupted {
+            std::process::exit(130);
+        }
+        interrupted = true;
+
+        eprintln!(
+            "{}, stopping after the current operation (press Ctrl-C again to abort)",
+            "Interrupted".bright_red()
+        );
+        for flag in INTERRUPT_FLAGS.lock().unwrap().iter() {
+            flag.store(true, Ordering::Relaxed);
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Plan out a flasher run, without a Device attached
+fn run_flash_dry(args: Args) -> Result<()> {
+    let magic_check = args.magic_check();
+    let Command::Flasher {
+        program_file_paths,
+        patch_file_paths,
+        flash,
+        ..
+    } = args.command
+    else {
+        unreachable!()
+    };
+
+    let Some(storage_type) = args.storage_type else {
+        bail!("Specify the storage type with --storage-type <emmc/ufs/nvme/nand/spinor>");
+    };
+    let storage_type = FirehoseStorageType::from_str(&storage_type)?;
+    let Some(sector_size) = args
+        .sector_size
+        .or(firehose_get_default_sector_size(&storage_type.to_string()))
+    else {
+        bail!("Specify storage sector size with --sector-size <n>");
+    };
+
+    let mut channel = DryRunChannel {
+        fh_cfg: FirehoseConfiguration {
+            storage_type,
+            storage_sector_size: sector_size,
             ..Default::default()
         },
-        reset_on_drop: false,
     };
+    flasher::run_flash(
+        &mut channel,
+        program_file_paths,
+        patch_file_paths,
+        flash.read_output_dir.as_deref(),
+        &flash.program_xml_options(args.phys_part_idx, magic_check, None),
+        &[],
+    )
+}
 
+/// Run the command on every Device in EDL mode, each one in its own thread
+fn run_on_all_devices(args: Args) -> Result<()> {
+    if !matches!(args.command, Command::Flasher { .. }) {
+        bail!("--all-devices is only supported with the flasher command");
+    }
+    if args.backend.as_deref().is_some_and(|b| b != "usb") {
+        bail!("--all-devices is only supported with the USB backend");
+    }
+
+    let serials = list_edl_devices(UsbIdFilter {
+        vid: args.usb_vid,
+        pid: args.usb_pid,
+    })?
+    .into_iter()
+    .map(|d| {
+        d.serial_no.ok_or(anyhow!(
+            "Device at {}:{} doesn't report a serial number",
+            d.bus_id,
+            d.device_address
+        ))
+    })
+    .collect::<Result<Vec<_>>>()?;
+    if serials.is_empty() {
+        bail!("Found no devices in EDL mode");
+    }
+
+    let results = thread::scope(|s| {
+        let handles = serials
+            .iter()
+            .map(|sn| {
+                let args = Args {
+                    serial_no: Some(sn.clone()),
+                    backend: Some("usb".to_owned()),
+                    // Don't have the devices clobber each other's logs
+                    firehose_log_file: args.firehose_log_file.as_ref().map(|f| format!("{f}.{sn}")),
+                    ..args.clone()
+                };
+                s.spawn(move || run(args))
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or_else(|_| Err(anyhow!("Thread panicked"))))
+            .collect::<Vec<_>>()
+    });
+
+    println!("{:<18} RESULT", "SERIAL");
+    for (sn, res) in serials.iter().zip(&results) {
+        match res {
+            Ok(_) => println!("{:<18} {}", sn, "OK".green()),
+            Err(e) => println!("{:<18} {} {}", sn, "FAILED:".red(), e),
+        }
+    }
+
+    let failed = results.iter().filter(|r| r.is_err()).count();
+    if failed > 0 {
+        bail!("{} out of {} devices failed", failed, results.len());
+    }
+
+    Ok(())
+}
+
+/// Identify the Device and send the programmer over, returning the chip serial number
+/// along with the OEM key hash
+fn sahara_handshake<T: QdlChan>(
+    qdl_dev: &mut T,
+    mbn_loader: &mut [Option<Vec<u8>>],
+    skip_hello_wait: bool,
+    verbose_sahara: bool,
+    quiet: bool,
+) -> Result<(u32, String)> {
     // In case another program on the system has already consumed the HELLO packet,
     // send a HELLO response upfront, to appease the state machine
-    if args.skip_hello_wait {
-        sahara_send_hello_rsp(&mut qdl_dev, SaharaMode::Command)?;
+    if skip_hello_wait {
+        sahara_send_hello_rsp(qdl_dev, SaharaMode::Command)?;
     }
 
     // Get some info about the device
-    let sn = sahara_run(
-        &mut qdl_dev,
+    let sn = match sahara_run(
+        qdl_dev,
         SaharaMode::Command,
         Some(SaharaCmdModeCmd::ReadSerialNum),
         &mut [],
-        vec![],
-        args.verbose_sahara,
-    )?;
+        &RamdumpOptions::default(),
+        verbose_sahara,
+    ) {
+        Ok(sn) => sn,
+        // A previous run may have left the device in Firehose mode, kick it back to EDL
+        Err(e) => match e.downcast_ref::<SaharaError>() {
+            Some(SaharaError::UnexpectedFirehoseData(_)) => {
+                println!(
+                    "{} {}",
+                    "Device is stuck in Firehose mode, resetting it to".bright_red(),
+                    "edl".bright_yellow()
+                );
+                let delay = qdl_dev.fh_config().reset_delay;
+                firehose_reset(qdl_dev, &FirehoseResetMode::ResetToEdl, delay)?;
+                bail!("{e}. Run the program again once it's back in EDL mode.");
+            }
+            None => return Err(e),
+        },
+    };
     let sn = u32::from_le_bytes([sn[0], sn[1], sn[2], sn[3]]);
-    println!("Chip serial number: 0x{sn:x}");
+    if !quiet {
+        println!("Chip serial number: 0x{sn:x}");
+    }
 
     let key_hash = sahara_run(
-        &mut qdl_dev,
+        qdl_dev,
         SaharaMode::Command,
         Some(SaharaCmdModeCmd::ReadOemKeyHash),
         &mut [],
-        vec![],
-        args.verbose_sahara,
+        &RamdumpOptions::default(),
+        verbose_sahara,
     )?;
-    println!(
-        "OEM Private Key hash: 0x{:02x}",
-        key_hash[..key_hash.len() / 3].iter().format("")
-    );
+    let key_hash = format!("{:02x}", key_hash[..key_hash.len() / 3].iter().format(""));
+    if !quiet {
+        println!("OEM Private Key hash: 0x{key_hash}");
+    }
 
     // Send the loader (and any other images)
     sahara_run(
-        &mut qdl_dev,
+        qdl_dev,
         SaharaMode::WaitingForImage,
         None,
-        &mut mbn_loader,
-        vec![],
-        args.verbose_sahara,
+        mbn_loader,
+        &RamdumpOptions::default(),
+        verbose_sahara,
     )?;
 
-    // If we're past Sahara, activate the Firehose reset-on-drop listener
-    qdl_dev.reset_on_drop = true;
+    Ok((sn, key_hash))
+}
+
+/// Print the responses to all the read-only Sahara client commands that the Device
+/// implements. The programmer is never sent, so the Device is left in Sahara mode.
+fn print_sahara_info<T: QdlChan>(
+    qdl_dev: &mut T,
+    skip_hello_wait: bool,
+    verbose_sahara: bool,
+) -> Result<()> {
+    if skip_hello_wait {
+        sahara_send_hello_rsp(qdl_dev, SaharaMode::Command)?;
+    }
+
+    let exec = |qdl_dev: &mut T, cmd| {
+        sahara_run(
+            qdl_dev,
+            SaharaMode::Command,
+            Some(cmd),
+            &mut [],
+            &RamdumpOptions::default(),
+            verbose_sahara,
+        )
+    };
+    let words = |buf: &[u8]| {
+        buf.chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect::<Vec<_>>()
+    };
+    let word = |buf: &[u8]| {
+        words(buf)
+            .first()
+            .copied()
+            .ok_or(anyhow!("Short Sahara response ({} bytes)", buf.len()))
+    };
+
+    let sn = exec(qdl_dev, SaharaCmdModeCmd::ReadSerialNum)?;
+    println!("Chip serial number: 0x{:x}", word(&sn)?);
+
+    let hw_id = exec(qdl_dev, SaharaCmdModeCmd::ReadHwId)?;
+    match hw_id.first_chunk::<8>() {
+        Some(id) => println!("MSM HW ID: 0x{:016x}", u64::from_le_bytes(*id)),
+        None => println!("MSM HW ID: 0x{:02x}", hw_id.iter().format("")),
+    }
+
+    let key_hash = exec(qdl_dev, SaharaCmdModeCmd::ReadOemKeyHash)?;
+    println!(
+        "OEM Private Key hash: 0x{:02x}",
+        key_hash[..key_hash.len() / 3].iter().format("")
+    );
+
+    // Anything past the basics is optional, and an unsupported command throws the Device
+    // off, so only go on with what it claims to implement
+    let cmds = match exec(qdl_dev, SaharaCmdModeCmd::GetCommandIdList) {
+        Ok(list) => words(&list),
+        Err(e) => {
+            println!(
+                "{} ({})",
+                "The device doesn't list its Sahara commands, skipping the rest".bright_yellow(),
+                e
+            );
+            return Ok(());
+        }
+    };
+    println!(
+        "Supported commands: {}",
+        cmds.iter()
+            .map(|&id| match SaharaCmdModeCmd::from_id(id) {
+                Some(c) => format!("{c:?}"),
+                None => format!("0x{id:x}"),
+            })
+            .join(", ")
+    );
+
+    if cmds.contains(&(SaharaCmdModeCmd::GetSblSwVersion as u32)) {
+        let ver = exec(qdl_dev, SaharaCmdModeCmd::GetSblSwVersion)?;
+        println!("SBL SW version: 0x{:08x}", word(&ver)?);
+    }
+
+    if cmds.contains(&(SaharaCmdModeCmd::ReadDebugData as u32)) {
+        let log = exec(qdl_dev, SaharaCmdModeCmd::ReadDebugData)?;
+        println!("Debug data ({} bytes):", log.len());
+        println!(
+            "{}",
+            String::from_utf8_lossy(&log)
+                .trim_end_matches('\0')
+                .bright_black()
+        );
+    }
+
+    Ok(())
+}
+
+fn run(args: Args) -> Result<()> {
+    let magic_check = args.magic_check();
+    let simulated_log = args.simulated_log().map(str::to_owned);
+    // Sahara only, so neither a programmer nor storage are involved
+    let sahara_only = matches!(args.command, Command::SaharaInfo);
+    if sahara_only && simulated_log.is_some() {
+        bail!("sahara-info can't be simulated, Sahara isn't captured in Firehose logs");
+    }
+    if args.loader_path.is_empty() && simulated_log.is_none() && !sahara_only {
+        bail!("Specify the programmer binary with --loader-path <FILE>");
+    }
+    let storage_type = match args.storage_type {
+        Some(ref t) => FirehoseStorageType::from_str(t)?,
+        None if sahara_only => FirehoseStorageType::Emmc,
+        None => {
+            bail!("Specify the storage type with --storage-type <emmc/ufs/nvme/nand/spinor>")
+        }
+    };
+    let backend = args
+        .backend
+        .as_deref()
+        .map(QdlBackend::from_str)
+        .transpose()?;
+    let reset_mode = FirehoseResetMode::from_str(&args.reset_mode)?;
+
+    // Get the MBN loader binary
+    let mut mbn_loader = match load_programmer_images(&args.loader_path) {
+        Ok(m) => m,
+        Err(e) => bail!("Couldn't open the programmer binary: {}", e.to_string()),
+    };
+    if args.print_loader_hash || args.verbose_sahara {
+        for (idx, img) in mbn_loader.iter().enumerate() {
+            if let Some(img) = img {
+                println!(
+                    "Loader image #{}: {} bytes, SHA256 {:02x}",
+                    idx,
+                    img.len(),
+                    Sha256::digest(img).iter().format("")
+                );
+            }
+        }
+    }
+
+    if !args.quiet {
+        println!(
+            "{} {}",
+            env!("CARGO_PKG_NAME").green(),
+            env!("CARGO_PKG_VERSION").yellow()
+        );
+    }
+
+    // Set up the device, an explicitly requested backend is always honored
+    let usb_ids = UsbIdFilter {
+        vid: args.usb_vid,
+        pid: args.usb_pid,
+    };
+    let (backend, rw_channel): (_, Box<dyn QdlReadWrite>) = match simulated_log {
+        #[cfg(feature = "simulate")]
+        Some(ref log) => {
+            if !args.quiet {
+                println!("Replaying the device responses from {}", log.bright_blue());
+            }
+            let log = fs::read_to_string(log)
+                .map_err(|e| anyhow!("Couldn't read the Firehose log {}: {}", log, e))?;
+            (
+                backend.unwrap_or_default(),
+                Box::new(QdlMockDevice::from_firehose_log(&log)),
+            )
+        }
+        _ => {
+            let (backend, dev_path) = match backend {
+                Some(b) => (b, args.dev_path),
+                None => {
+                    let (backend, dev_path) = autodetect_backend(args.dev_path, usb_ids);
+                    if !args.quiet {
+                        println!("Auto-selected the {} backend", backend.bright_blue());
+                    }
+                    (backend, dev_path)
+                }
+            };
+            match setup_target_device(
+                backend,
+                args.serial_no,
+                dev_path,
+                args.timeout.map(Duration::from_secs),
+                args.wait.map(Duration::from_secs),
+                args.baud,
+                UsbOptions {
+                    ids: usb_ids,
+                    align_writes: args.usb_align_writes,
+                    buffer_size: args.usb_buffer_size.map(|n| n as usize),
+                    interface: args.usb_interface,
+                    verbose: args.verbose_usb,
+                    quiet: args.quiet,
+                },
+            ) {
+                Ok(c) => (backend, c),
+                Err(e) => bail!("Couldn't set up device: {}", e.to_string()),
+            }
+        }
+    };
+    if sahara_only {
+        let mut qdl_dev = QdlDevice {
+            rw: rw_channel,
+            fh_cfg: FirehoseConfiguration {
+                quiet: args.quiet,
+                ..Default::default()
+            },
+            fh_log: None,
+            progress: None,
+            bypassed: Default::default(),
+            interrupt: None,
+            activity: None,
+            reset_on_drop: false,
+        };
+        return print_sahara_info(&mut qdl_dev, args.skip_hello_wait, args.verbose_sahara);
+    }
+
+    // The keepalive thread gets its own handle to the channel
+    let (rw_channel, keepalive_rw): (Box<dyn QdlReadWrite>, _) = match args.keepalive {
+        Some(_) => {
+            let shared = SharedChannel::new(rw_channel);
+            (Box::new(shared.handle()), Some(shared))
+        }
+        None => (rw_channel, None),
+    };
+    let progress_fd = args.progress_fd.map(open_progress_fd).transpose()?;
+    let mut qdl_dev = QdlDevice {
+        rw: rw_channel,
+        fh_cfg: FirehoseConfiguration {
+            hash_packets: args.hash_packets,
+            read_back_verify: args.read_back_verify,
+            program_retries: args.program_retries,
+            max_payload_size: args.max_payload_size,
+            read_chunk_sectors: args.read_chunk_sectors.map(|n| n as usize),
+            verify_sha256: args.verify_sha256,
+            fill_byte: args.fill_byte,
+            storage_type,
+            // Unless overridden, this is probed after the <configure> handshake
+            storage_sector_size: match args.sector_size {
+                Some(n) => n,
+                None => match firehose_get_default_sector_size(&storage_type.to_string()) {
+                    Some(m) => m,
+                    None => bail!("Specify storage sector size with --sector-size <n>"),
+                },
+            },
+            bypass_storage: args.bypass_storage,
+            backend,
+            reset_delay: args.reset_delay,
+            skip_firehose_log: !args.print_firehose_log && args.firehose_log_level.is_none(),
+            log_level: match args.firehose_log_level {
+                Some(ref l) => FirehoseLogLevel::from_str(l)?,
+                None => FirehoseLogLevel::default(),
+            },
+            verbose_firehose: args.verbose_firehose,
+            quiet: args.quiet,
+            // The remaining values are overwritten at runtime through a <configure> handshake
+            ..Default::default()
+        },
+        fh_log: match args.firehose_log_file {
+            Some(ref path) => Some(Box::new(File::create(path)?)),
+            None => None,
+        },
+        progress: match (args.quiet, progress_fd) {
+            (true, None) => None,
+            (false, None) => Some(Box::new(progress_bar_reporter())),
+            (true, Some(fd)) => Some(Box::new(json_progress_reporter(fd))),
+            (false, Some(fd)) => {
+                let mut bar = progress_bar_reporter();
+                let mut json = json_progress_reporter(fd);
+                Some(Box::new(move |e| {
+                    bar(e);
+                    json(e);
+                }))
+            }
+        },
+        bypassed: Default::default(),
+        interrupt: Some(new_interrupt_flag()),
+        activity: keepalive_rw.as_ref().map(|_| Default::default()),
+        reset_on_drop: false,
+    };
+    if let Some(max) = args.max_payload_size
+        && (max == 0 || !max.is_multiple_of(qdl_dev.fh_cfg.storage_sector_size))
+    {
+        bail!(
+            "--max-payload-size must be a multiple of the sector size ({} bytes)",
+            qdl_dev.fh_cfg.storage_sector_size
+        );
+    }
+
+    // A replayed log picks up right after Sahara (which isn't captured)
+    let sahara_info = match simulated_log {
+        Some(_) => None,
+        None => Some(sahara_handshake(
+            &mut qdl_dev,
+            &mut mbn_loader,
+            args.skip_hello_wait,
+            args.verbose_sahara,
+            args.quiet,
+        )?),
+    };
+
+    // If we're past Sahara, activate the Firehose reset-on-drop listener (unless the
+    // user wants the device left alone, even when interrupted)
+    qdl_dev.reset_on_drop = !args.no_reset;
 
     // Get any "welcome" logs
-    firehose_read(&mut qdl_dev, firehose_parser_ack_nak)?;
+    let (_, welcome_logs) = firehose_read_logs(&mut qdl_dev, firehose_parser_ack_nak)?;
+    let supported_functions = firehose_parser_supported_functions(&welcome_logs);
+
+    // Skip growing the send buffer if we've been through that with this device before
+    let mut config_cache = sahara_info
+        .as_ref()
+        .filter(|_| !args.no_config_cache)
+        .map(|(sn, _)| (*sn, ConfigCache::load()));
+    if let Some((sn, cache)) = &config_cache
+        && cache.apply(*sn, &mut qdl_dev.fh_cfg)
+        && !args.quiet
+    {
+        println!(
+            "{}",
+            "Using the cached <configure> parameters for this device".bright_black()
+        );
+    }
 
     // Send the host capabilities to the device
     firehose_configure(&mut qdl_dev, args.skip_storage_init)?;
 
     // Parse some information from the device
     firehose_read(&mut qdl_dev, firehose_parser_configure_response)?;
+    if let Some((sn, cache)) = &mut config_cache
+        && let Err(e) = cache.update(*sn, &qdl_dev.fh_cfg)
+        && !args.quiet
+    {
+        println!(
+            "{} {}",
+            "Couldn't update the <configure> cache:".bright_yellow(),
+            e
+        );
+    }
+
+    if args.sector_size.is_none() {
+        let medium = qdl_dev.fh_cfg.storage_type;
+        match firehose_get_storage_info(&mut qdl_dev, args.phys_part_idx)
+            .ok()
+            .and_then(|info| info.sector_size(medium))
+        {
+            Some(sector_size) => {
+                if !args.quiet {
+                    println!(
+                        "{} {}",
+                        "Using the sector size reported by the device:".bright_black(),
+                        sector_size
+                    );
+                }
+                qdl_dev.mut_fh_config().storage_sector_size = sector_size;
+            }
+            None if args.quiet => (),
+            None => println!(
+                "{} {}",
+                "Using a default sector size of".bright_black(),
+                qdl_dev.fh_config().storage_sector_size
+            ),
+        }
+    }
+
+    // Declared after qdl_dev, so that it's stopped before any reset-on-drop
+    let keepalive = keepalive_rw.zip(args.keepalive).map(|(rw, secs)| {
+        Keepalive::start(
+            rw,
+            qdl_dev.activity.clone().unwrap(),
+            qdl_dev.fh_cfg.clone(),
+            Duration::from_secs(secs),
+        )
+    });
 
     match args.command {
-        Command::Dump { outdir } => {
-            fs::create_dir_all(&outdir)?;
-            let outpath = Path::new(&outdir);
-
-            for (_, p) in
-                read_gpt_from_storage(&mut qdl_dev, args.storage_slot, args.phys_part_idx)?.iter()
-            {
-                // *sigh*
-                if p.partition_name.as_str().is_empty() || p.size()? == 0 {
-                    continue;
+        Command::Benchmark { num_sectors } => {
+            if !args.bypass_storage {
+                bail!("Benchmarking overwrites storage contents, rerun with --bypass-storage");
+            }
+
+            let stats = firehose_program_storage(
+                &mut qdl_dev,
+                &mut io::empty(),
+                "benchmark",
+                num_sectors,
+                args.storage_slot,
+                args.phys_part_idx,
+                "0",
+            )?;
+            println!("Write: {stats}");
+
+            let stats = firehose_read_storage(
+                &mut qdl_dev,
+                &mut io::sink(),
+                num_sectors,
+                args.storage_slot,
+                args.phys_part_idx,
+                0,
+            )?;
+            println!("Read: {stats}");
+        }
+        Command::Dump {
+            outdir,
+            resume,
+            compress,
+            all_luns,
+        } => {
+            // Only some media (e.g. UFS) are split into multiple LUNs
+            let luns = match all_luns {
+                true => {
+                    let info = firehose_get_storage_info(&mut qdl_dev, args.phys_part_idx)?;
+                    (0..info.num_physical.unwrap_or(1).max(1)).collect()
                 }
+                false => vec![args.phys_part_idx],
+            };
 
-                let mut out = File::create(outpath.join(p.partition_name.to_string()))?;
-                read_storage_logical_partition(
+            for lun in luns {
+                let outpath = match all_luns {
+                    true => Path::new(&outdir).join(format!("lun{lun}")),
+                    false => Path::new(&outdir).to_owned(),
+                };
+                let gpt = match read_gpt_from_storage(
                     &mut qdl_dev,
-                    &mut out,
-                    &p.partition_name.to_string(),
                     args.storage_slot,
-                    args.phys_part_idx,
-                )?
+                    lun,
+                    args.use_backup_gpt,
+                ) {
+                    Ok(gpt) => gpt,
+                    Err(e) if all_luns => {
+                        println!("{} {} ({})", "Skipping LUN".bright_yellow(), lun, e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                dump_physical_partition(
+                    &mut qdl_dev,
+                    &gpt,
+                    &outpath,
+                    resume,
+                    compress.as_deref(),
+                    args.storage_slot,
+                    lun,
+                )?;
             }
-            // TODO: create an xml file
         }
-        Command::DumpPart { name, outdir } => {
-            fs::create_dir_all(&outdir)?;
-            let outpath = Path::new(&outdir);
-            let mut out = File::create(outpath.join(&name))?;
-
-            read_storage_logical_partition(
+        Command::DumpPart {
+            name,
+            outdir,
+            resume,
+            compress,
+            pipe,
+        } => {
+            let part = find_part(
                 &mut qdl_dev,
-                &mut out,
                 &name,
                 args.storage_slot,
                 args.phys_part_idx,
-            )?
+                args.use_backup_gpt,
+            )?;
+            if let Some(cmd) = pipe {
+                pipe_logical_partition(
+                    &mut qdl_dev,
+                    &part,
+                    &cmd,
+                    args.storage_slot,
+                    args.phys_part_idx,
+                )?;
+            } else {
+                fs::create_dir_all(&outdir)?;
+                dump_logical_partition(
+                    &mut qdl_dev,
+                    Path::new(&outdir),
+                    &part,
+                    resume,
+                    compress.as_deref(),
+                    args.storage_slot,
+                    args.phys_part_idx,
+                )?;
+            }
         }
-        Command::Erase { name } => {
-            let part = find_part(&mut qdl_dev, &name, args.storage_slot, args.phys_part_idx)?;
+        Command::DumpRange {
+            start_sector,
+            num_sectors,
+            outfile,
+        } => {
+            // <getstorageinfo> can't address secondary storage devices
+            let total_sectors = match args.storage_slot {
+                0 => firehose_get_storage_info(&mut qdl_dev, args.phys_part_idx)?
+                    .total_sectors(qdl_dev.fh_cfg.storage_sector_size),
+                _ => u64::MAX,
+            };
+            if start_sector as u64 + num_sectors as u64 > total_sectors {
+                bail!(
+                    "Sectors {}+{} exceed the size of physical partition {} ({} sectors)",
+                    start_sector,
+                    num_sectors,
+                    args.phys_part_idx,
+                    total_sectors
+                );
+            }
 
-            firehose_program_storage(
+            firehose_read_storage(
+                &mut qdl_dev,
+                &mut File::create(&outfile)?,
+                num_sectors,
+                args.storage_slot,
+                args.phys_part_idx,
+                start_sector,
+            )?;
+        }
+        Command::Erase { name, zero_fill } => {
+            let part = find_part(
                 &mut qdl_dev,
-                &mut &[0u8][..],
                 &name,
-                (part.ending_lba - part.starting_lba + 1) as usize,
                 args.storage_slot,
                 args.phys_part_idx,
-                &part.starting_lba.to_string(),
+                args.use_backup_gpt,
             )?;
+
+            let num_sectors = (part.ending_lba - part.starting_lba + 1) as usize;
+            if zero_fill {
+                firehose_program_storage(
+                    &mut qdl_dev,
+                    &mut io::empty(),
+                    &name,
+                    num_sectors,
+                    args.storage_slot,
+                    args.phys_part_idx,
+                    &part.starting_lba.to_string(),
+                )?;
+            } else {
+                firehose_erase(
+                    &mut qdl_dev,
+                    &part.starting_lba.to_string(),
+                    num_sectors,
+                    args.storage_slot,
+                    args.phys_part_idx,
+                )?;
+            }
         }
+        Command::EraseLun { phys_part_idx, .. } => {
+            // <getstorageinfo> can't address secondary storage devices
+            let total_sectors = match args.storage_slot {
+                0 => firehose_get_storage_info(&mut qdl_dev, phys_part_idx)?
+                    .total_sectors(qdl_dev.fh_cfg.storage_sector_size),
+                _ => 0,
+            };
+            if total_sectors == 0 {
+                bail!("Couldn't determine the size of physical partition {phys_part_idx}");
+            }
+
+            // An empty list means the loader didn't tell us either way
+            let native_erase =
+                supported_functions.is_empty() || supported_functions.iter().any(|f| f == "erase");
+            if !args.quiet {
+                println!(
+                    "Erasing {} sectors of physical partition {}{}",
+                    total_sectors,
+                    phys_part_idx,
+                    if native_erase {
+                        ""
+                    } else {
+                        " by overwriting them with zeroes"
+                    }
+                );
+            }
+            match native_erase {
+                true => firehose_erase(
+                    &mut qdl_dev,
+                    "0",
+                    total_sectors as usize,
+                    args.storage_slot,
+                    phys_part_idx,
+                )?,
+                false => {
+                    firehose_program_storage(
+                        &mut qdl_dev,
+                        &mut io::empty(),
+                        "",
+                        total_sectors as usize,
+                        args.storage_slot,
+                        phys_part_idx,
+                        "0",
+                    )?;
+                }
+            }
+        }
+        Command::FixGpt {
+            phys_part_idx,
+            apply,
+        } => fix_gpt(&mut qdl_dev, args.storage_slot, phys_part_idx, apply)?,
+        Command::FlashDisk {
+            image,
+            relocate_backup_gpt,
+        } => flash_disk_image(
+            &mut qdl_dev,
+            Path::new(&image),
+            relocate_backup_gpt,
+            args.storage_slot,
+            args.phys_part_idx,
+        )?,
         Command::Flasher {
             program_file_paths,
             patch_file_paths,
-            verbose_flasher,
+            dry_run: _,
+            flash,
         } => {
             flasher::run_flash(
                 &mut qdl_dev,
                 program_file_paths,
                 patch_file_paths,
-                verbose_flasher,
+                flash.read_output_dir.as_deref(),
+                &flash.program_xml_options(
+                    args.phys_part_idx,
+                    magic_check,
+                    Some(&supported_functions),
+                ),
+                &supported_functions,
+            )?;
+        }
+        Command::FlashZip { zip_path, flash } => {
+            flasher::run_flash_zip(
+                &mut qdl_dev,
+                Path::new(&zip_path),
+                flash.read_output_dir.as_deref(),
+                &flash.program_xml_options(
+                    args.phys_part_idx,
+                    magic_check,
+                    Some(&supported_functions),
+                ),
+                &supported_functions,
             )?;
         }
+        Command::Info => {
+            match &sahara_info {
+                Some((sn, key_hash)) => {
+                    println!("Chip serial number: 0x{sn:x}");
+                    println!("OEM Private Key hash: 0x{key_hash}");
+                }
+                None => println!("Chip serial number and OEM Private Key hash: unknown"),
+            }
+            println!(
+                "Firehose protocol version: {}",
+                qdl_dev
+                    .fh_cfg
+                    .protocol_version
+                    .map_or("unknown".to_owned(), |v| v.to_string())
+            );
+            println!(
+                "SoC: {}",
+                qdl_dev.fh_cfg.target_name.as_deref().unwrap_or("unknown")
+            );
+            println!(
+                "Memory: {}",
+                qdl_dev.fh_cfg.memory_name.as_deref().unwrap_or("unknown")
+            );
+
+            let info = firehose_get_storage_info(&mut qdl_dev, args.phys_part_idx)?;
+            println!("{info}");
+
+            // Only some media (e.g. UFS) are split into multiple LUNs
+            for lun in 0..info.num_physical.unwrap_or(1).max(1) {
+                match read_gpt_from_storage(
+                    &mut qdl_dev,
+                    args.storage_slot,
+                    lun,
+                    args.use_backup_gpt,
+                ) {
+                    Ok(gpt) => println!(
+                        "LUN {}: {} partitions",
+                        lun,
+                        gpt.iter().filter(|(_, p)| p.is_used()).count()
+                    ),
+                    Err(_) => println!("LUN {lun}: no GPT"),
+                }
+            }
+        }
+        Command::ListDevices | Command::PrintGptFile { .. } | Command::SaharaInfo => {
+            unreachable!("Handled before setting up the device")
+        }
         Command::Nop => println!(
             "Your nop was {}",
             firehose_nop(&mut qdl_dev)
@@ -381,60 +1666,137 @@ fn main() -> Result<()> {
             )?;
         }
         Command::Peek { base, len } => firehose_peek(&mut qdl_dev, base, len)?,
-        Command::PrintGpt => {
-            print_partition_table(&mut qdl_dev, args.storage_slot, args.phys_part_idx)?
+        Command::Poke { base, value, width } => firehose_poke(&mut qdl_dev, base, width, value)?,
+        Command::PrintGpt { format } => print_partition_table(
+            &mut qdl_dev,
+            args.storage_slot,
+            args.phys_part_idx,
+            args.use_backup_gpt,
+            format == "json",
+        )?,
+        Command::Provision { config_file } => {
+            let path = Path::new(&config_file);
+            let xml = xmltree::Element::parse(&fs::read(path)?[..])?;
+            parse_provision_xml(&mut qdl_dev, &xml, path.parent().unwrap())?;
         }
-        Command::Reset { reset_mode } => {
-            firehose_reset(&mut qdl_dev, &FirehoseResetMode::from_str(&reset_mode)?, 0)?
+        Command::RawXml { xml_file, .. } => {
+            println!(
+                "{}",
+                "WARNING: Sending an unchecked Firehose packet".bright_red()
+            );
+            match firehose_raw_xml(&mut qdl_dev, &fs::read(&xml_file)?)? {
+                FirehoseStatus::Ack => println!("{}", "ACK".bright_green()),
+                FirehoseStatus::Nak => return Err(firehose_nak_error(&qdl_dev, "The packet")),
+            }
         }
+        Command::Reset { reset_mode } => reset_device(
+            &mut qdl_dev,
+            &FirehoseResetMode::from_str(&reset_mode)?,
+            args.storage_slot,
+            args.reset_delay,
+        )?,
+        Command::SetActiveSlot { slot } => firehose_set_active_slot(&mut qdl_dev, &slot)?,
         Command::SetBootablePart { idx } => firehose_set_bootable(&mut qdl_dev, idx)?,
+        Command::Sha256 {
+            start_sector,
+            num_sectors,
+        } => {
+            let digest = firehose_checksum_storage(
+                &mut qdl_dev,
+                num_sectors,
+                args.storage_slot,
+                args.phys_part_idx,
+                start_sector,
+            )?;
+            println!("{:02x}", digest.iter().format(""));
+        }
+        Command::StorageInfo => println!(
+            "{}",
+            firehose_get_storage_info(&mut qdl_dev, args.phys_part_idx)?
+        ),
         Command::Write {
             part_name,
             file_path,
         } => {
-            let part: gptman::GPTPartitionEntry = find_part(
+            let part = find_part(
                 &mut qdl_dev,
                 &part_name,
                 args.storage_slot,
                 args.phys_part_idx,
+                args.use_backup_gpt,
             )?;
-            let mut file = File::open(file_path)?;
-            let file_len_sectors = file
-                .metadata()?
-                .len()
-                .div_ceil(qdl_dev.fh_cfg.storage_sector_size as u64);
-            let part_len_sectors = part.ending_lba - part.starting_lba + 1;
-
-            if file_len_sectors > part_len_sectors {
-                bail!(
-                    "Partition {} is too small for the specified image ({} > {})",
-                    part_name,
-                    file_len_sectors,
-                    part_len_sectors
-                );
-            }
-
-            firehose_program_storage(
+            write_logical_partition(
                 &mut qdl_dev,
-                &mut file,
+                Path::new(&file_path),
+                &part,
                 &part_name,
-                file_len_sectors as usize,
                 args.storage_slot,
                 args.phys_part_idx,
-                &part.starting_lba.to_string(),
+                magic_check,
+            )?;
+        }
+        Command::WriteIdx { idx, file_path } => {
+            let part = find_part_by_idx(
+                &mut qdl_dev,
+                idx,
+                args.storage_slot,
+                args.phys_part_idx,
+                args.use_backup_gpt,
+            )?;
+            let label = match part.partition_name.as_str() {
+                "" => format!("#{idx}"),
+                name => name.to_owned(),
+            };
+            write_logical_partition(
+                &mut qdl_dev,
+                Path::new(&file_path),
+                &part,
+                &label,
+                args.storage_slot,
+                args.phys_part_idx,
+                magic_check,
             )?;
         }
     };
 
+    if !qdl_dev.bypassed.is_empty() {
+        println!(
+            "{}",
+            "Storage was bypassed, these writes were skipped:".bright_yellow()
+        );
+        let mut total = TransferStats::default();
+        for (label, stats) in &qdl_dev.bypassed {
+            println!("  {label}: {stats}");
+            total += *stats;
+        }
+        println!("  Total: {total}");
+    }
+
     // Finally, reset the device
+    drop(keepalive);
     qdl_dev.reset_on_drop = false;
-    firehose_reset(&mut qdl_dev, &reset_mode, 0)?;
+    if args.no_reset {
+        println!(
+            "{}",
+            "Leaving the device in Firehose mode. It won't respond to Sahara anymore, so power-cycle it (or run another Firehose client) to get it out"
+                .bright_yellow()
+        );
+        return Ok(());
+    }
+    reset_device(
+        &mut qdl_dev,
+        &reset_mode,
+        args.storage_slot,
+        args.reset_delay,
+    )?;
 
-    println!(
-        "{} {}",
-        "All went well! Resetting to".green(),
-        reset_mode.to_string().bright_yellow()
-    );
+    if !args.quiet {
+        println!(
+            "{} {}",
+            "All went well! Resetting to".green(),
+            reset_mode.to_string().bright_yellow()
+        );
+    }
 
     Ok(())
 }
@@ -1,46 +1,343 @@
 // SPDX-License-Identifier: BSD-3-Clause
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
-use anyhow::bail;
+use anyhow::{Context, anyhow, bail};
+use gptman::GPT;
 use indexmap::IndexMap;
+use itertools::Itertools;
+use owo_colors::OwoColorize;
 use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    fmt::Display,
     fs,
-    io::{Seek, SeekFrom},
+    io::{self, Read, Seek, SeekFrom, Write},
     path::Path,
+    str::FromStr,
 };
-use xmltree::{self, Element, XMLNode};
+use xmltree::{self, Element, EmitterConfig, XMLNode};
 
+use crate::magic::{MagicCheck, check_image_magic};
+use crate::util::read_gpt_from_storage;
 use qdl::{
-    firehose_checksum_storage, firehose_patch, firehose_program_storage, firehose_read_storage,
-    types::QdlChan,
+    firehose_checksum_storage, firehose_crc32_storage, firehose_firmware_write,
+    firehose_get_storage_info, firehose_patch, firehose_program_storage,
+    firehose_program_storage_sparse, firehose_provision, firehose_read_storage,
+    sparse::{is_sparse_image, parse_sparse_image},
+    types::{FirehoseError, QdlChan},
 };
 
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Where the files referenced by a program XML are looked up (e.g. a directory or an archive)
+pub trait ProgramFileSource {
+    fn exists(&self, filename: &str) -> bool;
+    fn open(&mut self, filename: &str) -> anyhow::Result<Box<dyn ReadSeek>>;
+    /// Human-readable location of `filename`, for messages
+    fn display(&self, filename: &str) -> String;
+}
+
+impl ProgramFileSource for &Path {
+    fn exists(&self, filename: &str) -> bool {
+        self.join(filename).exists()
+    }
+
+    fn open(&mut self, filename: &str) -> anyhow::Result<Box<dyn ReadSeek>> {
+        let file_path = self.join(filename);
+        let file = fs::File::open(&file_path)
+            .with_context(|| format!("Couldn't open {}", file_path.display()))?;
+
+        Ok(Box::new(file))
+    }
+
+    fn display(&self, filename: &str) -> String {
+        self.join(filename).display().to_string()
+    }
+}
+
+/// Knobs controlling how program/patch XMLs are executed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProgramXmlOptions<'a> {
+    /// Physical partition targeted by entries lacking a `physical_partition_number`,
+    /// all others are sent where they ask to, so a single file can span multiple LUNs
+    pub default_phys_part_idx: u8,
+    /// Skip \<program\> entries whose files are missing, instead of failing
+    pub allow_missing_files: bool,
+    /// Execute \<erase\> entries, instead of failing
+    pub allow_erase: bool,
+    /// Pick the bootable partition based on this slot's copy of the bootloader
+    pub slot_suffix: Option<&'a str>,
+    /// Substitute \<getcrc32digest\> for \<getsha256digest\>, for loaders lacking the latter
+    pub crc32_digests: bool,
+    /// Only validate the instructions and print what would be done
+    pub dry_run: bool,
+    /// Sniff images for the magic the target partition implies
+    pub magic_check: MagicCheck,
+    /// Note failed instructions in the [FlashReport] and carry on, instead of bailing
+    pub keep_going: bool,
+    pub verbose: bool,
+}
+
+const NUM_DISK_SECTORS: &str = "NUM_DISK_SECTORS";
+
+/// Evaluate a sector number/count attribute. Factory program files sometimes express these
+/// relative to the size of the storage (e.g. "NUM_DISK_SECTORS-33."), with the trailing dot
+/// marking a decimal number.
+fn resolve_sector_expr(attr: &str, total_sectors: Option<u64>) -> anyhow::Result<u64> {
+    let mut result: i128 = 0;
+    let mut sign = 1;
+    let mut rest = attr.trim();
+
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = rest[..end].trim();
+        let val = match term {
+            NUM_DISK_SECTORS => total_sectors.ok_or_else(|| {
+                anyhow!("Can't resolve '{attr}' without knowing the storage size")
+            })?,
+            _ => {
+                let term = term.strip_suffix('.').unwrap_or(term);
+                match term.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => term.parse::<u64>(),
+                }
+                .with_context(|| format!("Malformed sector expression '{attr}'"))?
+            }
+        };
+        result += sign * val as i128;
+
+        if end == rest.len() {
+            break;
+        }
+        sign = if rest.as_bytes()[end] == b'+' { 1 } else { -1 };
+        rest = &rest[end + 1..];
+    }
+
+    u64::try_from(result).map_err(|_| anyhow!("Sector expression '{attr}' evaluates to {result}"))
+}
+
+/// Get the mandatory attribute `name` of a \<tag\>
+fn get_attr<'a>(
+    tag: &str,
+    attrs: &'a IndexMap<String, String>,
+    name: &str,
+) -> anyhow::Result<&'a str> {
+    attrs
+        .get(name)
+        .map(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Got '<{tag}>' tag without a {name}"))
+}
+
+/// Parse the mandatory attribute `name` of a \<tag\>
+fn parse_attr<F>(tag: &str, attrs: &IndexMap<String, String>, name: &str) -> anyhow::Result<F>
+where
+    F: FromStr,
+    F::Err: Display,
+{
+    let val = get_attr(tag, attrs, name)?;
+    val.parse::<F>()
+        .map_err(|e| anyhow!("Got '<{tag}>' tag with an invalid {name} ({val:?}): {e}"))
+}
+
+/// Parse the optional attribute `name` of a \<tag\>, falling back to `default` if absent
+fn parse_attr_or<F>(
+    tag: &str,
+    attrs: &IndexMap<String, String>,
+    name: &str,
+    default: F,
+) -> anyhow::Result<F>
+where
+    F: FromStr,
+    F::Err: Display,
+{
+    match attrs.contains_key(name) {
+        true => parse_attr(tag, attrs, name),
+        false => Ok(default),
+    }
+}
+
+/// Resolve the sector attribute `name` of a \<tag\>, asking the Device for NUM_DISK_SECTORS
+/// of the given physical partition if needed (once)
+fn sector_attr<T: QdlChan>(
+    channel: &mut T,
+    disk_sectors: &mut HashMap<u8, u64>,
+    phys_part_idx: u8,
+    tag: &str,
+    attrs: &IndexMap<String, String>,
+    name: &str,
+) -> anyhow::Result<u64> {
+    let attr = get_attr(tag, attrs, name)?;
+    let total_sectors = match attr.contains(NUM_DISK_SECTORS) {
+        true => Some(match disk_sectors.entry(phys_part_idx) {
+            Entry::Occupied(e) => *e.get(),
+            Entry::Vacant(e) => {
+                let sector_size = channel.fh_config().storage_sector_size;
+                *e.insert(
+                    firehose_get_storage_info(channel, phys_part_idx)?.total_sectors(sector_size),
+                )
+            }
+        }),
+        false => None,
+    };
+
+    resolve_sector_expr(attr, total_sectors)
+        .with_context(|| format!("Got '<{tag}>' tag with an invalid {name}"))
+}
+
+fn parse_erase_cmd<T: QdlChan>(
+    channel: &mut T,
+    attrs: &IndexMap<String, String>,
+    disk_sectors: &mut HashMap<u8, u64>,
+    opts: &ProgramXmlOptions,
+) -> anyhow::Result<()> {
+    let num_sectors = get_attr("erase", attrs, "num_partition_sectors")?;
+    let slot = parse_attr_or("erase", attrs, "slot", 0)?;
+    let phys_part_idx = parse_attr_or(
+        "erase",
+        attrs,
+        "physical_partition_number",
+        opts.default_phys_part_idx,
+    )?;
+    let start_sector = get_attr("erase", attrs, "start_sector")?;
+
+    // What a dry run would do is its result, so that's printed regardless
+    if opts.dry_run || !channel.fh_config().quiet {
+        println!(
+            "{} sectors {}+{} on physical partition {}",
+            match opts.dry_run {
+                true => "Would erase".bright_red(),
+                false => "Erasing".bright_red(),
+            },
+            start_sector,
+            num_sectors,
+            phys_part_idx
+        );
+    }
+    if opts.dry_run {
+        return Ok(());
+    }
+
+    let num_sectors = sector_attr(
+        channel,
+        disk_sectors,
+        phys_part_idx,
+        "erase",
+        attrs,
+        "num_partition_sectors",
+    )? as usize;
+    let start_sector = sector_attr(
+        channel,
+        disk_sectors,
+        phys_part_idx,
+        "erase",
+        attrs,
+        "start_sector",
+    )?;
+    firehose_program_storage(
+        channel,
+        &mut io::empty(),
+        attrs.get("label").map_or("erase", |l| l.as_str()),
+        num_sectors,
+        slot,
+        phys_part_idx,
+        &start_sector.to_string(),
+    )?;
+
+    Ok(())
+}
+
+/// What to do with the sectors described by a \<read\>-like tag
+#[derive(Clone, Copy, PartialEq)]
+enum ReadMode {
+    Data,
+    Sha256,
+    Crc32,
+}
+
 fn parse_read_cmd<T: QdlChan>(
     channel: &mut T,
     out_dir: &Path,
+    tag: &str,
     attrs: &IndexMap<String, String>,
-    checksum_only: bool,
+    disk_sectors: &mut HashMap<u8, u64>,
+    opts: &ProgramXmlOptions,
+    mode: ReadMode,
 ) -> anyhow::Result<()> {
-    let num_sectors = attrs
-        .get("num_partition_sectors")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-    let slot = attrs.get("slot").map_or(0, |a| a.parse::<u8>().unwrap());
-    let phys_part_idx = attrs
-        .get("physical_partition_number")
-        .unwrap()
-        .parse::<u8>()
-        .unwrap();
-    let start_sector = attrs.get("start_sector").unwrap().parse::<u32>().unwrap();
+    let num_sectors = get_attr(tag, attrs, "num_partition_sectors")?;
+    let slot = parse_attr_or(tag, attrs, "slot", 0)?;
+    let phys_part_idx = parse_attr_or(
+        tag,
+        attrs,
+        "physical_partition_number",
+        opts.default_phys_part_idx,
+    )?;
+    let start_sector = get_attr(tag, attrs, "start_sector")?;
 
-    if checksum_only {
-        return firehose_checksum_storage(channel, num_sectors, phys_part_idx, start_sector);
+    if opts.dry_run {
+        println!(
+            "Would {} sectors {}+{} on physical partition {}{}",
+            if mode == ReadMode::Data {
+                "read"
+            } else {
+                "checksum"
+            },
+            start_sector,
+            num_sectors,
+            phys_part_idx,
+            attrs
+                .get("filename")
+                .map_or("".to_owned(), |f| format!(" into {f}"))
+        );
+        return Ok(());
     }
 
-    if !attrs.contains_key("filename") {
-        bail!("Got '<read>' tag without a filename");
+    let num_sectors = sector_attr(
+        channel,
+        disk_sectors,
+        phys_part_idx,
+        tag,
+        attrs,
+        "num_partition_sectors",
+    )? as usize;
+    let start_sector = sector_attr(
+        channel,
+        disk_sectors,
+        phys_part_idx,
+        tag,
+        attrs,
+        "start_sector",
+    )?;
+    let Ok(start_sector) = u32::try_from(start_sector) else {
+        bail!("Sector {} is out of the addressable range", start_sector);
+    };
+    match mode {
+        ReadMode::Sha256 => {
+            let digest =
+                firehose_checksum_storage(channel, num_sectors, slot, phys_part_idx, start_sector)?;
+            println!(
+                "SHA256 of sectors {}+{} on physical partition {}: {:02x}",
+                start_sector,
+                num_sectors,
+                phys_part_idx,
+                digest.iter().format("")
+            );
+            return Ok(());
+        }
+        ReadMode::Crc32 => {
+            let digest =
+                firehose_crc32_storage(channel, num_sectors, slot, phys_part_idx, start_sector)?;
+            println!(
+                "CRC32 of sectors {}+{} on physical partition {}: {:08x}",
+                start_sector, num_sectors, phys_part_idx, digest
+            );
+            return Ok(());
+        }
+        ReadMode::Data => (),
     }
-    let mut outfile = fs::File::create(out_dir.join(attrs.get("filename").unwrap()))?;
+
+    let filename = get_attr(tag, attrs, "filename")?;
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("Couldn't create output directory {}", out_dir.display()))?;
+    let mut outfile = fs::File::create(out_dir.join(filename))?;
 
     firehose_read_storage(
         channel,
@@ -49,116 +346,289 @@ fn parse_read_cmd<T: QdlChan>(
         slot,
         phys_part_idx,
         start_sector,
-    )
+    )?;
+
+    Ok(())
 }
 
 fn parse_patch_cmd<T: QdlChan>(
     channel: &mut T,
     attrs: &IndexMap<String, String>,
-    verbose: bool,
+    disk_sectors: &mut HashMap<u8, u64>,
+    opts: &ProgramXmlOptions,
 ) -> anyhow::Result<()> {
-    if let Some(filename) = attrs.get("filename") {
-        if filename != "DISK" {
-            if verbose {
-                println!("Skipping <patch> tag trying to alter {filename} on Host filesystem");
-            }
-            return Ok(());
+    let filename = get_attr("patch", attrs, "filename")?;
+    // Patches for files on the Host (e.g. to produce a flashable image) are never applied
+    if filename != "DISK" {
+        if opts.verbose {
+            println!("Skipping <patch> tag trying to alter {filename} on Host filesystem");
         }
-    } else {
-        bail!("Got '<patch>' tag without a filename");
+        return Ok(());
     }
 
-    let byte_off = attrs.get("byte_offset").unwrap().parse::<u64>().unwrap();
-    let slot = attrs.get("slot").map_or(0, |a| a.parse::<u8>().unwrap());
-    let phys_part_idx = attrs
-        .get("physical_partition_number")
-        .unwrap()
-        .parse::<u8>()
-        .unwrap();
-    let size = attrs.get("size_in_bytes").unwrap().parse::<u64>().unwrap();
-    let start_sector = attrs.get("start_sector").unwrap();
-    let val = attrs.get("value").unwrap();
+    let byte_off = parse_attr::<u64>("patch", attrs, "byte_offset")?;
+    let slot = parse_attr_or("patch", attrs, "slot", 0)?;
+    let phys_part_idx = parse_attr_or(
+        "patch",
+        attrs,
+        "physical_partition_number",
+        opts.default_phys_part_idx,
+    )?;
+    let size = parse_attr::<u64>("patch", attrs, "size_in_bytes")?;
+    let start_sector = get_attr("patch", attrs, "start_sector")?;
+    let val = get_attr("patch", attrs, "value")?;
+
+    if opts.dry_run {
+        println!(
+            "Would patch {} bytes at sector {} (+{} bytes) on physical partition {} with {}",
+            size, start_sector, byte_off, phys_part_idx, val
+        );
+        return Ok(());
+    }
 
+    let start_sector = sector_attr(
+        channel,
+        disk_sectors,
+        phys_part_idx,
+        "patch",
+        attrs,
+        "start_sector",
+    )?;
     firehose_patch(
         channel,
         byte_off,
         slot,
         phys_part_idx,
         size,
-        start_sector,
+        &start_sector.to_string(),
         val,
     )
 }
 
 const BOOTABLE_PART_NAMES: [&str; 3] = ["xbl", "xbl_a", "sbl1"];
+const BOOTABLE_PART_BASE_NAMES: [&str; 2] = ["xbl", "sbl1"];
+
+fn is_bootable_part(label: &str, slot_suffix: Option<&str>) -> bool {
+    match slot_suffix {
+        Some(suffix) => label
+            .strip_suffix(suffix)
+            .is_some_and(|l| BOOTABLE_PART_BASE_NAMES.contains(&l)),
+        None => BOOTABLE_PART_NAMES.contains(&label),
+    }
+}
+
+/// Record the physical partition holding the primary bootloader, the last one wins
+fn note_bootable_part(bootable_part_idx: &mut Option<u8>, phys_part_idx: u8, quiet: bool) {
+    if bootable_part_idx.is_some_and(|idx| idx != phys_part_idx) && !quiet {
+        eprintln!(
+            "{} {}",
+            "Multiple candidates for the primary bootloader found, using physical partition"
+                .bright_yellow(),
+            phys_part_idx
+        );
+    }
+    *bootable_part_idx = Some(phys_part_idx);
+}
+
+/// Make sure a \<program\> doesn't spill over into whatever follows the partition it targets.
+/// The Device's current GPT is used as the reference, entries whose start doesn't match it
+/// are let through. Physical partitions that the program files repartition are never
+/// checked, see [parse_program_xml].
+fn check_program_extent(
+    gpt: Option<&GPT>,
+    label: &str,
+    start_sector: u64,
+    num_sectors: usize,
+) -> anyhow::Result<()> {
+    let Some(gpt) = gpt else {
+        return Ok(());
+    };
+    let Some((_, part)) = gpt
+        .iter()
+        .find(|(_, p)| p.is_used() && p.partition_name.as_str() == label)
+    else {
+        return Ok(());
+    };
+
+    if part.starting_lba == start_sector && part.size()? < num_sectors as u64 {
+        bail!(
+            "<program> of {} ({} sectors) would overflow the partition ({} sectors)",
+            label,
+            num_sectors,
+            part.size()?
+        );
+    }
+
+    Ok(())
+}
 
 // TODO: readbackverify
+#[allow(clippy::too_many_arguments)]
 fn parse_program_cmd<T: QdlChan>(
     channel: &mut T,
-    program_file_dir: &Path,
+    files: &mut impl ProgramFileSource,
     attrs: &IndexMap<String, String>,
-    allow_missing_files: bool,
+    shared_files: &HashSet<String>,
     bootable_part_idx: &mut Option<u8>,
-    verbose: bool,
+    gpts: &mut HashMap<(u8, u8), Option<GPT>>,
+    disk_sectors: &mut HashMap<u8, u64>,
+    opts: &ProgramXmlOptions,
 ) -> anyhow::Result<()> {
-    let sector_size = attrs
-        .get("SECTOR_SIZE_IN_BYTES")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
+    let sector_size = parse_attr::<usize>("program", attrs, "SECTOR_SIZE_IN_BYTES")?;
     if sector_size != channel.fh_config().storage_sector_size {
         bail!(
             "Mismatch in storage sector size! Programfile requests {}",
             sector_size
         );
     }
-    let num_sectors = attrs
-        .get("num_partition_sectors")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-    let slot = attrs.get("slot").map_or(0, |a| a.parse::<u8>().unwrap());
-    let phys_part_idx = attrs
-        .get("physical_partition_number")
-        .unwrap()
-        .parse::<u8>()
-        .unwrap();
-    let start_sector = attrs.get("start_sector").unwrap();
-    let file_sector_offset = attrs
-        .get("file_sector_offset")
-        .unwrap_or(&"".to_owned())
-        .parse::<u32>()
-        .unwrap_or(0);
-
-    let label = attrs.get("label").unwrap();
-    if num_sectors == 0 {
-        println!("Skipping 0-length entry for {label}");
-        return Ok(());
+    let slot = parse_attr_or("program", attrs, "slot", 0)?;
+    let phys_part_idx = parse_attr_or(
+        "program",
+        attrs,
+        "physical_partition_number",
+        opts.default_phys_part_idx,
+    )?;
+    let num_sectors_attr = get_attr("program", attrs, "num_partition_sectors")?;
+    // The storage size is unknown during a dry run, disk-relative sizes are only printed then
+    let num_sectors = match opts.dry_run && num_sectors_attr.contains(NUM_DISK_SECTORS) {
+        true => None,
+        false => Some(sector_attr(
+            channel,
+            disk_sectors,
+            phys_part_idx,
+            "program",
+            attrs,
+            "num_partition_sectors",
+        )? as usize),
+    };
+    let start_sector = get_attr("program", attrs, "start_sector")?;
+    // Several entries may slice up a single file (e.g. the primary and backup GPT, both
+    // found in gpt_main0.bin), so a bogus offset mustn't silently fall back to 0
+    let file_sector_offset = match attrs.get("file_sector_offset").map(String::as_str) {
+        None | Some("") => 0,
+        Some(_) => parse_attr::<u64>("program", attrs, "file_sector_offset")?,
+    };
+
+    let label = get_attr("program", attrs, "label")?;
+    // Like the reference tool, consider every entry naming the bootloader (even ones that
+    // end up being skipped), so that the same physical partition gets marked bootable
+    if is_bootable_part(label, opts.slot_suffix) {
+        note_bootable_part(bootable_part_idx, phys_part_idx, channel.fh_config().quiet);
     }
-    if BOOTABLE_PART_NAMES.contains(&&label[..]) {
-        *bootable_part_idx = Some(phys_part_idx);
+    if num_sectors == Some(0) {
+        if !channel.fh_config().quiet {
+            println!("Skipping 0-length entry for {label}");
+        }
+        return Ok(());
     }
 
-    let filename = attrs.get("filename").unwrap();
-    let file_path = program_file_dir.join(filename);
-    if allow_missing_files {
+    let filename = get_attr("program", attrs, "filename")?;
+    if opts.allow_missing_files {
         if filename.is_empty() {
-            if verbose {
+            if opts.verbose {
                 println!("Skipping bogus entry for {label}");
             }
             return Ok(());
-        } else if !file_path.exists() {
-            if verbose {
-                println!("Skipping non-existent file {}", file_path.to_str().unwrap());
+        } else if !files.exists(filename) {
+            if opts.verbose {
+                println!("Skipping non-existent file {}", files.display(filename));
             }
             return Ok(());
         }
     }
 
-    let mut buf = fs::File::open(file_path)?;
-    buf.seek(SeekFrom::Current(
-        sector_size as i64 * file_sector_offset as i64,
-    ))?;
+    let mut buf = files.open(filename)?;
+    let file_offset = sector_size as u64 * file_sector_offset;
+    let file_len = buf.seek(SeekFrom::End(0))?;
+    if file_offset > file_len {
+        bail!(
+            "file_sector_offset {} of {} points past the end of {} ({} bytes)",
+            file_sector_offset,
+            label,
+            filename,
+            file_len
+        );
+    }
+    buf.seek(SeekFrom::Start(file_offset))?;
+
+    let sparse = is_sparse_image(&mut buf)?;
+    if !sparse {
+        check_image_magic(&mut buf, filename, label, opts.magic_check)?;
+    }
+    let data_len = match sparse {
+        true => {
+            let pos = buf.stream_position()?;
+            let len = parse_sparse_image(&mut buf)?.expanded_len;
+            buf.seek(SeekFrom::Start(pos))?;
+            len
+        }
+        false => file_len - file_offset,
+    };
+    // Files sliced up between multiple entries naturally extend past each slice
+    let data_len = match num_sectors {
+        Some(n) if shared_files.contains(filename) => data_len.min((n * sector_size) as u64),
+        _ => data_len,
+    };
+    if let Some(num_sectors) = num_sectors
+        && data_len > (num_sectors * sector_size) as u64
+    {
+        bail!(
+            "{} ({} bytes) doesn't fit in the {} sectors allotted to {}",
+            filename,
+            data_len,
+            num_sectors,
+            label
+        );
+    }
+
+    if opts.dry_run {
+        println!(
+            "Would program {} ({}{}{} {} bytes) to sectors {}+{} on physical partition {}",
+            label,
+            filename,
+            match file_sector_offset {
+                0 => String::new(),
+                n => format!(" from sector {n}"),
+            },
+            if sparse { ", sparse," } else { "," },
+            data_len,
+            start_sector,
+            num_sectors_attr,
+            phys_part_idx
+        );
+        return Ok(());
+    }
+    let num_sectors = num_sectors.unwrap();
+    let start_sector = sector_attr(
+        channel,
+        disk_sectors,
+        phys_part_idx,
+        "program",
+        attrs,
+        "start_sector",
+    )?;
+
+    // Devices with unprovisioned storage may not have a GPT at all, that's fine. Neither
+    // is a GPT being replaced by the program files, that's already noted as None here
+    let gpt = match gpts.entry((slot, phys_part_idx)) {
+        Entry::Occupied(e) => e.into_mut(),
+        Entry::Vacant(e) => {
+            e.insert(read_gpt_from_storage(channel, slot, phys_part_idx, false).ok())
+        }
+    };
+    check_program_extent(gpt.as_ref(), label, start_sector, num_sectors)?;
+
+    if sparse {
+        return firehose_program_storage_sparse(
+            channel,
+            &mut buf,
+            label,
+            num_sectors,
+            slot,
+            phys_part_idx,
+            start_sector,
+        );
+    }
 
     firehose_program_storage(
         channel,
@@ -167,22 +637,38 @@ fn parse_program_cmd<T: QdlChan>(
         num_sectors,
         slot,
         phys_part_idx,
-        start_sector,
-    )
+        &start_sector.to_string(),
+    )?;
+
+    Ok(())
+}
+
+/// Instructions that failed with [ProgramXmlOptions::keep_going] set
+#[derive(Debug, Default)]
+pub struct FlashReport {
+    /// Label (or tag name) along with the error, for every failed instruction
+    pub failed: Vec<(String, anyhow::Error)>,
+    /// Whether one of the failures was a write to the bootable partition
+    pub bootable_failed: bool,
 }
 
 // TODO: there's some funny optimizations to make here, such as OoO loading files into memory, or doing things while we're waiting on the device to finish
 pub fn parse_program_xml<T: QdlChan>(
     channel: &mut T,
     xml: &Element,
-    program_file_dir: &Path,
+    files: &mut impl ProgramFileSource,
     out_dir: &Path,
-    allow_missing_files: bool,
-    verbose: bool,
-) -> anyhow::Result<Option<u8>> {
-    let mut bootable_part_idx: Option<u8> = None;
+    opts: &ProgramXmlOptions,
+    bootable_part_idx: &mut Option<u8>,
+    report: &mut FlashReport,
+) -> anyhow::Result<()> {
+    let mut gpts = HashMap::new();
+    let mut disk_sectors = HashMap::new();
+    let mut seen_files = HashSet::new();
+    let mut shared_files = HashSet::new();
 
-    // First make sure we have all the necessary files (and fail unless specified otherwise)
+    // First make sure we have all the necessary files (and fail unless specified otherwise),
+    // and that we're allowed to wipe data if asked to
     for node in xml.children.iter() {
         if let XMLNode::Element(e) = node {
             match e.name.to_lowercase().as_str() {
@@ -192,12 +678,28 @@ pub fn parse_program_xml<T: QdlChan>(
                     }
 
                     let filename = e.attributes.get("filename").unwrap();
-                    let file_path = program_file_dir.join(filename);
-
-                    if !file_path.exists() && !allow_missing_files {
-                        bail!("{} doesn't exist!", file_path.to_str().unwrap())
+                    if !files.exists(filename) && !opts.allow_missing_files {
+                        bail!("{} doesn't exist!", files.display(filename))
+                    }
+                    if !filename.is_empty() && !seen_files.insert(filename) {
+                        shared_files.insert(filename.to_owned());
+                    }
+                    // The GPT on the Device is about to be replaced, so it says nothing about
+                    // how large the partitions being flashed are allowed to be
+                    if e.attributes.get("label").map(String::as_str) == Some("PrimaryGPT") {
+                        let slot = parse_attr_or("program", &e.attributes, "slot", 0)?;
+                        let phys_part_idx = parse_attr_or(
+                            "program",
+                            &e.attributes,
+                            "physical_partition_number",
+                            opts.default_phys_part_idx,
+                        )?;
+                        gpts.insert((slot, phys_part_idx), None);
                     }
                 }
+                "erase" if !opts.allow_erase => {
+                    bail!("Got '<erase>' tag, pass --allow-erase to execute it")
+                }
                 _ => continue,
             }
         }
@@ -206,26 +708,390 @@ pub fn parse_program_xml<T: QdlChan>(
     // At last, do the things we're supposed to do
     for node in xml.children.iter() {
         if let XMLNode::Element(e) = node {
-            match e.name.to_lowercase().as_str() {
-                "getsha256digest" => parse_read_cmd(channel, out_dir, &e.attributes, true)?,
-                "patch" => parse_patch_cmd(channel, &e.attributes, verbose)?,
+            let res = match e.name.to_lowercase().as_str() {
+                "erase" => parse_erase_cmd(channel, &e.attributes, &mut disk_sectors, opts),
+                "getcrc32digest" => parse_read_cmd(
+                    channel,
+                    out_dir,
+                    &e.name,
+                    &e.attributes,
+                    &mut disk_sectors,
+                    opts,
+                    ReadMode::Crc32,
+                ),
+                "getsha256digest" => parse_read_cmd(
+                    channel,
+                    out_dir,
+                    &e.name,
+                    &e.attributes,
+                    &mut disk_sectors,
+                    opts,
+                    match opts.crc32_digests {
+                        true => ReadMode::Crc32,
+                        false => ReadMode::Sha256,
+                    },
+                ),
+                "patch" => parse_patch_cmd(channel, &e.attributes, &mut disk_sectors, opts),
                 "program" => parse_program_cmd(
                     channel,
-                    program_file_dir,
+                    files,
+                    &e.attributes,
+                    &shared_files,
+                    bootable_part_idx,
+                    &mut gpts,
+                    &mut disk_sectors,
+                    opts,
+                ),
+                "read" => parse_read_cmd(
+                    channel,
+                    out_dir,
+                    &e.name,
                     &e.attributes,
-                    allow_missing_files,
-                    &mut bootable_part_idx,
-                    verbose,
-                )?,
-                "read" => parse_read_cmd(channel, out_dir, &e.attributes, false)?,
+                    &mut disk_sectors,
+                    opts,
+                    ReadMode::Data,
+                ),
 
                 unknown => bail!(
                     "Got unknown instruction ({}), failing to prevent damage",
                     unknown
                 ),
             };
+
+            let Err(err) = res else {
+                continue;
+            };
+            // Stopping is what the user asked for when interrupting, so don't carry on
+            if !opts.keep_going
+                || matches!(
+                    err.downcast_ref::<FirehoseError>(),
+                    Some(FirehoseError::Interrupted)
+                )
+            {
+                return Err(err);
+            }
+
+            let label = e.attributes.get("label").unwrap_or(&e.name).to_owned();
+            println!("{} {}: {}", "Failed to execute".bright_red(), label, err);
+            if e.name.eq_ignore_ascii_case("program") && is_bootable_part(&label, opts.slot_suffix)
+            {
+                report.bootable_failed = true;
+            }
+            report.failed.push((label, err));
         }
     }
 
-    Ok(bootable_part_idx)
+    Ok(())
+}
+
+/// Provision the storage medium, as described by a provisioning XML (\<ufs\> descriptors,
+/// possibly with a \<firmwarewrite\> of a file found next to the XML)
+pub fn parse_provision_xml<T: QdlChan>(
+    channel: &mut T,
+    xml: &Element,
+    xml_dir: &Path,
+) -> anyhow::Result<()> {
+    for node in xml.children.iter() {
+        if let XMLNode::Element(e) = node {
+            match e.name.to_lowercase().as_str() {
+                "firmwarewrite" => {
+                    let Some(filename) = e.attributes.get("filename") else {
+                        bail!("Got '<firmwarewrite>' tag without a filename");
+                    };
+                    let file_path = xml_dir.join(filename);
+                    let mut file = fs::File::open(&file_path).with_context(|| {
+                        format!("Couldn't open firmware file {}", file_path.display())
+                    })?;
+                    let num_sectors = file
+                        .metadata()?
+                        .len()
+                        .div_ceil(channel.fh_config().storage_sector_size as u64);
+
+                    println!("Updating storage firmware with {filename}");
+                    firehose_firmware_write(channel, &mut file, num_sectors as usize)?;
+                }
+                "ufs" => firehose_provision(channel, &e.name, &e.attributes)?,
+                unknown => bail!(
+                    "Got unknown provisioning instruction ({}), failing to prevent damage",
+                    unknown
+                ),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A single \<program\> instruction, as emitted by [write_program_xml]
+pub struct ProgramEntry {
+    pub label: String,
+    pub filename: String,
+    pub start_sector: u64,
+    pub num_sectors: u64,
+}
+
+/// Generate a rawprogram XML that [parse_program_xml] can consume
+pub fn write_program_xml(
+    out: &mut impl Write,
+    entries: &[ProgramEntry],
+    sector_size: usize,
+    slot: u8,
+    phys_part_idx: u8,
+) -> anyhow::Result<()> {
+    let mut xml = Element::new("data");
+
+    for entry in entries.iter() {
+        let mut node = Element::new("program");
+        for (k, v) in [
+            ("SECTOR_SIZE_IN_BYTES", sector_size.to_string()),
+            ("file_sector_offset", "0".to_owned()),
+            ("filename", entry.filename.to_owned()),
+            ("label", entry.label.to_owned()),
+            ("num_partition_sectors", entry.num_sectors.to_string()),
+            ("slot", slot.to_string()),
+            ("physical_partition_number", phys_part_idx.to_string()),
+            ("start_sector", entry.start_sector.to_string()),
+        ] {
+            node.attributes.insert(k.to_owned(), v);
+        }
+        xml.children.push(XMLNode::Element(node));
+    }
+
+    xml.write_with_config(out, EmitterConfig::new().perform_indent(true))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdl::mock::QdlMockDevice;
+    use qdl::types::QdlDevice;
+    use std::io::Cursor;
+
+    /// Program files kept in memory
+    struct MemFiles(HashMap<&'static str, Vec<u8>>);
+
+    impl ProgramFileSource for MemFiles {
+        fn exists(&self, filename: &str) -> bool {
+            self.0.contains_key(filename)
+        }
+
+        fn open(&mut self, filename: &str) -> anyhow::Result<Box<dyn ReadSeek>> {
+            match self.0.get(filename) {
+                Some(data) => Ok(Box::new(Cursor::new(data.clone()))),
+                None => bail!("{filename} doesn't exist"),
+            }
+        }
+
+        fn display(&self, filename: &str) -> String {
+            filename.to_owned()
+        }
+    }
+
+    /// How a Device without a partition table responds to it being looked up (primary GPT,
+    /// \<getstorageinfo\> to find the backup GPT, MBR and the sector size probe)
+    fn push_no_gpt(mock: &mut QdlMockDevice) {
+        for _ in 0..4 {
+            mock.push_nak();
+        }
+    }
+
+    fn run(
+        mock: QdlMockDevice,
+        xml: &str,
+        files: &[(&'static str, Vec<u8>)],
+        opts: &ProgramXmlOptions,
+    ) -> QdlDevice<QdlMockDevice> {
+        let mut dev = mock.into_device();
+        let xml = Element::parse(xml.as_bytes()).unwrap();
+        let mut files = MemFiles(files.iter().cloned().collect());
+
+        parse_program_xml(
+            &mut dev,
+            &xml,
+            &mut files,
+            Path::new("."),
+            opts,
+            &mut None,
+            &mut FlashReport::default(),
+        )
+        .unwrap();
+        assert!(dev.rw.is_drained());
+
+        dev
+    }
+
+    /// The Firehose commands named `tag` that were sent to the Device
+    fn sent(dev: &QdlDevice<QdlMockDevice>, tag: &str) -> Vec<Element> {
+        dev.rw
+            .written_xml()
+            .iter()
+            .map(|x| Element::parse(x.as_bytes()).unwrap())
+            .filter_map(|x| x.get_child(tag).cloned())
+            .collect()
+    }
+
+    #[test]
+    fn program_mixed_luns() {
+        let mut mock = QdlMockDevice::default();
+        for _ in 0..2 {
+            push_no_gpt(&mut mock);
+            mock.push_rawmode_ack().push_ack();
+        }
+        let xml = r#"<data>
+            <program SECTOR_SIZE_IN_BYTES="512" filename="a.img" label="a" num_partition_sectors="1" physical_partition_number="1" start_sector="6" />
+            <program SECTOR_SIZE_IN_BYTES="512" filename="b.img" label="b" num_partition_sectors="1" physical_partition_number="4" start_sector="6" />
+        </data>"#;
+        let opts = ProgramXmlOptions {
+            default_phys_part_idx: 0,
+            ..Default::default()
+        };
+
+        let dev = run(
+            mock,
+            xml,
+            &[("a.img", vec![1; 512]), ("b.img", vec![2; 512])],
+            &opts,
+        );
+        let luns = sent(&dev, "program")
+            .iter()
+            .map(|p| p.attributes["physical_partition_number"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(luns, ["1", "4"]);
+    }
+
+    #[test]
+    fn program_file_sector_offset() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_rawmode_ack().push_ack();
+        mock.push_rawmode_ack().push_ack();
+        // Primary GPT at the start of storage, backup at the end, both from a single file
+        let xml = r#"<data>
+            <program SECTOR_SIZE_IN_BYTES="512" file_sector_offset="0" filename="gpt_main0.bin" label="PrimaryGPT" num_partition_sectors="2" physical_partition_number="0" start_sector="0" />
+            <program SECTOR_SIZE_IN_BYTES="512" file_sector_offset="2" filename="gpt_main0.bin" label="BackupGPT" num_partition_sectors="3" physical_partition_number="0" start_sector="1000" />
+        </data>"#;
+        let gpt = (0..5 * 512).map(|i| (i / 512) as u8).collect::<Vec<_>>();
+
+        let dev = run(
+            mock,
+            xml,
+            &[("gpt_main0.bin", gpt.clone())],
+            &Default::default(),
+        );
+        let data = dev
+            .rw
+            .written
+            .iter()
+            .filter(|w| !w.is_empty() && !w.starts_with(b"<?xml"))
+            .collect::<Vec<_>>();
+        assert_eq!(data, [&gpt[..2 * 512], &gpt[2 * 512..]]);
+    }
+
+    #[test]
+    fn default_phys_part_idx() {
+        let mut mock = QdlMockDevice::default();
+        push_no_gpt(&mut mock);
+        mock.push_rawmode_ack().push_ack();
+        mock.push_ack();
+        mock.push_log(&format!("Digest {}", "0".repeat(64)))
+            .push_ack();
+        let xml = r#"<data>
+            <program SECTOR_SIZE_IN_BYTES="512" filename="a.img" label="a" num_partition_sectors="1" start_sector="6" />
+            <patch SECTOR_SIZE_IN_BYTES="512" byte_offset="0" filename="DISK" size_in_bytes="4" start_sector="7" value="0" />
+            <getsha256digest SECTOR_SIZE_IN_BYTES="512" num_partition_sectors="1" start_sector="6" />
+        </data>"#;
+        let opts = ProgramXmlOptions {
+            default_phys_part_idx: 2,
+            ..Default::default()
+        };
+
+        let dev = run(mock, xml, &[("a.img", vec![1; 512])], &opts);
+        for tag in ["program", "patch", "getsha256digest"] {
+            let cmds = sent(&dev, tag);
+            assert_eq!(cmds.len(), 1, "{tag}");
+            assert_eq!(
+                cmds[0].attributes["physical_partition_number"], "2",
+                "{tag}"
+            );
+        }
+    }
+
+    #[test]
+    fn patch_host_file_skipped() {
+        let xml = r#"<data>
+            <patch SECTOR_SIZE_IN_BYTES="512" byte_offset="0" filename="somefile.img" physical_partition_number="0" size_in_bytes="4" start_sector="7" value="0" />
+            <patch SECTOR_SIZE_IN_BYTES="512" byte_offset="8" filename="DISK" physical_partition_number="0" size_in_bytes="4" start_sector="7" value="0" />
+        </data>"#;
+
+        for verbose in [false, true] {
+            let mut mock = QdlMockDevice::default();
+            mock.push_ack();
+            let opts = ProgramXmlOptions {
+                verbose,
+                ..Default::default()
+            };
+
+            let dev = run(mock, xml, &[], &opts);
+            let patches = sent(&dev, "patch");
+            assert_eq!(patches.len(), 1);
+            assert_eq!(patches[0].attributes["byte_offset"], "8");
+        }
+    }
+
+    #[test]
+    fn program_grown_partition_with_new_gpt() {
+        // Nothing but the writes is scripted, looking up the (soon to be stale) GPT on the
+        // Device would desync the exchange
+        let mut mock = QdlMockDevice::default();
+        for _ in 0..2 {
+            mock.push_rawmode_ack().push_ack();
+        }
+        let xml = r#"<data>
+            <program SECTOR_SIZE_IN_BYTES="512" filename="gpt_main0.bin" label="PrimaryGPT" num_partition_sectors="2" physical_partition_number="0" start_sector="0" />
+            <program SECTOR_SIZE_IN_BYTES="512" filename="boot.img" label="boot" num_partition_sectors="8" physical_partition_number="0" start_sector="34" />
+        </data>"#;
+
+        let dev = run(
+            mock,
+            xml,
+            &[
+                ("gpt_main0.bin", vec![0; 2 * 512]),
+                ("boot.img", vec![1; 8 * 512]),
+            ],
+            &Default::default(),
+        );
+        assert_eq!(sent(&dev, "read").len(), 0);
+        assert_eq!(sent(&dev, "program").len(), 2);
+    }
+
+    #[test]
+    fn sector_exprs() {
+        for (attr, total_sectors, expected) in [
+            ("NUM_DISK_SECTORS-33.", Some(1000), 967),
+            ("NUM_DISK_SECTORS-5", Some(1000), 995),
+            ("0x10+2", None, 18),
+            ("0x10 + 2", None, 18),
+            ("34.", None, 34),
+            ("34", None, 34),
+            ("6.+2.", None, 8),
+            ("NUM_DISK_SECTORS", Some(1000), 1000),
+        ] {
+            assert_eq!(
+                resolve_sector_expr(attr, total_sectors).unwrap(),
+                expected,
+                "{attr}"
+            );
+        }
+
+        for (attr, total_sectors) in [
+            ("NUM_DISK_SECTORS-33.", Some(10)),
+            ("1-2", None),
+            ("NUM_DISK_SECTORS-33.", None),
+            ("0xzz", None),
+            ("", None),
+        ] {
+            assert!(resolve_sector_expr(attr, total_sectors).is_err(), "{attr}");
+        }
+    }
 }
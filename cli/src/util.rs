@@ -1,16 +1,137 @@
 // SPDX-License-Identifier: BSD-3-Clause
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
+use flate2::{Compression, write::GzEncoder};
 use gptman::{self, GPT, GPTHeader, GPTPartitionEntry};
+use itertools::Itertools;
 use owo_colors::OwoColorize;
-use std::io::{Cursor, Error, ErrorKind, Seek, Write};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cmp::{max, min};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{ChildStdin, Command, Stdio};
+use std::time::Instant;
 
-use qdl::{self, firehose_read_storage, types::QdlChan};
+use crate::magic::{MagicCheck, check_image_magic};
+use crate::programfile::{ProgramEntry, write_program_xml};
+use qdl::{
+    self, firehose_get_storage_info, firehose_program_storage, firehose_program_storage_sparse,
+    firehose_read_storage, firehose_reset, firehose_write_bootloader_message,
+    sparse::is_sparse_image,
+    types::{FirehoseResetMode, ProgressEvent, QdlChan, UsbIdFilter},
+    usb::list_edl_devices,
+};
 
+/// Read the GPT of a physical partition, falling back to the backup copy if the
+/// primary one can't be parsed (or straight away, with `use_backup` set), and then
+/// to a legacy MBR partition table
 pub fn read_gpt_from_storage<T: QdlChan>(
     channel: &mut T,
     slot: u8,
     phys_part_idx: u8,
+    use_backup: bool,
+) -> Result<GPT> {
+    if use_backup {
+        return read_backup_gpt_from_storage(channel, slot, phys_part_idx)
+            .map_err(|e| explain_gpt_error(channel, slot, phys_part_idx, e));
+    }
+
+    let primary_err = match read_primary_gpt_from_storage(channel, slot, phys_part_idx) {
+        Ok(gpt) => return Ok(gpt),
+        Err(e) => e,
+    };
+    // Kept off stdout, where e.g. print-gpt --format json emits its results
+    if !channel.fh_config().quiet {
+        eprintln!(
+            "{} ({}), {}",
+            "Couldn't read the primary GPT".bright_yellow(),
+            primary_err,
+            "trying the backup one".bright_yellow()
+        );
+    }
+    let backup_err = match read_backup_gpt_from_storage(channel, slot, phys_part_idx) {
+        Ok(gpt) => return Ok(gpt),
+        Err(e) => e,
+    };
+
+    // Some older (e.g. eMMC) devices come with a plain MBR instead. Only looked for once
+    // both GPT copies are gone, as a GPT disk's protective MBR parses just fine.
+    match read_mbr_from_storage(channel, slot, phys_part_idx) {
+        Ok(Some(gpt)) => return Ok(gpt),
+        Ok(None) => (),
+        Err(e) if !channel.fh_config().quiet => {
+            eprintln!("{} ({})", "Couldn't read an MBR either".bright_yellow(), e)
+        }
+        Err(_) => (),
+    }
+
+    let err = anyhow!(
+        "Couldn't read the primary ({}) or the backup ({}) GPT",
+        primary_err,
+        backup_err
+    );
+    Err(explain_gpt_error(channel, slot, phys_part_idx, err))
+}
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// Number of entries in a standard GPT partition entry array
+const GPT_MAX_PARTITIONS: u32 = 128;
+/// Protective MBR, primary header, partition entry array and the backup copies thereof
+const GPT_MIN_SECTORS: u64 = 1 + 2 * 33;
+const COMMON_SECTOR_SIZES: [usize; 2] = [512, 4096];
+
+/// A wrong --sector-size makes for some obscure GPT parsing errors, so point it out if
+/// it's likely what's behind `err`. The GPT header lives in sector 1, so if its signature
+/// is missing at the offset implied by the configured sector size but shows up at another
+/// common one, that's most likely what the storage actually uses.
+fn explain_gpt_error<T: QdlChan>(
+    channel: &mut T,
+    slot: u8,
+    phys_part_idx: u8,
+    err: anyhow::Error,
+) -> anyhow::Error {
+    let sector_size = channel.fh_config().storage_sector_size;
+    let probe_len = 2 * COMMON_SECTOR_SIZES.iter().max().unwrap();
+
+    // Best effort, `err` says it all if the storage can't even be read
+    let mut buf = Vec::<u8>::new();
+    if firehose_read_storage(
+        channel,
+        &mut buf,
+        probe_len.div_ceil(sector_size),
+        slot,
+        phys_part_idx,
+        0,
+    )
+    .is_err()
+    {
+        return err;
+    }
+
+    let has_signature = |off: usize| buf.get(off..off + GPT_SIGNATURE.len()) == Some(GPT_SIGNATURE);
+    if has_signature(sector_size) {
+        return err;
+    }
+    match COMMON_SECTOR_SIZES
+        .into_iter()
+        .find(|&n| n != sector_size && has_signature(n))
+    {
+        Some(actual) => anyhow!(
+            "{}. There's a GPT assuming {}-byte sectors though, try --sector-size {}",
+            err,
+            actual,
+            actual
+        ),
+        None => err,
+    }
+}
+
+fn read_primary_gpt_from_storage<T: QdlChan>(
+    channel: &mut T,
+    slot: u8,
+    phys_part_idx: u8,
 ) -> Result<GPT> {
     let mut buf = Cursor::new(Vec::<u8>::new());
 
@@ -31,9 +152,280 @@ pub fn read_gpt_from_storage<T: QdlChan>(
     buf.rewind()?;
     firehose_read_storage(channel, &mut buf, gpt_len, slot, phys_part_idx, 0)?;
 
-    // Ignore the aforementioned MBR sector
-    buf.set_position(channel.fh_config().storage_sector_size as u64);
-    GPT::read_from(&mut buf, channel.fh_config().storage_sector_size as u64).map_err(|e| e.into())
+    read_gpt_from_image(
+        &mut buf,
+        Some(channel.fh_config().storage_sector_size as u64),
+    )
+}
+
+/// Parse the GPT of a storage image (or any other stream starting at sector 0)
+/// Without a `sector_size`, both 512- and 4096-byte sectors are tried
+pub fn read_gpt_from_image(
+    image: &mut (impl Read + Seek),
+    sector_size: Option<u64>,
+) -> Result<GPT> {
+    match sector_size {
+        Some(n) => GPT::read_from(image, n),
+        None => GPT::find_from(image),
+    }
+    .map_err(|e| anyhow!("Couldn't parse the GPT: {}", e))
+}
+
+fn read_backup_gpt_from_storage<T: QdlChan>(
+    channel: &mut T,
+    slot: u8,
+    phys_part_idx: u8,
+) -> Result<GPT> {
+    // Finding it requires knowing where storage ends
+    if slot != 0 {
+        bail!(
+            "The backup GPT can't be located on secondary storage devices, <getstorageinfo> can't address them"
+        );
+    }
+
+    let sector_size = channel.fh_config().storage_sector_size as u64;
+    let total_sectors =
+        firehose_get_storage_info(channel, phys_part_idx)?.total_sectors(sector_size as usize);
+    if total_sectors == 0 {
+        bail!("Couldn't determine the size of physical partition {phys_part_idx}");
+    }
+    let to_u32 = |n: u64| {
+        u32::try_from(n).map_err(|_| anyhow!("Sector {} is out of the addressable range", n))
+    };
+
+    // The backup GPT header lives in the very last sector..
+    let mut buf = Cursor::new(Vec::<u8>::new());
+    firehose_read_storage(
+        channel,
+        &mut buf,
+        1,
+        slot,
+        phys_part_idx,
+        to_u32(total_sectors - 1)?,
+    )?;
+
+    buf.rewind()?;
+    let header = match GPTHeader::read_from(&mut buf) {
+        Ok(h) => h,
+        Err(e) => bail!("Couldn't parse the backup GPT header: {}", e),
+    };
+
+    // ..and is preceded by the partition entry array
+    let tail_start = header.partition_entry_lba;
+    if tail_start >= total_sectors {
+        bail!("Backup GPT header points past the end of storage");
+    }
+    let mut tail = Vec::<u8>::new();
+    firehose_read_storage(
+        channel,
+        &mut tail,
+        (total_sectors - tail_start) as usize,
+        slot,
+        phys_part_idx,
+        to_u32(tail_start)?,
+    )?;
+
+    let mut disk = StorageTail {
+        data: tail,
+        offset: tail_start * sector_size,
+        len: total_sectors * sector_size,
+        pos: 0,
+    };
+    GPT::read_from(&mut disk, sector_size).map_err(|e| e.into())
+}
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PART_TABLE_OFFSET: usize = 446;
+const MBR_PART_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Stands in for the GPT signature in the header of a table converted from an MBR
+const MBR_HEADER_SIGNATURE: [u8; 8] = *b"MBR PART";
+/// "Legacy BIOS bootable", the GPT counterpart of the MBR active flag
+const GPT_ATTR_LEGACY_BOOTABLE: u64 = 1 << 2;
+
+#[derive(Clone, Copy)]
+struct MbrEntry {
+    status: u8,
+    kind: u8,
+    start: u32,
+    len: u32,
+}
+
+/// Parse the partition entries of an MBR (or EBR) sector, if that's what it is
+fn parse_mbr_entries(sector: &[u8]) -> Option<[MbrEntry; 4]> {
+    if sector.get(510..512) != Some(&MBR_SIGNATURE[..]) {
+        return None;
+    }
+
+    let entries: [MbrEntry; 4] = std::array::from_fn(|i| {
+        let e = &sector[MBR_PART_TABLE_OFFSET + i * MBR_PART_ENTRY_SIZE..][..MBR_PART_ENTRY_SIZE];
+        MbrEntry {
+            status: e[0],
+            kind: e[4],
+            start: u32::from_le_bytes(e[8..12].try_into().unwrap()),
+            len: u32::from_le_bytes(e[12..16].try_into().unwrap()),
+        }
+    });
+
+    // Also rules out e.g. FAT boot sectors, which end with the same signature
+    match entries.iter().all(|e| e.status & 0x7f == 0) {
+        true => Some(entries),
+        false => None,
+    }
+}
+
+/// Whether `gpt` was converted from an MBR by [read_mbr_from_storage]
+fn is_from_mbr(gpt: &GPT) -> bool {
+    gpt.header.signature == MBR_HEADER_SIGNATURE
+}
+
+/// Read a legacy MBR partition table, converted into a GPT so that it can be used the same
+/// way. Partitions are named after their Linux numbering (p1-p4 for primary ones, logical
+/// ones from p5 onwards), the first byte of the type GUID holds the MBR partition type and
+/// the active flag maps onto the legacy BIOS bootable attribute.
+///
+/// Returns `None` if sector 0 doesn't hold an MBR, or only a protective one.
+fn read_mbr_from_storage<T: QdlChan>(
+    channel: &mut T,
+    slot: u8,
+    phys_part_idx: u8,
+) -> Result<Option<GPT>> {
+    let read_sector = |channel: &mut T, sector: u32| -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        firehose_read_storage(channel, &mut buf, 1, slot, phys_part_idx, sector)?;
+        Ok(buf)
+    };
+
+    let Some(mbr) = parse_mbr_entries(&read_sector(channel, 0)?) else {
+        return Ok(None);
+    };
+    if mbr.iter().all(|e| e.kind == 0) || mbr.iter().any(|e| e.kind == MBR_TYPE_PROTECTIVE) {
+        return Ok(None);
+    }
+
+    // (index, absolute starting sector, entry)
+    let mut parts = Vec::<(u32, u64, MbrEntry)>::new();
+    let mut extended = None;
+    for (i, e) in mbr.iter().enumerate() {
+        if MBR_TYPES_EXTENDED.contains(&e.kind) {
+            extended = Some(e.start);
+        } else if e.kind != 0 && e.len != 0 {
+            parts.push((i as u32 + 1, e.start as u64, *e));
+        }
+    }
+
+    // Logical partitions are chained through an EBR preceding each one
+    if let Some(ext_start) = extended {
+        let mut ebr_sector = ext_start;
+        for idx in 5.. {
+            let Some(ebr) = parse_mbr_entries(&read_sector(channel, ebr_sector)?) else {
+                bail!("Sector {} doesn't hold a valid EBR", ebr_sector);
+            };
+            if ebr[0].kind != 0 && ebr[0].len != 0 {
+                // A GPT only has room for so many partitions, this also breaks EBR loops
+                if idx > GPT_MAX_PARTITIONS {
+                    bail!("The MBR has more than {} partitions", GPT_MAX_PARTITIONS);
+                }
+                parts.push((idx, ebr_sector as u64 + ebr[0].start as u64, ebr[0]));
+            }
+            if !MBR_TYPES_EXTENDED.contains(&ebr[1].kind) {
+                break;
+            }
+            ebr_sector = ext_start
+                .checked_add(ebr[1].start)
+                .ok_or(anyhow!("EBR {} points past the end of storage", ebr_sector))?;
+        }
+    }
+
+    let sector_size = channel.fh_config().storage_sector_size as u64;
+    let end = parts
+        .iter()
+        .map(|(_, start, e)| start + e.len as u64)
+        .max()
+        .unwrap_or(1);
+    let total_sectors = match slot {
+        0 => firehose_get_storage_info(channel, phys_part_idx)
+            .ok()
+            .map(|info| info.total_sectors(sector_size as usize)),
+        _ => None,
+    }
+    .unwrap_or(end)
+    .max(end);
+
+    // gptman wants room for an actual GPT, the header is fixed up below anyway
+    let mut gpt = GPT::new_from(
+        &mut StorageTail {
+            data: Vec::new(),
+            offset: 0,
+            len: total_sectors.max(GPT_MIN_SECTORS) * sector_size,
+            pos: 0,
+        },
+        sector_size,
+        [0; 16],
+    )?;
+    gpt.header.signature = MBR_HEADER_SIGNATURE;
+    gpt.header.primary_lba = 0;
+    gpt.header.backup_lba = total_sectors - 1;
+    gpt.header.first_usable_lba = 1;
+    gpt.header.last_usable_lba = total_sectors - 1;
+
+    for (idx, start, e) in parts {
+        let mut partition_type_guid = [0; 16];
+        partition_type_guid[0] = e.kind;
+
+        gpt[idx] = GPTPartitionEntry {
+            partition_type_guid,
+            unique_partition_guid: [0; 16],
+            starting_lba: start,
+            ending_lba: start + e.len as u64 - 1,
+            attribute_bits: match e.status {
+                0x80 => GPT_ATTR_LEGACY_BOOTABLE,
+                _ => 0,
+            },
+            partition_name: format!("p{idx}").as_str().into(),
+        };
+    }
+
+    Ok(Some(gpt))
+}
+
+/// Presents the end of a physical partition as if it were the entire thing (reading
+/// zeroes everywhere else), so that gptman finds the backup GPT at the expected offsets
+struct StorageTail {
+    data: Vec<u8>,
+    offset: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl Read for StorageTail {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = min(buf.len() as u64, self.len.saturating_sub(self.pos)) as usize;
+        for (i, b) in buf[..n].iter_mut().enumerate() {
+            *b = (self.pos + i as u64)
+                .checked_sub(self.offset)
+                .and_then(|off| self.data.get(off as usize))
+                .copied()
+                .unwrap_or(0);
+        }
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Seek for StorageTail {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+        }
+        .ok_or(Error::from(ErrorKind::InvalidInput))?;
+
+        Ok(self.pos)
+    }
 }
 
 pub fn find_part<T: QdlChan>(
@@ -41,28 +433,228 @@ pub fn find_part<T: QdlChan>(
     name: &str,
     slot: u8,
     phys_part_idx: u8,
+    use_backup_gpt: bool,
 ) -> Result<GPTPartitionEntry> {
-    match read_gpt_from_storage(channel, slot, phys_part_idx)?
+    let gpt = read_gpt_from_storage(channel, slot, phys_part_idx, use_backup_gpt)?;
+    if let Some((_, p)) = gpt
         .iter()
         .find(|(_, p)| p.partition_name.to_string() == name)
     {
-        Some(p) => Ok(p.1.clone()),
-        None => Err(Error::from(ErrorKind::NotFound).into()),
+        return Ok(p.clone());
+    }
+
+    let names = gpt
+        .iter()
+        .filter(|(_, p)| p.is_used())
+        .map(|(_, p)| p.partition_name.to_string())
+        .collect::<Vec<_>>();
+    let closest = names.iter().min_by_key(|n| {
+        let (n, name) = (n.to_lowercase(), name.to_lowercase());
+        // Favor e.g. "system_a" for "system"
+        match n.starts_with(&name) || name.starts_with(&n) {
+            true => 0,
+            false => edit_distance(&n, &name),
+        }
+    });
+
+    bail!(
+        "Partition {} not found on physical partition {}{}\nAvailable partitions: {}",
+        name,
+        phys_part_idx,
+        closest.map_or("".to_owned(), |c| format!(", did you mean {c}?")),
+        names.join(", ")
+    )
+}
+
+/// Look for a partition on every physical partition (e.g. UFS LUN), returning the index
+/// of the one it was found on along with its entry
+pub fn find_part_on_any_lun<T: QdlChan>(
+    channel: &mut T,
+    name: &str,
+    slot: u8,
+) -> Result<(u8, GPTPartitionEntry)> {
+    let info = firehose_get_storage_info(channel, 0)?;
+    for lun in 0..info.num_physical.unwrap_or(1).max(1) {
+        let Ok(gpt) = read_gpt_from_storage(channel, slot, lun, false) else {
+            continue;
+        };
+        if let Some((_, p)) = gpt.iter().find(|(_, p)| p.partition_name.as_str() == name) {
+            return Ok((lun, p.clone()));
+        }
+    }
+
+    bail!("Partition {} not found on any physical partition", name)
+}
+
+/// Reset the Device, first asking the bootloader for the requested boot target (fastboot,
+/// recovery) through the misc partition if need be
+pub fn reset_device<T: QdlChan>(
+    channel: &mut T,
+    mode: &FirehoseResetMode,
+    slot: u8,
+    delay: u32,
+) -> Result<()> {
+    if let Some(command) = mode.bootloader_command() {
+        let (lun, misc) = find_part_on_any_lun(channel, "misc", slot)
+            .map_err(|e| anyhow!("Can't reset to {}: {}", mode, e))?;
+        firehose_write_bootloader_message(
+            channel,
+            command,
+            slot,
+            lun,
+            u32::try_from(misc.starting_lba)?,
+        )?;
+    }
+
+    firehose_reset(channel, mode, delay)
+}
+
+/// Same as [find_part], but looks the partition up by its GPT entry index
+pub fn find_part_by_idx<T: QdlChan>(
+    channel: &mut T,
+    idx: u32,
+    slot: u8,
+    phys_part_idx: u8,
+    use_backup_gpt: bool,
+) -> Result<GPTPartitionEntry> {
+    let gpt = read_gpt_from_storage(channel, slot, phys_part_idx, use_backup_gpt)?;
+    match gpt.iter().find(|(i, _)| *i == idx) {
+        Some((_, p)) if p.is_used() => Ok(p.clone()),
+        _ => bail!(
+            "There's no partition #{} on physical partition {}",
+            idx,
+            phys_part_idx
+        ),
+    }
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let prev = row[j + 1];
+            row[j + 1] = min(min(row[j], prev) + 1, diag + (ca != *cb) as usize);
+            diag = prev;
+        }
     }
+
+    row[b.len()]
+}
+
+/// Machine-readable representation of a GPT entry
+#[derive(Serialize)]
+pub struct GptEntryInfo {
+    pub index: u32,
+    pub name: String,
+    pub starting_lba: u64,
+    pub ending_lba: u64,
+    pub size_bytes: Option<u64>,
+    pub partition_type_guid: String,
+    pub unique_partition_guid: String,
+    pub attribute_bits: u64,
+}
+
+impl GptEntryInfo {
+    fn new(index: u32, part: &GPTPartitionEntry, sector_size: u64) -> Self {
+        GptEntryInfo {
+            index,
+            name: part.partition_name.to_string(),
+            starting_lba: part.starting_lba,
+            ending_lba: part.ending_lba,
+            size_bytes: part.size().ok().map(|s| s * sector_size),
+            partition_type_guid: format_guid(&part.partition_type_guid),
+            unique_partition_guid: format_guid(&part.unique_partition_guid),
+            attribute_bits: part.attribute_bits,
+        }
+    }
+}
+
+/// Format a GUID stored in the on-disk (mixed-endian) layout
+fn format_guid(guid: &[u8; 16]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}-{:02X}",
+        u32::from_le_bytes(guid[0..4].try_into().unwrap()),
+        u16::from_le_bytes(guid[4..6].try_into().unwrap()),
+        u16::from_le_bytes(guid[6..8].try_into().unwrap()),
+        guid[8..10].iter().format(""),
+        guid[10..16].iter().format("")
+    )
 }
 
 pub fn print_partition_table<T: QdlChan>(
     channel: &mut T,
     slot: u8,
     phys_part_idx: u8,
+    use_backup_gpt: bool,
+    json: bool,
 ) -> Result<()> {
-    let gpt = read_gpt_from_storage(channel, slot, phys_part_idx)?;
+    let gpt = read_gpt_from_storage(channel, slot, phys_part_idx, use_backup_gpt)?;
 
-    println!(
-        "GPT on physical partition {} of {}:",
-        phys_part_idx.bright_yellow(),
-        channel.fh_config().storage_type.to_string().bright_yellow()
-    );
+    if !json {
+        println!(
+            "{} on physical partition {} of {}:",
+            match is_from_mbr(&gpt) {
+                true => "MBR",
+                false => "GPT",
+            },
+            phys_part_idx.bright_yellow(),
+            channel.fh_config().storage_type.to_string().bright_yellow()
+        );
+    }
+
+    print_gpt(&gpt, json)?;
+    if !json {
+        let sector_size = channel.fh_config().storage_sector_size;
+        let total_sectors = match slot {
+            0 => firehose_get_storage_info(channel, phys_part_idx)
+                .ok()
+                .map(|info| info.total_sectors(sector_size)),
+            _ => None,
+        };
+        print_gpt_usage(&gpt, total_sectors);
+    }
+
+    Ok(())
+}
+
+/// Same as [print_partition_table], but for a local storage image
+pub fn print_partition_table_from_image(
+    path: &Path,
+    sector_size: Option<usize>,
+    json: bool,
+) -> Result<()> {
+    let gpt = read_gpt_from_image(&mut File::open(path)?, sector_size.map(|n| n as u64))?;
+
+    if !json {
+        println!(
+            "GPT in {} ({}-byte sectors):",
+            path.display().bright_yellow(),
+            gpt.sector_size
+        );
+    }
+
+    print_gpt(&gpt, json)?;
+    if !json {
+        print_gpt_usage(&gpt, None);
+    }
+
+    Ok(())
+}
+
+fn print_gpt(gpt: &GPT, json: bool) -> Result<()> {
+    if json {
+        let entries = gpt
+            .iter()
+            .map(|(idx, part)| GptEntryInfo::new(idx, part, gpt.sector_size))
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
 
     for (idx, part) in gpt.iter() {
         let size = part.size();
@@ -86,27 +678,917 @@ pub fn print_partition_table<T: QdlChan>(
     Ok(())
 }
 
+fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 * 1024 {
+        format!("{:.2} GiB", bytes as f64 / (1024 * 1024 * 1024) as f64)
+    } else {
+        format!("{:.2} MiB", bytes as f64 / (1024 * 1024) as f64)
+    }
+}
+
+/// Print how much of the storage is taken up by partitions
+///
+/// `total_sectors` falls back to the extent described by the GPT header.
+fn print_gpt_usage(gpt: &GPT, total_sectors: Option<u64>) {
+    let total = total_sectors.unwrap_or(max(gpt.header.primary_lba, gpt.header.backup_lba) + 1);
+    let used = gpt
+        .iter()
+        .filter(|(_, part)| part.is_used())
+        .filter_map(|(_, part)| part.size().ok())
+        .sum::<u64>();
+    let usable = gpt.header.last_usable_lba - gpt.header.first_usable_lba + 1;
+    let free = usable.saturating_sub(used);
+
+    println!();
+    for (desc, sectors) in [
+        ("Total", total),
+        ("Partitioned", used),
+        ("Unallocated", free),
+    ] {
+        println!(
+            "{:<12} {} sectors ({})",
+            format!("{desc}:"),
+            sectors,
+            format_size(sectors * gpt.sector_size)
+        );
+    }
+}
+
+/// Dump a partition, skipping the first `start_offset` sectors (e.g. to resume an earlier dump)
 pub fn read_storage_logical_partition<T: QdlChan>(
     channel: &mut T,
     out: &mut impl Write,
-    name: &str,
+    part: &GPTPartitionEntry,
     slot: u8,
     phys_part_idx: u8,
+    start_offset: usize,
 ) -> Result<()> {
-    let gpt = read_gpt_from_storage(channel, slot, phys_part_idx)?;
-
-    let part = gpt
-        .iter()
-        .find(|(_, p)| p.partition_name.as_str() == name)
-        .ok_or(Error::from(ErrorKind::NotFound))?
-        .1;
+    let name = part.partition_name.as_str();
+    let part_len = (part.ending_lba - part.starting_lba + 1) as usize;
+    if start_offset > part_len {
+        bail!(
+            "Partition {} is smaller than the existing dump ({} > {} sectors)",
+            name,
+            start_offset,
+            part_len
+        );
+    } else if start_offset == part_len {
+        println!("Partition {name} has already been dumped, skipping");
+        return Ok(());
+    }
 
     firehose_read_storage(
         channel,
         out,
-        (part.ending_lba - part.starting_lba + 1) as usize,
+        part_len - start_offset,
+        slot,
+        phys_part_idx,
+        (part.starting_lba as usize + start_offset) as u32,
+    )?;
+
+    Ok(())
+}
+
+/// Open the output file for a partition dump
+///
+/// With `resume` set, an existing file is kept and positioned right after its last
+/// complete sector. The number of such sectors is returned alongside the file.
+pub fn open_dump_file(path: &Path, resume: bool, sector_size: usize) -> Result<(File, usize)> {
+    if !resume || !path.exists() {
+        return Ok((File::create(path)?, 0));
+    }
+
+    let mut f = OpenOptions::new().write(true).open(path)?;
+    let done_sectors = f.metadata()?.len() as usize / sector_size;
+
+    // A partially written trailing sector can't be trusted, re-read it
+    f.set_len((done_sectors * sector_size) as u64)?;
+    f.seek(SeekFrom::End(0))?;
+
+    if done_sectors > 0 {
+        println!(
+            "Resuming {} after {} sectors",
+            path.display(),
+            done_sectors.bright_blue()
+        );
+    }
+
+    Ok((f, done_sectors))
+}
+
+/// Flash an image (raw or sparse) onto a partition, making sure it fits
+pub fn write_logical_partition<T: QdlChan>(
+    channel: &mut T,
+    file_path: &Path,
+    part: &GPTPartitionEntry,
+    label: &str,
+    slot: u8,
+    phys_part_idx: u8,
+    magic_check: MagicCheck,
+) -> Result<()> {
+    let mut file = File::open(file_path)?;
+    let part_len_sectors = part.ending_lba - part.starting_lba + 1;
+
+    if is_sparse_image(&mut file)? {
+        firehose_program_storage_sparse(
+            channel,
+            &mut file,
+            label,
+            part_len_sectors as usize,
+            slot,
+            phys_part_idx,
+            part.starting_lba,
+        )?;
+    } else {
+        check_image_magic(
+            &mut file,
+            &file_path.display().to_string(),
+            label,
+            magic_check,
+        )?;
+        let file_len_sectors = file
+            .metadata()?
+            .len()
+            .div_ceil(channel.fh_config().storage_sector_size as u64);
+
+        if file_len_sectors > part_len_sectors {
+            bail!(
+                "Partition {} is too small for the specified image ({} > {})",
+                label,
+                file_len_sectors,
+                part_len_sectors
+            );
+        }
+
+        firehose_program_storage(
+            channel,
+            &mut file,
+            label,
+            file_len_sectors as usize,
+            slot,
+            phys_part_idx,
+            &part.starting_lba.to_string(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Passes data through, computing its SHA256 digest along the way
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Account for data that's already there (e.g. when resuming a dump)
+    pub fn with_prefix(inner: W, prefix: &mut impl Read) -> io::Result<Self> {
+        let mut hashing = HashingWriter::new(io::sink());
+        io::copy(prefix, &mut hashing)?;
+
+        Ok(Self {
+            inner,
+            hasher: hashing.hasher,
+        })
+    }
+
+    pub fn finish(self) -> (W, [u8; 32]) {
+        (self.inner, self.hasher.finalize().into())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Dump a partition into `outdir`, optionally compressing it on the fly.
+/// Returns the name of the file that was written, along with its SHA256 digest.
+pub fn dump_logical_partition<T: QdlChan>(
+    channel: &mut T,
+    outdir: &Path,
+    part: &GPTPartitionEntry,
+    resume: bool,
+    compress: Option<&str>,
+    slot: u8,
+    phys_part_idx: u8,
+) -> Result<(String, [u8; 32])> {
+    let name = part.partition_name.as_str();
+    match compress {
+        Some("gzip") => {
+            if resume {
+                bail!("Resuming compressed dumps is not supported");
+            }
+
+            let filename = format!("{name}.img.gz");
+            let mut out = GzEncoder::new(
+                HashingWriter::new(File::create(outdir.join(&filename))?),
+                Compression::default(),
+            );
+            read_storage_logical_partition(channel, &mut out, part, slot, phys_part_idx, 0)?;
+            let (_, digest) = out.finish()?.finish();
+
+            Ok((filename, digest))
+        }
+        Some(c) => bail!("Unknown compression method {}", c),
+        None => {
+            let path = outdir.join(name);
+            let sector_size = channel.fh_config().storage_sector_size;
+            let (out, done_sectors) = open_dump_file(&path, resume, sector_size)?;
+            let mut out = HashingWriter::with_prefix(
+                out,
+                &mut File::open(&path)?.take((done_sectors * sector_size) as u64),
+            )?;
+            read_storage_logical_partition(
+                channel,
+                &mut out,
+                part,
+                slot,
+                phys_part_idx,
+                done_sectors,
+            )?;
+            let (_, digest) = out.finish();
+
+            Ok((name.to_owned(), digest))
+        }
+    }
+}
+
+/// Dump every partition listed in `gpt` into `outdir`, along with the GPT itself and a
+/// rawprogram XML, so that the result can be flashed back as-is (once decompressed, if
+/// `compress` is set). The digests of the dumped files end up in a sha256sum-compatible
+/// manifest.
+pub fn dump_physical_partition<T: QdlChan>(
+    channel: &mut T,
+    gpt: &GPT,
+    outdir: &Path,
+    resume: bool,
+    compress: Option<&str>,
+    slot: u8,
+    phys_part_idx: u8,
+) -> Result<()> {
+    fs::create_dir_all(outdir)?;
+
+    // Grab the GPT itself too, so that the dump can be flashed back as-is
+    let gpt_filename = format!("gpt_main{phys_part_idx}.bin");
+    let gpt_len = gpt.header.first_usable_lba;
+    let mut gpt_out = HashingWriter::new(File::create(outdir.join(&gpt_filename))?);
+    firehose_read_storage(
+        channel,
+        &mut gpt_out,
+        gpt_len as usize,
+        slot,
+        phys_part_idx,
+        0,
+    )?;
+    let mut digests = vec![(gpt_filename.clone(), gpt_out.finish().1)];
+    let mut program_entries = vec![ProgramEntry {
+        label: "PrimaryGPT".to_owned(),
+        filename: gpt_filename,
+        start_sector: 0,
+        num_sectors: gpt_len,
+    }];
+
+    for (_, p) in gpt.iter() {
+        // *sigh*
+        if p.partition_name.as_str().is_empty() || p.size()? == 0 {
+            continue;
+        }
+
+        let (filename, digest) =
+            dump_logical_partition(channel, outdir, p, resume, compress, slot, phys_part_idx)?;
+        // Firehose can't take compressed data, so the XML refers to the image that
+        // decompressing the dump (e.g. with gunzip) produces
+        let program_filename = filename.strip_suffix(".gz").unwrap_or(&filename).to_owned();
+        digests.push((filename, digest));
+
+        program_entries.push(ProgramEntry {
+            label: p.partition_name.to_string(),
+            filename: program_filename,
+            start_sector: p.starting_lba,
+            num_sectors: p.size()?,
+        });
+    }
+
+    let xml_path = outdir.join(format!("rawprogram{phys_part_idx}.xml"));
+    write_program_xml(
+        &mut File::create(&xml_path)?,
+        &program_entries,
+        channel.fh_config().storage_sector_size,
+        slot,
+        phys_part_idx,
+    )?;
+    println!("Wrote {}", xml_path.display());
+
+    let manifest_path = outdir.join("sha256sums.txt");
+    let mut manifest = File::create(&manifest_path)?;
+    for (filename, digest) in digests {
+        writeln!(manifest, "{:02x}  {}", digest.iter().format(""), filename)?;
+    }
+    println!("Wrote {}", manifest_path.display());
+    if compress.is_some() {
+        println!(
+            "{} {} before flashing the dump back",
+            "Decompress the partition images in".bright_yellow(),
+            outdir.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Write a whole-disk image (e.g. a full dump of the physical partition) from sector 0,
+/// after making sure it carries a valid GPT and fits on the device. If the device is larger
+/// than the image, `relocate_backup_gpt` moves the backup GPT to its actual end, extending
+/// the usable area accordingly.
+pub fn flash_disk_image<T: QdlChan>(
+    channel: &mut T,
+    path: &Path,
+    relocate_backup_gpt: bool,
+    slot: u8,
+    phys_part_idx: u8,
+) -> Result<()> {
+    // Checking the image against the device size requires <getstorageinfo>, which can't
+    // address secondary storage devices
+    if slot != 0 {
+        bail!("Whole-disk images can only be written to the primary storage device");
+    }
+
+    let sector_size = channel.fh_config().storage_sector_size as u64;
+    let mut image = File::open(path)?;
+    let mut gpt = read_gpt_from_image(&mut image, Some(sector_size)).map_err(|e| {
+        anyhow!(
+            "{} doesn't start with a valid GPT assuming {}-byte sectors ({})",
+            path.display(),
+            sector_size,
+            e
+        )
+    })?;
+
+    let image_sectors = image.metadata()?.len().div_ceil(sector_size);
+    let total_sectors =
+        firehose_get_storage_info(channel, phys_part_idx)?.total_sectors(sector_size as usize);
+    if total_sectors == 0 {
+        bail!("Couldn't determine the size of physical partition {phys_part_idx}");
+    }
+    if image_sectors > total_sectors {
+        bail!(
+            "{} is {} sectors long, but physical partition {} only has {}",
+            path.display(),
+            image_sectors,
+            phys_part_idx,
+            total_sectors
+        );
+    }
+
+    let backup_misplaced = gpt.header.backup_lba != total_sectors - 1;
+    if backup_misplaced && !relocate_backup_gpt {
+        println!(
+            "{} {} {} {}{}",
+            "The backup GPT of the image is at sector".bright_yellow(),
+            gpt.header.backup_lba,
+            "but the device ends at sector".bright_yellow(),
+            total_sectors - 1,
+            ", pass --relocate-backup-gpt to move it there".bright_yellow()
+        );
+    }
+
+    image.rewind()?;
+    firehose_program_storage(
+        channel,
+        &mut image,
+        "",
+        image_sectors as usize,
+        slot,
+        phys_part_idx,
+        "0",
+    )?;
+
+    if !(backup_misplaced && relocate_backup_gpt) {
+        return Ok(());
+    }
+
+    image.rewind()?;
+    write_relocated_gpt(
+        channel,
+        &mut gpt,
+        &mut image,
+        total_sectors,
+        slot,
+        phys_part_idx,
+    )
+}
+
+/// Check that the backup GPT of a physical partition sits in its very last sector and
+/// matches the primary one. With `apply`, it's rewritten from the primary one if it doesn't.
+pub fn fix_gpt<T: QdlChan>(
+    channel: &mut T,
+    slot: u8,
+    phys_part_idx: u8,
+    apply: bool,
+) -> Result<()> {
+    // Locating the end requires <getstorageinfo>, which can't address secondary storage
+    if slot != 0 {
+        bail!("The GPT can only be checked on the primary storage device");
+    }
+
+    let sector_size = channel.fh_config().storage_sector_size;
+    let total_sectors =
+        firehose_get_storage_info(channel, phys_part_idx)?.total_sectors(sector_size);
+    if total_sectors == 0 {
+        bail!("Couldn't determine the size of physical partition {phys_part_idx}");
+    }
+    let mut gpt = read_primary_gpt_from_storage(channel, slot, phys_part_idx).map_err(|e| {
+        anyhow!("Couldn't read the primary GPT ({e}), there's nothing to fix the backup one from")
+    })?;
+
+    let problem = if gpt.header.backup_lba != total_sectors - 1 {
+        Some(format!(
+            "The primary GPT expects the backup one at sector {}, but storage ends at sector {}",
+            gpt.header.backup_lba,
+            total_sectors - 1
+        ))
+    } else {
+        match read_backup_gpt_from_storage(channel, slot, phys_part_idx) {
+            Err(e) => Some(format!("The backup GPT is unreadable ({e})")),
+            Ok(backup) if !backup.iter().eq(gpt.iter()) => {
+                Some("The backup GPT doesn't match the primary one".to_owned())
+            }
+            Ok(_) => None,
+        }
+    };
+
+    let Some(problem) = problem else {
+        println!("The GPT on physical partition {phys_part_idx} is consistent");
+        return Ok(());
+    };
+    println!("{}", problem.bright_yellow());
+    if !apply {
+        println!(
+            "Rerun with --apply to rewrite the backup GPT (and the primary header pointing at it)"
+        );
+        return Ok(());
+    }
+
+    let mut head = Vec::<u8>::new();
+    firehose_read_storage(
+        channel,
+        &mut head,
+        gpt.header.first_usable_lba as usize,
+        slot,
+        phys_part_idx,
+        0,
+    )?;
+    write_relocated_gpt(
+        channel,
+        &mut gpt,
+        &mut Cursor::new(head),
+        total_sectors,
         slot,
         phys_part_idx,
-        part.starting_lba as u32,
     )
 }
+
+/// Rewrite both copies of `gpt` so that the backup one ends up in the very last sector of
+/// a physical partition of `total_sectors`, with the usable area extending up to it.
+/// `head` provides the current contents of the sectors preceding the first usable one
+/// (protective MBR included), which the primary copy is rendered on top of.
+fn write_relocated_gpt<T: QdlChan>(
+    channel: &mut T,
+    gpt: &mut GPT,
+    head: &mut impl Read,
+    total_sectors: u64,
+    slot: u8,
+    phys_part_idx: u8,
+) -> Result<()> {
+    let sector_size = channel.fh_config().storage_sector_size as u64;
+
+    // Only the end moves, some layouts reserve extra space at the start
+    let first_usable_lba = gpt.header.first_usable_lba;
+    gpt.header.update_from(
+        &mut StorageTail {
+            data: Vec::new(),
+            offset: 0,
+            len: total_sectors * sector_size,
+            pos: 0,
+        },
+        sector_size,
+    )?;
+    gpt.header.first_usable_lba = first_usable_lba;
+    let partitions = gpt.iter().map(|(_, p)| p.clone()).collect::<Vec<_>>();
+
+    let mut primary = SectorWindow::new(0, first_usable_lba * sector_size);
+    head.read_exact(primary.buf.get_mut())?;
+    gpt.header
+        .write_into(&mut primary, sector_size, &partitions)?;
+
+    let backup_start = gpt.header.last_usable_lba + 1;
+    let mut backup_header = gpt.header.clone();
+    backup_header.primary_lba = gpt.header.backup_lba;
+    backup_header.backup_lba = gpt.header.primary_lba;
+    backup_header.partition_entry_lba = backup_start;
+    let mut backup = SectorWindow::new(
+        backup_start * sector_size,
+        (total_sectors - backup_start) * sector_size,
+    );
+    backup_header.write_into(&mut backup, sector_size, &partitions)?;
+
+    for (window, label) in [(primary, "PrimaryGPT"), (backup, "BackupGPT")] {
+        let start = window.base / sector_size;
+        let len = window.buf.get_ref().len() / sector_size as usize;
+        firehose_program_storage(
+            channel,
+            &mut Cursor::new(window.buf.into_inner()),
+            label,
+            len,
+            slot,
+            phys_part_idx,
+            &start.to_string(),
+        )?;
+    }
+    println!(
+        "Wrote the backup GPT to sector {}",
+        (total_sectors - 1).bright_blue()
+    );
+
+    Ok(())
+}
+
+/// An in-memory stretch of storage starting at byte `base`, to render GPT copies into
+struct SectorWindow {
+    base: u64,
+    buf: Cursor<Vec<u8>>,
+}
+
+impl SectorWindow {
+    fn new(base: u64, len: u64) -> Self {
+        Self {
+            base,
+            buf: Cursor::new(vec![0; len as usize]),
+        }
+    }
+}
+
+impl Write for SectorWindow {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SectorWindow {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let SeekFrom::Start(n) = pos else {
+            return Err(Error::from(ErrorKind::Unsupported));
+        };
+        let off = n
+            .checked_sub(self.base)
+            .ok_or(Error::from(ErrorKind::InvalidInput))?;
+
+        Ok(self.base + self.buf.seek(SeekFrom::Start(off))?)
+    }
+}
+
+/// Forwards data into a child process' stdin. Once the child stops reading, everything
+/// else is swallowed, so that the read in progress can still be seen through and the
+/// Firehose session stays in sync.
+struct PipeSink {
+    stdin: ChildStdin,
+    broken: bool,
+}
+
+impl Write for PipeSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.broken {
+            return Ok(buf.len());
+        }
+
+        match self.stdin.write(buf) {
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                println!(
+                    "{}",
+                    "The pipe command exited early, discarding the rest of the data"
+                        .bright_yellow()
+                );
+                self.broken = true;
+                Ok(buf.len())
+            }
+            r => r,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.broken {
+            true => Ok(()),
+            false => self.stdin.flush(),
+        }
+    }
+}
+
+/// Stream a partition into the stdin of a shell command (e.g. "gzip > out.gz")
+pub fn pipe_logical_partition<T: QdlChan>(
+    channel: &mut T,
+    part: &GPTPartitionEntry,
+    cmd: &str,
+    slot: u8,
+    phys_part_idx: u8,
+) -> Result<()> {
+    let mut child = match cfg!(windows) {
+        true => Command::new("cmd")
+            .args(["/C", cmd])
+            .stdin(Stdio::piped())
+            .spawn(),
+        false => Command::new("sh")
+            .args(["-c", cmd])
+            .stdin(Stdio::piped())
+            .spawn(),
+    }
+    .map_err(|e| anyhow!("Couldn't run '{}': {}", cmd, e))?;
+
+    let mut sink = PipeSink {
+        stdin: child.stdin.take().unwrap(),
+        broken: false,
+    };
+    let res = read_storage_logical_partition(channel, &mut sink, part, slot, phys_part_idx, 0);
+    let broken = sink.broken;
+    // Close the pipe, so that the child sees EOF
+    drop(sink);
+    let status = child.wait()?;
+    res?;
+
+    if broken || !status.success() {
+        bail!("'{}' didn't consume the whole partition ({})", cmd, status);
+    }
+
+    Ok(())
+}
+
+/// A single record of the --progress-fd stream
+#[derive(Serialize)]
+struct ProgressRecord<'a> {
+    partition: Option<&'a str>,
+    done: u64,
+    total: u64,
+    /// Average throughput of the transfer so far, in bytes per second
+    rate: u64,
+}
+
+/// Open a file descriptor inherited from the parent process, for writing
+pub fn open_progress_fd(fd: u32) -> Result<File> {
+    if !cfg!(unix) {
+        bail!("--progress-fd is only supported on Unix-like systems");
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .open(format!("/dev/fd/{fd}"))
+        .map_err(|e| anyhow!("Couldn't open file descriptor {}: {}", fd, e))
+}
+
+/// Progress callback writing newline-delimited JSON records, for programs wrapping qdl-rs
+pub fn json_progress_reporter(mut out: impl Write + Send) -> impl FnMut(ProgressEvent) + Send {
+    let mut label: Option<String> = None;
+    let mut start = Instant::now();
+    let mut total = 0;
+
+    let mut emit = move |label: Option<&str>, done: u64, total: u64, start: Instant| {
+        let elapsed = start.elapsed().as_secs_f64();
+        let record = ProgressRecord {
+            partition: label,
+            done,
+            total,
+            rate: match elapsed > 0.0 {
+                true => (done as f64 / elapsed) as u64,
+                false => 0,
+            },
+        };
+        // A consumer going away mustn't interrupt the transfer itself
+        let _ = serde_json::to_writer(&mut out, &record)
+            .map_err(io::Error::from)
+            .and_then(|_| out.write_all(b"\n"))
+            .and_then(|_| out.flush());
+    };
+
+    move |event| match event {
+        ProgressEvent::Start {
+            label: l,
+            total_bytes,
+        } => {
+            label = l.map(str::to_owned);
+            start = Instant::now();
+            total = total_bytes;
+            emit(label.as_deref(), 0, total, start);
+        }
+        ProgressEvent::Advance {
+            bytes_done,
+            total_bytes,
+        } => {
+            total = total_bytes;
+            emit(label.as_deref(), bytes_done, total, start);
+        }
+        ProgressEvent::Finish => emit(label.as_deref(), total, total, start),
+    }
+}
+
+pub fn print_edl_devices(ids: UsbIdFilter) -> Result<()> {
+    let devices = list_edl_devices(ids)?;
+    if devices.is_empty() {
+        println!("No devices in EDL mode");
+        return Ok(());
+    }
+
+    println!(
+        "{:<8} {:<8} {:<8} {:<18} PRODUCT",
+        "BUS", "ADDRESS", "MODE", "SERIAL"
+    );
+    for d in devices {
+        println!(
+            "{:<8} {:<8} {:<8} {:<18} {}",
+            d.bus_id,
+            d.device_address,
+            match d.pid {
+                0x900e => "ramdump",
+                _ => "edl",
+            },
+            d.serial_no.unwrap_or("-".to_owned()),
+            d.product
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdl::mock::QdlMockDevice;
+    use qdl::types::QdlDevice;
+
+    /// An MBR/EBR sector holding the given (status, type, start, length) entries
+    fn mbr_sector(entries: &[(u8, u8, u32, u32)]) -> Vec<u8> {
+        let mut sector = vec![0; 512];
+        for (i, &(status, kind, start, len)) in entries.iter().enumerate() {
+            let e = &mut sector[MBR_PART_TABLE_OFFSET + i * MBR_PART_ENTRY_SIZE..]
+                [..MBR_PART_ENTRY_SIZE];
+            e[0] = status;
+            e[4] = kind;
+            e[8..12].copy_from_slice(&start.to_le_bytes());
+            e[12..16].copy_from_slice(&len.to_le_bytes());
+        }
+        sector[510..].copy_from_slice(&MBR_SIGNATURE);
+        sector
+    }
+
+    fn push_sector(mock: &mut QdlMockDevice, sector: &[u8]) {
+        mock.push_rawmode_ack().push_raw(sector).push_ack();
+    }
+
+    fn read_mbr(mock: QdlMockDevice) -> (QdlDevice<QdlMockDevice>, Result<Option<GPT>>) {
+        let mut dev = mock.into_device();
+        let res = read_mbr_from_storage(&mut dev, 0, 0);
+        (dev, res)
+    }
+
+    /// (name, first sector, last sector, attributes) of the used entries
+    fn parts(gpt: &GPT) -> Vec<(String, u64, u64, u64)> {
+        gpt.iter()
+            .filter(|(_, p)| p.is_used())
+            .map(|(_, p)| {
+                (
+                    p.partition_name.as_str().to_owned(),
+                    p.starting_lba,
+                    p.ending_lba,
+                    p.attribute_bits,
+                )
+            })
+            .collect()
+    }
+
+    fn read_sectors(dev: &QdlDevice<QdlMockDevice>) -> Vec<String> {
+        dev.rw
+            .written_xml()
+            .iter()
+            .filter(|x| x.contains("<read "))
+            .map(|x| {
+                let start = x.split("start_sector=\"").nth(1).unwrap();
+                start[..start.find('"').unwrap()].to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mbr_primary_only() {
+        let mut mock = QdlMockDevice::default();
+        push_sector(
+            &mut mock,
+            &mbr_sector(&[(0x80, 0x83, 2048, 1000), (0, 0x0c, 4096, 2000)]),
+        );
+        // No <getstorageinfo>, the partitions tell how large storage is at least
+        mock.push_nak();
+
+        let (dev, res) = read_mbr(mock);
+        let gpt = res.unwrap().unwrap();
+        assert!(dev.rw.is_drained());
+        assert!(is_from_mbr(&gpt));
+        assert_eq!(
+            parts(&gpt),
+            [
+                ("p1".to_owned(), 2048, 3047, GPT_ATTR_LEGACY_BOOTABLE),
+                ("p2".to_owned(), 4096, 6095, 0),
+            ]
+        );
+        assert_eq!(gpt[1].partition_type_guid[0], 0x83);
+        assert_eq!(gpt.header.last_usable_lba, 6095);
+    }
+
+    #[test]
+    fn mbr_logical_partitions() {
+        let mut mock = QdlMockDevice::default();
+        push_sector(
+            &mut mock,
+            &mbr_sector(&[(0, 0x83, 2048, 1000), (0, 0x05, 10000, 5000)]),
+        );
+        // Logical partitions start relative to their EBR, the next EBR relative to the
+        // start of the extended partition
+        push_sector(
+            &mut mock,
+            &mbr_sector(&[(0, 0x83, 1, 999), (0, 0x05, 1000, 2000)]),
+        );
+        push_sector(&mut mock, &mbr_sector(&[(0, 0x83, 1, 500)]));
+        mock.push_nak();
+
+        let (dev, res) = read_mbr(mock);
+        let gpt = res.unwrap().unwrap();
+        assert!(dev.rw.is_drained());
+        assert_eq!(read_sectors(&dev), ["0", "10000", "11000"]);
+        assert_eq!(
+            parts(&gpt),
+            [
+                ("p1".to_owned(), 2048, 3047, 0),
+                ("p5".to_owned(), 10001, 10999, 0),
+                ("p6".to_owned(), 11001, 11500, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn mbr_ebr_loop() {
+        let mut mock = QdlMockDevice::default();
+        push_sector(&mut mock, &mbr_sector(&[(0, 0x05, 10000, 5000)]));
+        // An EBR pointing back at itself, with logical partitions 5..=128 and then one more
+        for _ in 5..=GPT_MAX_PARTITIONS + 1 {
+            push_sector(
+                &mut mock,
+                &mbr_sector(&[(0, 0x83, 1, 10), (0, 0x05, 0, 5000)]),
+            );
+        }
+
+        let (dev, res) = read_mbr(mock);
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("more than 128 partitions"), "{err}");
+        assert!(dev.rw.is_drained());
+    }
+
+    #[test]
+    fn mbr_ebr_overflow() {
+        let mut mock = QdlMockDevice::default();
+        push_sector(&mut mock, &mbr_sector(&[(0, 0x05, u32::MAX - 16, 16)]));
+        push_sector(&mut mock, &mbr_sector(&[(0, 0x83, 1, 4), (0, 0x05, 32, 4)]));
+
+        let (dev, res) = read_mbr(mock);
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("points past the end of storage"), "{err}");
+        assert!(dev.rw.is_drained());
+    }
+
+    #[test]
+    fn mbr_protective() {
+        let mut mock = QdlMockDevice::default();
+        push_sector(
+            &mut mock,
+            &mbr_sector(&[(0, MBR_TYPE_PROTECTIVE, 1, u32::MAX)]),
+        );
+
+        let (dev, res) = read_mbr(mock);
+        assert!(res.unwrap().is_none());
+        assert!(dev.rw.is_drained());
+    }
+}
@@ -0,0 +1,340 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+use anyhow::{Context, Result, anyhow, bail};
+use flate2::read::DeflateDecoder;
+use itertools::Itertools;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::programfile::{ProgramFileSource, ReadSeek};
+
+/// A factory package (program/patch XMLs along with the images they reference), as a ZIP
+pub struct ZipPackage {
+    path: PathBuf,
+    archive: ZipArchive<File>,
+}
+
+impl ZipPackage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Couldn't open {}", path.display()))?;
+        let archive = ZipArchive::new(file)
+            .with_context(|| format!("Couldn't read {} as a ZIP archive", path.display()))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            archive,
+        })
+    }
+
+    /// Members named `<prefix><N>.xml` (in any directory), ordered by N.
+    /// Variants like rawprogram0_BLANK_GPT.xml are deliberately left out.
+    pub fn numbered_xmls(&self, prefix: &str) -> Vec<String> {
+        self.archive
+            .file_names()
+            .filter_map(|name| {
+                let base = name.rsplit('/').next().unwrap_or(name);
+                let idx = base
+                    .strip_prefix(prefix)?
+                    .strip_suffix(".xml")?
+                    .parse::<u32>()
+                    .ok()?;
+                Some((idx, name.to_owned()))
+            })
+            .sorted()
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    /// Decompress a (small) member into memory
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.archive
+            .by_name(name)
+            .with_context(|| format!("Couldn't find {name} in the archive"))?
+            .read_to_end(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// View of the members in `dir`, which program XMLs found there refer to
+    pub fn dir(&mut self, dir: &str) -> ZipDir<'_> {
+        ZipDir {
+            pkg: self,
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// Open a member for streaming, without extracting it
+    fn open_member(&mut self, name: &str) -> Result<ZipMember> {
+        let entry = self
+            .archive
+            .by_name(name)
+            .with_context(|| format!("Couldn't find {name} in the archive"))?;
+        if entry.encrypted() {
+            bail!("{name} is encrypted");
+        }
+        let deflated = match entry.compression() {
+            CompressionMethod::Stored => false,
+            CompressionMethod::Deflated => true,
+            m => bail!("{name} uses an unsupported compression method ({m})"),
+        };
+
+        Ok(ZipMember {
+            path: self.path.clone(),
+            data_start: entry
+                .data_start()
+                .ok_or(anyhow!("Couldn't locate the data of {name}"))?,
+            compressed_size: entry.compressed_size(),
+            size: entry.size(),
+            deflated,
+            reader: None,
+            reader_pos: 0,
+            pos: 0,
+        })
+    }
+}
+
+/// A directory within a [ZipPackage]
+pub struct ZipDir<'a> {
+    pkg: &'a mut ZipPackage,
+    dir: String,
+}
+
+impl ZipDir<'_> {
+    fn member_name(&self, filename: &str) -> String {
+        match self.dir.is_empty() {
+            true => filename.to_owned(),
+            false => format!("{}/{}", self.dir, filename),
+        }
+    }
+}
+
+impl ProgramFileSource for ZipDir<'_> {
+    fn exists(&self, filename: &str) -> bool {
+        self.pkg
+            .archive
+            .index_for_name(&self.member_name(filename))
+            .is_some()
+    }
+
+    fn open(&mut self, filename: &str) -> Result<Box<dyn ReadSeek>> {
+        let name = self.member_name(filename);
+        Ok(Box::new(self.pkg.open_member(&name)?))
+    }
+
+    fn display(&self, filename: &str) -> String {
+        format!("{}:{}", self.pkg.path.display(), self.member_name(filename))
+    }
+}
+
+/// Seekable reader of a (possibly deflated) archive member. Seeking is lazy, with
+/// backwards seeks within deflated members restarting decompression from the top.
+struct ZipMember {
+    path: PathBuf,
+    data_start: u64,
+    compressed_size: u64,
+    size: u64,
+    deflated: bool,
+    reader: Option<Box<dyn Read>>,
+    /// Position of [Self::reader] within the uncompressed data
+    reader_pos: u64,
+    /// Position requested through [Seek]
+    pos: u64,
+}
+
+impl ZipMember {
+    /// Bring the reader to the requested position
+    fn sync(&mut self) -> io::Result<()> {
+        if self.reader.is_some() && self.pos == self.reader_pos {
+            return Ok(());
+        }
+
+        // Stored data can be sought to directly, deflated data has to be decompressed from
+        // the start (or the current position, if moving forward)
+        if self.reader.is_none() || !self.deflated || self.pos < self.reader_pos {
+            let mut file = File::open(&self.path)?;
+            let skip = match self.deflated {
+                true => 0,
+                false => self.pos,
+            };
+            file.seek(SeekFrom::Start(self.data_start + skip))?;
+            let raw = BufReader::new(file).take(self.compressed_size.saturating_sub(skip));
+
+            self.reader = Some(match self.deflated {
+                true => Box::new(DeflateDecoder::new(raw)),
+                false => Box::new(raw),
+            });
+            self.reader_pos = skip;
+        }
+
+        let reader = self.reader.as_mut().unwrap();
+        let skip = self.pos - self.reader_pos;
+        self.reader_pos += io::copy(&mut reader.take(skip), &mut io::sink())?;
+
+        Ok(())
+    }
+}
+
+impl Read for ZipMember {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size {
+            return Ok(0);
+        }
+
+        self.sync()?;
+        let n = self.reader.as_mut().unwrap().read(buf)?;
+        self.pos += n as u64;
+        self.reader_pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Seek for ZipMember {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(n) => self.size.checked_add_signed(n),
+        };
+
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    const MEMBER_LEN: usize = 256 * 1024;
+
+    fn member_data() -> Vec<u8> {
+        (0..MEMBER_LEN)
+            .map(|i| (i as u32).wrapping_mul(2654435761).to_le_bytes()[3])
+            .collect()
+    }
+
+    /// Build a package with a stored and a deflated copy of [member_data] under images/,
+    /// plus one more member at the top. The members are read back from a file, like the
+    /// real thing, so the archive is written out to one named after the test.
+    fn write_package(test: &str) -> PathBuf {
+        let mut zip = ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, method) in [
+            ("images/stored.bin", CompressionMethod::Stored),
+            ("images/deflated.bin", CompressionMethod::Deflated),
+            ("top.bin", CompressionMethod::Stored),
+        ] {
+            zip.start_file(
+                name,
+                SimpleFileOptions::default().compression_method(method),
+            )
+            .unwrap();
+            zip.write_all(&member_data()).unwrap();
+        }
+        let buf = zip.finish().unwrap().into_inner();
+
+        let path =
+            std::env::temp_dir().join(format!("qdlrs-zipfile-{}-{}.zip", test, std::process::id()));
+        std::fs::write(&path, buf).unwrap();
+        path
+    }
+
+    fn read_at(member: &mut dyn ReadSeek, pos: SeekFrom, len: usize) -> Vec<u8> {
+        member.seek(pos).unwrap();
+        let mut buf = vec![0; len];
+        member.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn member_seeks() {
+        let path = write_package("member_seeks");
+        let mut pkg = ZipPackage::open(&path).unwrap();
+        let data = member_data();
+
+        for name in ["stored.bin", "deflated.bin"] {
+            let mut dir = pkg.dir("images");
+            let mut member = dir.open(name).unwrap();
+
+            assert_eq!(read_at(&mut *member, SeekFrom::Start(0), 16), data[..16]);
+            // Forward, then back to before the reader's position
+            assert_eq!(
+                read_at(&mut *member, SeekFrom::Start(200_000), 100),
+                data[200_000..200_100],
+                "{name}"
+            );
+            assert_eq!(
+                read_at(&mut *member, SeekFrom::Start(1000), 100),
+                data[1000..1100],
+                "{name}"
+            );
+            assert_eq!(
+                read_at(&mut *member, SeekFrom::Current(-50), 10),
+                data[1050..1060],
+                "{name}"
+            );
+            assert_eq!(
+                read_at(&mut *member, SeekFrom::End(-16), 16),
+                data[MEMBER_LEN - 16..],
+                "{name}"
+            );
+
+            // The sparse image check peeks at the header and rewinds, then the size is
+            // taken by seeking to the end, before the data is streamed from the top
+            assert_eq!(read_at(&mut *member, SeekFrom::Start(0), 28), data[..28]);
+            assert_eq!(member.seek(SeekFrom::End(0)).unwrap(), MEMBER_LEN as u64);
+            assert_eq!(member.read(&mut [0; 16]).unwrap(), 0);
+            member.rewind().unwrap();
+            let mut all = Vec::new();
+            member.read_to_end(&mut all).unwrap();
+            assert!(all == data, "{name}");
+
+            assert!(
+                member
+                    .seek(SeekFrom::Current(-(MEMBER_LEN as i64) - 1))
+                    .is_err()
+            );
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn dir_resolves_names() {
+        let path = write_package("dir_resolves_names");
+        let mut pkg = ZipPackage::open(&path).unwrap();
+
+        let mut images = pkg.dir("images");
+        assert!(images.exists("stored.bin"));
+        assert!(!images.exists("top.bin"));
+        assert!(images.open("top.bin").is_err());
+        assert_eq!(
+            images.display("stored.bin"),
+            format!("{}:images/stored.bin", path.display())
+        );
+
+        let mut top = pkg.dir("");
+        assert!(top.exists("top.bin"));
+        assert!(!top.exists("stored.bin"));
+        let mut member = top.open("top.bin").unwrap();
+        assert_eq!(
+            read_at(&mut *member, SeekFrom::Start(5), 5),
+            member_data()[5..10]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
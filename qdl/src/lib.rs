@@ -1,31 +1,48 @@
 // SPDX-License-Identifier: BSD-3-Clause
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
-use anstream::println;
+use anstream::{eprintln, println};
 use anyhow::Result;
 use indexmap::{Equivalent, IndexMap};
 use owo_colors::OwoColorize;
-use parsers::firehose_parser_ack_nak;
+use parsers::{
+    firehose_parser_ack_nak, firehose_parser_crc32_digest, firehose_parser_print_response,
+    firehose_parser_sha256_digest, firehose_parser_storage_info,
+};
 use serde::{Deserialize, Serialize};
-use std::cmp::min;
+use sha2::{Digest, Sha256};
+use std::cmp::{max, min};
+use std::collections::VecDeque;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::str::{self, FromStr};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use types::FirehoseError;
+use types::FirehoseLogLevel;
 use types::FirehoseResetMode;
 use types::FirehoseStatus;
 use types::FirehoseStorageType;
+use types::ProgressEvent;
 use types::QdlBackend;
 use types::QdlChan;
 use types::QdlReadWrite;
+use types::StorageInfo;
+use types::TransferStats;
+use types::UsbIdFilter;
+use types::UsbOptions;
 
 use anyhow::bail;
 use pbr::{ProgressBar, Units};
 use xmltree::{self, Element, XMLNode};
 
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod parsers;
 pub mod sahara;
 #[cfg(feature = "serial")]
 pub mod serial;
+pub mod sparse;
 pub mod types;
 #[cfg(feature = "usb")]
 pub mod usb;
@@ -141,34 +158,60 @@ fn decode_programmer_archive(blob: &[u8], images: &mut Vec<Option<Vec<u8>>>) ->
 
 /// Load Sahara programmer image(s) from disk.
 ///
-/// If `path` points to a CPIO `newc` archive, this decodes entries named like
+/// If a single `path` points to a CPIO `newc` archive, this decodes entries named like
 /// `<id>:<name>` (or just `<id>`) and stores each file in its Sahara image slot.
-/// Otherwise, the file is returned as a single-slot image list, preserving
-/// legacy single-image Sahara behavior.
-pub fn load_programmer_images(path: impl AsRef<Path>) -> Result<Vec<Option<Vec<u8>>>> {
-    let blob = fs::read(path.as_ref())?;
+/// Otherwise, the files are returned in slots starting at 0 (which is never a valid
+/// archive image ID), and are handed out in the order the Device asks for new image IDs.
+/// A single file is served for any image ID, preserving legacy single-image Sahara behavior.
+pub fn load_programmer_images(paths: &[impl AsRef<Path>]) -> Result<Vec<Option<Vec<u8>>>> {
     let mut images: Vec<Option<Vec<u8>>> = Vec::new();
 
-    if decode_programmer_archive(&blob, &mut images)? {
-        return Ok(images);
+    if let [path] = paths {
+        let blob = fs::read(path.as_ref())?;
+        if decode_programmer_archive(&blob, &mut images)? {
+            return Ok(images);
+        }
+
+        return Ok(vec![Some(blob)]);
+    }
+
+    for path in paths {
+        let blob = fs::read(path.as_ref())?;
+        if decode_programmer_archive(&blob, &mut Vec::new())? {
+            bail!(
+                "{} is a programmer archive, which can't be combined with other images",
+                path.as_ref().display()
+            );
+        }
+        images.push(Some(blob));
     }
 
-    Ok(vec![Some(blob)])
+    Ok(images)
 }
 
+/// Open a channel to the Device
+///
+/// `_timeout` bounds every read/write on the channel, an elapsed timeout is reported as an
+/// [std::io::Error] of kind [std::io::ErrorKind::TimedOut]. `None` keeps the backend's default.
+/// `_wait` allows for waiting on the Device to show up (USB only).
+/// `_baud_rate` overrides the default of 115200 (serial only).
 pub fn setup_target_device(
     backend: QdlBackend,
     _serial_no: Option<String>,
     _port: Option<String>,
+    _timeout: Option<Duration>,
+    _wait: Option<Duration>,
+    _baud_rate: Option<u32>,
+    _usb_opts: UsbOptions,
 ) -> Result<Box<dyn QdlReadWrite>> {
     match backend {
         #[cfg(feature = "serial")]
-        QdlBackend::Serial => match serial::setup_serial_device(_port) {
+        QdlBackend::Serial => match serial::setup_serial_device(_port, _timeout, _baud_rate) {
             Ok(d) => Ok(Box::new(d)),
             Err(e) => Err(e),
         },
         #[cfg(feature = "usb")]
-        QdlBackend::Usb => match usb::setup_usb_device(_serial_no) {
+        QdlBackend::Usb => match usb::setup_usb_device(_serial_no, _timeout, _wait, _usb_opts) {
             Ok(d) => Ok(Box::new(d)),
             Err(e) => Err(e),
         },
@@ -178,6 +221,32 @@ pub fn setup_target_device(
     }
 }
 
+/// Pick a backend when none was explicitly requested: USB if there's a Device in EDL mode
+/// on the bus, otherwise a serial port that looks like one (serial is implied by `port`).
+/// Falls back to [QdlBackend::default] if nothing is found (e.g. when waiting on a Device).
+///
+/// Returns the backend, along with the serial port to use.
+pub fn autodetect_backend(
+    port: Option<String>,
+    _usb_ids: UsbIdFilter,
+) -> (QdlBackend, Option<String>) {
+    if port.is_some() {
+        return (QdlBackend::Serial, port);
+    }
+
+    #[cfg(feature = "usb")]
+    if usb::list_edl_devices(_usb_ids).is_ok_and(|devs| !devs.is_empty()) {
+        return (QdlBackend::Usb, None);
+    }
+
+    #[cfg(feature = "serial")]
+    if let Some(port) = serial::find_edl_serial_port() {
+        return (QdlBackend::Serial, Some(port));
+    }
+
+    (QdlBackend::default(), None)
+}
+
 /// Wrapper for easily creating Firehose-y XML packets
 fn firehose_xml_setup(op: &str, kvps: &[(&str, &str)]) -> anyhow::Result<Vec<u8>> {
     let mut xml = Element::new("data");
@@ -203,9 +272,54 @@ fn firehose_xml_setup(op: &str, kvps: &[(&str, &str)]) -> anyhow::Result<Vec<u8>
 pub fn firehose_read<T: QdlChan>(
     channel: &mut T,
     response_parser: fn(&mut T, &IndexMap<String, String>) -> Result<FirehoseStatus, anyhow::Error>,
+) -> Result<FirehoseStatus, anyhow::Error> {
+    firehose_read_inner(channel, response_parser, None)
+}
+
+/// Like [firehose_read], but also hands back the \<log\> messages received along the way
+pub fn firehose_read_logs<T: QdlChan>(
+    channel: &mut T,
+    response_parser: fn(&mut T, &IndexMap<String, String>) -> Result<FirehoseStatus, anyhow::Error>,
+) -> Result<(FirehoseStatus, Vec<String>), anyhow::Error> {
+    let mut logs = Vec::new();
+    let status = firehose_read_inner(channel, response_parser, Some(&mut logs))?;
+
+    Ok((status, logs))
+}
+
+/// Pick out the \<log\> messages explaining a NAK, preferring warnings and errors
+fn nak_reason(logs: &[String]) -> Option<String> {
+    let errors = logs
+        .iter()
+        .filter(|l| FirehoseLogLevel::of_message(l) >= FirehoseLogLevel::Warn)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    match errors.is_empty() {
+        true => logs.last().cloned(),
+        false => Some(errors),
+    }
+}
+
+/// Error for a NAKed `what`, along with whatever reason the Device gave for it
+pub fn firehose_nak_error<T: QdlChan>(channel: &T, what: &str) -> anyhow::Error {
+    FirehoseError::Nak {
+        what: what.to_owned(),
+        reason: channel.fh_config().last_nak_reason.clone(),
+    }
+    .into()
+}
+
+fn firehose_read_inner<T: QdlChan>(
+    channel: &mut T,
+    response_parser: fn(&mut T, &IndexMap<String, String>) -> Result<FirehoseStatus, anyhow::Error>,
+    mut logs: Option<&mut Vec<String>>,
 ) -> Result<FirehoseStatus, anyhow::Error> {
     let mut got_any_data = false;
     let mut pending: Vec<u8> = Vec::new();
+    // Messages that may explain a NAK
+    let mut recent_logs: Vec<String> = Vec::new();
 
     loop {
         // Use BufRead to peek at available data
@@ -244,6 +358,18 @@ pub fn firehose_read<T: QdlChan>(
 
             // Only parse the XML portion
             let xml_chunk = &pending[..xml_end];
+            if let Some(sink) = channel.fh_log_sink() {
+                let ts = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                writeln!(
+                    sink,
+                    "[{}.{:03}] {}",
+                    ts.as_secs(),
+                    ts.subsec_millis(),
+                    String::from_utf8_lossy(xml_chunk).trim()
+                )?;
+            }
             let xml = match xmltree::Element::parse(xml_chunk) {
                 Ok(x) => x,
                 Err(e) => {
@@ -269,6 +395,13 @@ pub fn firehose_read<T: QdlChan>(
             if let Some(XMLNode::Element(e)) = xml.children.first() {
                 // Check for a 'log' node and print out the message
                 if e.name == "log" {
+                    if let Some(val) = e.attributes.get("value") {
+                        if let Some(logs) = logs.as_deref_mut() {
+                            logs.push(val.to_owned());
+                        }
+                        recent_logs.push(val.trim().to_owned());
+                    }
+
                     // The last message within the initial logspam should be this
                     // Try to match on it to not pay the USB xfer timeout penalty each time
                     if let Some(val) = e.attributes.get_key_value("value")
@@ -276,16 +409,17 @@ pub fn firehose_read<T: QdlChan>(
                     {
                         return Ok(FirehoseStatus::Ack);
                     }
-                    if channel.fh_config().skip_firehose_log {
+                    let cfg = channel.fh_config();
+                    let val = e.attributes.get("value");
+                    if cfg.skip_firehose_log
+                        || val.is_some_and(|v| FirehoseLogLevel::of_message(v) < cfg.log_level)
+                    {
                         continue;
                     }
 
                     println!(
                         "LOG: {}",
-                        e.attributes
-                            .get("value")
-                            .to_owned()
-                            .unwrap_or(&String::from("<garbage log data>"))
+                        val.unwrap_or(&String::from("<garbage log data>"))
                             .bright_black()
                     );
 
@@ -300,15 +434,24 @@ pub fn firehose_read<T: QdlChan>(
 
                 // TODO: Use std::intrinsics::unlikely after it exits nightly
                 if e.attributes.get("AttemptRetry").is_some() {
-                    return firehose_read::<T>(channel, response_parser);
+                    return firehose_read_inner::<T>(channel, response_parser, logs);
                 } else if e.attributes.get("AttemptRestart").is_some() {
                     // TODO: handle this automagically
-                    firehose_reset(channel, &FirehoseResetMode::ResetToEdl, 0)?;
+                    let delay = channel.fh_config().reset_delay;
+                    firehose_reset(channel, &FirehoseResetMode::ResetToEdl, delay)?;
                     bail!("Firehose requested a restart. Run the program again.");
                 }
 
+                // The Device is only done with the command once it's out of raw mode
+                channel.set_idle(e.attributes.get("rawmode").is_none_or(|r| r != "true"));
+
                 // Pass other nodes to specialized parsers
-                return response_parser(channel, &e.attributes);
+                let status = response_parser(channel, &e.attributes);
+                channel.mut_fh_config().last_nak_reason = match status {
+                    Ok(FirehoseStatus::Nak) => nak_reason(&recent_logs),
+                    _ => None,
+                };
+                return status;
             }
         } else {
             // Didn't find the tail of the XML document in "pending" +
@@ -322,18 +465,28 @@ pub fn firehose_read<T: QdlChan>(
 
 /// Send a Firehose packet
 pub fn firehose_write<T: QdlChan>(channel: &mut T, buf: &mut [u8]) -> anyhow::Result<()> {
+    if channel.take_interrupt() {
+        return Err(FirehoseError::Interrupted.into());
+    }
+    channel.set_idle(false);
+
     let mut b = buf.to_vec();
 
     // XML can't be n * 512 bytes long by fh spec
     if !buf.is_empty() && buf.len().is_multiple_of(512) {
-        println!("{}", "INFO: Appending '\n' to outgoing XML".bright_black());
+        if !channel.fh_config().quiet {
+            println!("{}", "INFO: Appending '\n' to outgoing XML".bright_black());
+        }
         b.push(b'\n');
     }
 
     match channel.write_all(&b) {
         Ok(_) => Ok(()),
         // Assume FH will hang after NAK..
-        Err(_) => firehose_reset(channel, &FirehoseResetMode::ResetToEdl, 0),
+        Err(_) => {
+            let delay = channel.fh_config().reset_delay;
+            firehose_reset(channel, &FirehoseResetMode::ResetToEdl, delay)
+        }
     }
 }
 
@@ -349,13 +502,91 @@ pub fn firehose_write_getack<T: QdlChan>(
         Ok(FirehoseStatus::Ack) => Ok(()),
         Ok(FirehoseStatus::Nak) => {
             // Assume FH will hang after NAK..
-            firehose_reset(channel, &FirehoseResetMode::ResetToEdl, 0)?;
+            let delay = channel.fh_config().reset_delay;
+            firehose_reset(channel, &FirehoseResetMode::ResetToEdl, delay)?;
             Err(anyhow::Error::msg(format!("Couldn't {couldnt_what}")))
         }
         Err(e) => Err(e),
     }
 }
 
+fn firehose_report_progress<T: QdlChan>(channel: &mut T, event: ProgressEvent) {
+    if let Some(cb) = channel.progress_sink() {
+        cb(event);
+    }
+}
+
+/// How far back the throughput is averaged over, when estimating the remaining time
+const ETA_WINDOW: Duration = Duration::from_secs(5);
+
+/// Estimates the remaining time of a transfer from its recent throughput, so that
+/// stalls and speedups (e.g. past a sparse image's holes) are accounted for quickly
+#[derive(Default)]
+struct EtaEstimator {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl EtaEstimator {
+    fn update(&mut self, bytes_done: u64, total_bytes: u64) -> Option<Duration> {
+        let now = Instant::now();
+        self.samples.push_back((now, bytes_done));
+        while self.samples.len() > 2 && now - self.samples[1].0 > ETA_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let (then, bytes_then) = *self.samples.front()?;
+        let rate = (bytes_done - bytes_then) as f64 / (now - then).as_secs_f64();
+        if !rate.is_normal() {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(
+            total_bytes.saturating_sub(bytes_done) as f64 / rate,
+        ))
+    }
+}
+
+fn format_eta(eta: Option<Duration>) -> String {
+    let Some(secs) = eta.map(|e| e.as_secs()) else {
+        return "--:--".to_owned();
+    };
+
+    match secs / 3600 {
+        0 => format!("{:02}:{:02}", secs / 60, secs % 60),
+        h => format!("{}:{:02}:{:02}", h, secs / 60 % 60, secs % 60),
+    }
+}
+
+/// Progress callback drawing a terminal progress bar, see [QdlChan::progress_sink]
+pub fn progress_bar_reporter() -> impl FnMut(ProgressEvent) + Send {
+    let mut pb: Option<(ProgressBar<std::io::Stdout>, String, EtaEstimator)> = None;
+
+    move |event| match event {
+        ProgressEvent::Start { label, total_bytes } => {
+            let mut bar = ProgressBar::new(total_bytes);
+            let prefix = label.map_or(String::new(), |l| format!("Sending partition {l}: "));
+            bar.message(&format!("{prefix}ETA {} ", format_eta(None)));
+            bar.set_units(Units::Bytes);
+            // Superseded by the (rolling) estimate in the message
+            bar.show_time_left = false;
+            pb = Some((bar, prefix, EtaEstimator::default()));
+        }
+        ProgressEvent::Advance {
+            bytes_done,
+            total_bytes,
+        } => {
+            if let Some((bar, prefix, eta)) = pb.as_mut() {
+                let eta = eta.update(bytes_done, total_bytes);
+                bar.message(&format!("{prefix}ETA {} ", format_eta(eta)));
+                bar.set(bytes_done);
+            }
+        }
+        ProgressEvent::Finish => {
+            pb = None;
+        }
+    }
+}
+
 /// Test performance without sample data
 pub fn firehose_benchmark<T: QdlChan>(
     channel: &mut T,
@@ -385,6 +616,11 @@ pub fn firehose_configure<T: QdlChan>(
     channel: &mut T,
     skip_storage_init: bool,
 ) -> anyhow::Result<()> {
+    if let Some(max) = channel.fh_config().max_payload_size {
+        let config = channel.mut_fh_config();
+        config.send_buffer_size = config.send_buffer_size.min(max);
+    }
+
     let config = channel.fh_config();
     // Spec requirement
     assert!(
@@ -430,12 +666,34 @@ pub fn firehose_nop<T: QdlChan>(channel: &mut T) -> anyhow::Result<()> {
     firehose_write_getack(channel, &mut xml, "issue a NOP".to_owned())
 }
 
+/// Send a hand-crafted Firehose packet as-is, printing out whatever the Device responds with.
+/// Only well-formedness is checked, so this can easily wedge (or worse) the Device.
+pub fn firehose_raw_xml<T: QdlChan>(channel: &mut T, xml: &[u8]) -> anyhow::Result<FirehoseStatus> {
+    let doc = Element::parse(xml).map_err(|e| anyhow::anyhow!("Couldn't parse the packet: {e}"))?;
+    if doc.name != "data" {
+        bail!(
+            "Firehose packets must have <data> as the root node, got <{}>",
+            doc.name
+        );
+    }
+    if xml.len() > channel.fh_config().xml_buf_size {
+        bail!(
+            "The packet is {} bytes long, but the Device only accepts up to {}",
+            xml.len(),
+            channel.fh_config().xml_buf_size
+        );
+    }
+
+    firehose_write(channel, &mut xml.to_vec())?;
+
+    firehose_read::<T>(channel, firehose_parser_print_response)
+}
+
 /// Get information about the physical partition of a storage medium (e.g. LUN)
-/// Prints to \<log\> only
 pub fn firehose_get_storage_info<T: QdlChan>(
     channel: &mut T,
     phys_part_idx: u8,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<StorageInfo> {
     let mut xml = firehose_xml_setup(
         "getstorageinfo",
         &[("physical_partition_number", &phys_part_idx.to_string())],
@@ -443,7 +701,42 @@ pub fn firehose_get_storage_info<T: QdlChan>(
 
     firehose_write(channel, &mut xml)?;
 
-    firehose_read::<T>(channel, firehose_parser_ack_nak).and(Ok(()))
+    let (status, logs) = firehose_read_logs::<T>(channel, firehose_parser_ack_nak)?;
+    if status != FirehoseStatus::Ack {
+        return Err(firehose_nak_error(channel, "<getstorageinfo>"));
+    }
+
+    firehose_parser_storage_info(&logs)
+}
+
+/// Erase a range of sectors using the Device's native erase mechanism (e.g. UFS unmap)
+pub fn firehose_erase<T: QdlChan>(
+    channel: &mut T,
+    start_sector: &str,
+    num_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+) -> anyhow::Result<()> {
+    let mut xml: Vec<u8> = firehose_xml_setup(
+        "erase",
+        &[
+            ("StorageType", &channel.fh_config().storage_type.to_string()),
+            (
+                "SECTOR_SIZE_IN_BYTES",
+                &channel.fh_config().storage_sector_size.to_string(),
+            ),
+            ("num_partition_sectors", &num_sectors.to_string()),
+            ("slot", &slot.to_string()),
+            ("physical_partition_number", &phys_part_idx.to_string()),
+            ("start_sector", start_sector),
+        ],
+    )?;
+
+    firehose_write_getack(
+        channel,
+        &mut xml,
+        format!("erase sectors {start_sector}+{num_sectors} on physical partition {phys_part_idx}"),
+    )
 }
 
 /// Alter Device (TODO: or Host) storage
@@ -473,7 +766,91 @@ pub fn firehose_patch<T: QdlChan>(
         ],
     )?;
 
-    firehose_write_getack(channel, &mut xml, "patch".to_string())
+    firehose_write_getack(channel, &mut xml, "patch".to_string())?;
+
+    let cfg = channel.fh_config();
+    if (cfg.read_back_verify || cfg.verify_sha256) && !cfg.bypass_storage {
+        firehose_verify_patch(
+            channel,
+            byte_off,
+            slot,
+            phys_part_idx,
+            size,
+            start_sector,
+            val,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Read back the bytes altered by a \<patch\> and compare them against its `value`.
+///
+/// Values computed by the Device (e.g. "NUM_DISK_SECTORS-1." or "CRC32(...)") can't be
+/// checked, so such patches are skipped.
+fn firehose_verify_patch<T: QdlChan>(
+    channel: &mut T,
+    byte_off: u64,
+    slot: u8,
+    phys_part_idx: u8,
+    size: u64,
+    start_sector: &str,
+    val: &str,
+) -> anyhow::Result<()> {
+    let literal = val.trim().trim_end_matches('.');
+    let expected = match literal.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => literal.parse::<u64>().ok(),
+    };
+    let (Some(expected), Ok(start), 1..=8) = (expected, start_sector.parse::<u64>(), size) else {
+        if !channel.fh_config().quiet {
+            eprintln!(
+                "{} value {} at sector {} (+{} bytes)",
+                "Skipping verification of patched".bright_yellow(),
+                val,
+                start_sector,
+                byte_off
+            );
+        }
+        return Ok(());
+    };
+
+    let sector_size = channel.fh_config().storage_sector_size as u64;
+    let first_sector = start + byte_off / sector_size;
+    let Ok(first_sector) = u32::try_from(first_sector) else {
+        bail!(
+            "Can't verify the patch at sector {}, it's out of the addressable range",
+            first_sector
+        );
+    };
+    let offset = (byte_off % sector_size) as usize;
+    let num_sectors = (offset as u64 + size).div_ceil(sector_size);
+
+    let mut buf = Vec::new();
+    firehose_read_storage(
+        channel,
+        &mut buf,
+        num_sectors as usize,
+        slot,
+        phys_part_idx,
+        first_sector,
+    )?;
+
+    let mut actual = [0u8; 8];
+    actual[..size as usize].copy_from_slice(&buf[offset..offset + size as usize]);
+    let actual = u64::from_le_bytes(actual);
+    if actual != expected {
+        bail!(
+            "Patch verification failed at sector {} (+{} bytes) on physical partition {}: expected {:#x}, got {:#x}",
+            first_sector,
+            offset,
+            phys_part_idx,
+            expected,
+            actual
+        );
+    }
+
+    Ok(())
 }
 
 /// Peek at memory
@@ -504,14 +881,19 @@ pub fn firehose_peek<T: QdlChan>(
 
 /// Poke at memory
 /// This can lead to lock-ups and resets
-// TODO:x
 pub fn firehose_poke<T: QdlChan>(
     channel: &mut T,
     addr: u64,
-    // TODO: byte count is 1..=8
     byte_count: u8,
     val: u64,
 ) -> anyhow::Result<()> {
+    if ![1, 2, 4, 8].contains(&byte_count) {
+        bail!("Invalid <poke> width ({byte_count}), must be one of 1/2/4/8 bytes");
+    }
+    if byte_count < 8 && val >> (byte_count * 8) != 0 {
+        bail!("Value {val:#x} doesn't fit in {byte_count} bytes");
+    }
+
     let mut xml: Vec<u8> = firehose_xml_setup(
         "poke",
         &[
@@ -521,20 +903,147 @@ pub fn firehose_poke<T: QdlChan>(
         ],
     )?;
 
-    firehose_write_getack(channel, &mut xml, format!("peek @ {addr:#x}"))
+    firehose_write_getack(channel, &mut xml, format!("poke @ {addr:#x}"))
 }
 
+/// Delay before the first re-attempt of a NAKed \<program\>, doubling with every one after
+const PROGRAM_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Keeps the backoff from growing past ~6s (100ms * 2^6)
+const PROGRAM_RETRY_MAX_DOUBLINGS: u32 = 6;
+
 /// Write to Device storage
+/// NAKed operations are retried up to [FirehoseConfiguration::program_retries] times,
+/// with an exponential backoff, rewinding `data` to its initial position each time
 pub fn firehose_program_storage<T: QdlChan>(
     channel: &mut T,
-    data: &mut impl Read,
+    data: &mut (impl Read + Seek),
+    label: &str,
+    num_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: &str,
+) -> anyhow::Result<TransferStats> {
+    let data_start = data.stream_position()?;
+    let mut attempt = 0;
+
+    loop {
+        let mut hasher = Sha256::new();
+        let verify = channel.fh_config().verify_sha256 && !channel.fh_config().bypass_storage;
+        let start = Instant::now();
+
+        let status = firehose_program_storage_once(
+            channel,
+            data,
+            label,
+            num_sectors,
+            slot,
+            phys_part_idx,
+            start_sector,
+            verify.then_some(&mut hasher),
+        )?;
+        let stats = TransferStats {
+            bytes: (num_sectors * channel.fh_config().storage_sector_size) as u64,
+            sectors: num_sectors as u64,
+            elapsed: start.elapsed(),
+        };
+
+        match status {
+            FirehoseStatus::Ack if verify => {
+                firehose_verify_sha256(
+                    channel,
+                    hasher.finalize().into(),
+                    label,
+                    num_sectors,
+                    slot,
+                    phys_part_idx,
+                    start_sector,
+                )?;
+                return Ok(stats);
+            }
+            FirehoseStatus::Ack => {
+                if channel.fh_config().bypass_storage
+                    && let Some(tally) = channel.bypassed_writes()
+                {
+                    *tally.entry(label.to_owned()).or_default() += stats;
+                }
+                return Ok(stats);
+            }
+            FirehoseStatus::Nak if attempt < channel.fh_config().program_retries => {
+                let delay =
+                    PROGRAM_RETRY_DELAY * 2u32.pow(attempt.min(PROGRAM_RETRY_MAX_DOUBLINGS));
+                attempt += 1;
+                eprintln!(
+                    "{} {} (sectors {}+{}), retrying in {:?} ({}/{})",
+                    "<program> NAKed for".bright_red(),
+                    label,
+                    start_sector,
+                    num_sectors,
+                    delay,
+                    attempt,
+                    channel.fh_config().program_retries
+                );
+                sleep(delay);
+                data.seek(SeekFrom::Start(data_start))?;
+            }
+            FirehoseStatus::Nak => {
+                let err = firehose_nak_error(channel, &format!("<program> of {label}"));
+                if channel.fh_config().last_nak_reason.is_none() {
+                    bail!("{err}. Did you set sector-size correctly?");
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Compare the Device's digest of a freshly programmed range against the Host-side one
+fn firehose_verify_sha256<T: QdlChan>(
+    channel: &mut T,
+    expected: [u8; 32],
     label: &str,
     num_sectors: usize,
     slot: u8,
     phys_part_idx: u8,
     start_sector: &str,
 ) -> anyhow::Result<()> {
-    let mut sectors_left = num_sectors;
+    let Ok(start) = start_sector.parse::<u32>() else {
+        if !channel.fh_config().quiet {
+            eprintln!(
+                "{} {label} (start_sector = {start_sector})",
+                "Skipping SHA256 verification of".bright_yellow()
+            );
+        }
+        return Ok(());
+    };
+
+    let digest = firehose_checksum_storage(channel, num_sectors, slot, phys_part_idx, start)?;
+    if digest != expected {
+        let to_hex = |d: &[u8]| d.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        bail!(
+            "SHA256 mismatch for {} (sectors {}+{} on physical partition {}): expected {}, got {}",
+            label,
+            start_sector,
+            num_sectors,
+            phys_part_idx,
+            to_hex(&expected),
+            to_hex(&digest)
+        );
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn firehose_program_storage_once<T: QdlChan>(
+    channel: &mut T,
+    data: &mut impl Read,
+    label: &str,
+    num_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: &str,
+    hasher: Option<&mut Sha256>,
+) -> anyhow::Result<FirehoseStatus> {
     let mut xml = firehose_xml_setup(
         "program",
         &[
@@ -556,13 +1065,31 @@ pub fn firehose_program_storage<T: QdlChan>(
     firehose_write(channel, &mut xml)?;
 
     if firehose_read::<T>(channel, firehose_parser_ack_nak)? != FirehoseStatus::Ack {
-        bail!("<program> was NAKed. Did you set sector-size correctly?");
+        return Ok(FirehoseStatus::Nak);
     }
 
-    let mut pb = ProgressBar::new((sectors_left * channel.fh_config().storage_sector_size) as u64);
-    pb.show_time_left = true;
-    pb.message(&format!("Sending partition {label}: "));
-    pb.set_units(Units::Bytes);
+    firehose_send_raw_data(channel, data, label, num_sectors, hasher)
+}
+
+/// Stream `num_sectors` worth of `data` following an ACKed \<program\>-like command,
+/// returning the Device's verdict
+fn firehose_send_raw_data<T: QdlChan>(
+    channel: &mut T,
+    data: &mut impl Read,
+    label: &str,
+    num_sectors: usize,
+    mut hasher: Option<&mut Sha256>,
+) -> anyhow::Result<FirehoseStatus> {
+    let mut sectors_left = num_sectors;
+    let total_bytes = (sectors_left * channel.fh_config().storage_sector_size) as u64;
+    let mut bytes_done = 0;
+    firehose_report_progress(
+        channel,
+        ProgressEvent::Start {
+            label: Some(label),
+            total_bytes,
+        },
+    );
 
     while sectors_left > 0 {
         let chunk_size_sectors = min(
@@ -576,24 +1103,140 @@ pub fn firehose_program_storage<T: QdlChan>(
                 chunk_size_sectors * channel.fh_config().storage_sector_size,
             )
         ];
-        let _ = data.read(&mut buf).unwrap();
+        // Short reads (e.g. at EOF) leave the remainder of the buffer zeroed, save for the
+        // tail of the last sector with data in it, which gets the configured fill byte
+        let mut filled = 0;
+        while filled < buf.len() {
+            match data.read(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        let sector_end = filled.next_multiple_of(channel.fh_config().storage_sector_size);
+        buf[filled..sector_end].fill(channel.fh_config().fill_byte);
+
+        if let Some(h) = hasher.as_deref_mut() {
+            h.update(&buf);
+        }
 
-        let n = channel.write(&buf).expect("Error sending data");
+        let n = channel.write(&buf)?;
         if n != chunk_size_sectors * channel.fh_config().storage_sector_size {
             bail!("Wrote an unexpected number of bytes ({})", n);
         }
 
         sectors_left -= chunk_size_sectors;
-        pb.add((chunk_size_sectors * channel.fh_config().storage_sector_size) as u64);
+        bytes_done += n as u64;
+        firehose_report_progress(
+            channel,
+            ProgressEvent::Advance {
+                bytes_done,
+                total_bytes,
+            },
+        );
     }
+    firehose_report_progress(channel, ProgressEvent::Finish);
 
     // Send a Zero-Length Packet to indicate end of stream
     if channel.fh_config().backend == QdlBackend::Usb {
-        let _ = channel.write(&[]).expect("Error sending ZLP");
+        let _ = channel.write(&[])?;
     }
 
+    firehose_read::<T>(channel, firehose_parser_ack_nak)
+}
+
+/// Send a storage provisioning descriptor (e.g. \<ufs\>), as found in provisioning XMLs
+pub fn firehose_provision<T: QdlChan>(
+    channel: &mut T,
+    tag: &str,
+    attrs: &IndexMap<String, String>,
+) -> anyhow::Result<()> {
+    let kvps = attrs
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect::<Vec<_>>();
+    let mut xml = firehose_xml_setup(tag, &kvps)?;
+
+    firehose_write_getack(
+        channel,
+        &mut xml,
+        format!(
+            "apply the <{tag}> descriptor ({})",
+            kvps.iter()
+                .map(|(k, v)| format!("{k}=\"{v}\""))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+    )
+}
+
+/// Update the firmware of the storage medium
+pub fn firehose_firmware_write<T: QdlChan>(
+    channel: &mut T,
+    data: &mut impl Read,
+    num_sectors: usize,
+) -> anyhow::Result<()> {
+    let mut xml = firehose_xml_setup(
+        "firmwarewrite",
+        &[
+            (
+                "SECTOR_SIZE_IN_BYTES",
+                &channel.fh_config().storage_sector_size.to_string(),
+            ),
+            ("num_partition_sectors", &num_sectors.to_string()),
+        ],
+    )?;
+
+    firehose_write(channel, &mut xml)?;
     if firehose_read::<T>(channel, firehose_parser_ack_nak)? != FirehoseStatus::Ack {
-        bail!("Failed to complete 'write' op");
+        return Err(firehose_nak_error(channel, "<firmwarewrite>"));
+    }
+
+    match firehose_send_raw_data(channel, data, "firmware", num_sectors, None)? {
+        FirehoseStatus::Ack => Ok(()),
+        FirehoseStatus::Nak => Err(firehose_nak_error(channel, "The storage firmware")),
+    }
+}
+
+/// Write an Android sparse image to Device storage
+/// Every contiguous run of data gets its own \<program\>, "don't care" regions are skipped
+pub fn firehose_program_storage_sparse<T: QdlChan>(
+    channel: &mut T,
+    data: &mut (impl Read + Seek),
+    label: &str,
+    max_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: u64,
+) -> anyhow::Result<()> {
+    let sector_size = channel.fh_config().storage_sector_size as u64;
+    let img = sparse::parse_sparse_image(data)?;
+
+    if img.expanded_len > max_sectors as u64 * sector_size {
+        bail!(
+            "Sparse image for {} expands beyond its target ({} > {} bytes)",
+            label,
+            img.expanded_len,
+            max_sectors as u64 * sector_size
+        );
+    }
+
+    let runs = img.runs();
+    for (i, run) in runs.iter().enumerate() {
+        let offset = run[0].offset;
+        let len = run.iter().map(|c| c.len).sum::<u64>();
+        if !offset.is_multiple_of(sector_size) || !len.is_multiple_of(sector_size) {
+            bail!("Sparse image block size is not a multiple of the storage sector size");
+        }
+
+        firehose_program_storage(
+            channel,
+            &mut sparse::SparseRunReader::new(data, run),
+            &format!("{label} ({}/{})", i + 1, runs.len()),
+            (len / sector_size) as usize,
+            slot,
+            phys_part_idx,
+            &(start_sector + offset / sector_size).to_string(),
+        )?;
     }
 
     Ok(())
@@ -603,9 +1246,10 @@ pub fn firehose_program_storage<T: QdlChan>(
 pub fn firehose_checksum_storage<T: QdlChan>(
     channel: &mut T,
     num_sectors: usize,
+    slot: u8,
     phys_part_idx: u8,
     start_sector: u32,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<[u8; 32]> {
     let mut xml = firehose_xml_setup(
         "getsha256digest",
         &[
@@ -614,6 +1258,7 @@ pub fn firehose_checksum_storage<T: QdlChan>(
                 &channel.fh_config().storage_sector_size.to_string(),
             ),
             ("num_partition_sectors", &num_sectors.to_string()),
+            ("slot", &slot.to_string()),
             ("physical_partition_number", &phys_part_idx.to_string()),
             ("start_sector", &start_sector.to_string()),
         ],
@@ -622,14 +1267,99 @@ pub fn firehose_checksum_storage<T: QdlChan>(
     firehose_write(channel, &mut xml)?;
 
     // TODO: figure out some sane way to figure out the timeout
-    if firehose_read::<T>(channel, firehose_parser_ack_nak)? != FirehoseStatus::Ack {
-        bail!("Checksum request was NAKed");
+    let (status, logs) = firehose_read_logs::<T>(channel, firehose_parser_ack_nak)?;
+    if status != FirehoseStatus::Ack {
+        return Err(firehose_nak_error(channel, "Checksum request"));
+    }
+
+    firehose_parser_sha256_digest(&logs)
+}
+
+/// Get a CRC32 digest of a portion of Device storage, for loaders lacking \<getsha256digest\>
+pub fn firehose_crc32_storage<T: QdlChan>(
+    channel: &mut T,
+    num_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: u32,
+) -> anyhow::Result<u32> {
+    let mut xml = firehose_xml_setup(
+        "getcrc32digest",
+        &[
+            (
+                "SECTOR_SIZE_IN_BYTES",
+                &channel.fh_config().storage_sector_size.to_string(),
+            ),
+            ("num_partition_sectors", &num_sectors.to_string()),
+            ("slot", &slot.to_string()),
+            ("physical_partition_number", &phys_part_idx.to_string()),
+            ("start_sector", &start_sector.to_string()),
+        ],
+    )?;
+
+    firehose_write(channel, &mut xml)?;
+
+    let (status, logs) = firehose_read_logs::<T>(channel, firehose_parser_ack_nak)?;
+    if status != FirehoseStatus::Ack {
+        return Err(firehose_nak_error(channel, "Checksum request"));
+    }
+
+    firehose_parser_crc32_digest(&logs)
+}
+
+/// Fail early on reads past the end of storage, which some programmers hang on instead
+/// of NAKing them. The size of each physical partition is only asked for once.
+fn firehose_check_read_bounds<T: QdlChan>(
+    channel: &mut T,
+    num_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: u32,
+) -> anyhow::Result<()> {
+    // <getstorageinfo> can't address secondary storage devices
+    if slot != 0 || channel.fh_config().bypass_storage {
+        return Ok(());
+    }
+
+    let total_sectors = match channel.fh_config().storage_sectors.get(&phys_part_idx) {
+        Some(n) => *n,
+        None => {
+            let sector_size = channel.fh_config().storage_sector_size;
+            let n = firehose_get_storage_info(channel, phys_part_idx)
+                .ok()
+                .map(|info| info.total_sectors(sector_size))
+                .filter(|n| *n > 0);
+            channel
+                .mut_fh_config()
+                .storage_sectors
+                .insert(phys_part_idx, n);
+            n
+        }
+    };
+
+    if let Some(total) = total_sectors
+        && start_sector as u64 + num_sectors as u64 > total
+    {
+        bail!(
+            "Read of sectors {}+{} is out of range, physical partition {} only has {} sectors",
+            start_sector,
+            num_sectors,
+            phys_part_idx,
+            total
+        );
     }
 
     Ok(())
 }
 
+/// Granularity at which corrupted reads are pinpointed with [FirehoseConfiguration::hash_packets]
+const READ_VALIDATION_CHUNK_SIZE: usize = 1024 * 1024;
+
 /// Read (sector-aligned) parts of storage.
+///
+/// Firehose doesn't checksum the data it sends back, so with [FirehoseConfiguration::hash_packets]
+/// set, the result is compared against the Device's \<getsha256digest\> of the same range instead.
+/// A mismatch is reported as [FirehoseError::MalformedData].
 pub fn firehose_read_storage(
     channel: &mut impl QdlChan,
     out: &mut impl Write,
@@ -637,8 +1367,11 @@ pub fn firehose_read_storage(
     slot: u8,
     phys_part_idx: u8,
     start_sector: u32,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<TransferStats> {
+    firehose_check_read_bounds(channel, num_sectors, slot, phys_part_idx, start_sector)?;
+
     let mut bytes_left = num_sectors * channel.fh_config().storage_sector_size;
+    let stats_start = Instant::now();
     let mut xml = firehose_xml_setup(
         "read",
         &[
@@ -655,18 +1388,41 @@ pub fn firehose_read_storage(
 
     firehose_write(channel, &mut xml)?;
     if firehose_read(channel, firehose_parser_ack_nak)? != FirehoseStatus::Ack {
-        bail!("Read request was NAKed");
+        return Err(firehose_nak_error(channel, "<read>"));
     }
 
-    let mut pb = ProgressBar::new(bytes_left as u64);
-    pb.set_units(Units::Bytes);
+    let total_bytes = bytes_left as u64;
+    firehose_report_progress(
+        channel,
+        ProgressEvent::Start {
+            label: None,
+            total_bytes,
+        },
+    );
+
+    let sector_size = channel.fh_config().storage_sector_size;
+    let validate = channel.fh_config().hash_packets;
+    let chunk_len = max(1, READ_VALIDATION_CHUNK_SIZE / sector_size) * sector_size;
+    let mut range_hasher = Sha256::new();
+    let mut chunk_hasher = Sha256::new();
+    let mut chunk_filled = 0;
+    let mut chunk_digests: Vec<[u8; 32]> = Vec::new();
+
+    // Per-read overhead adds up quickly with small chunks, especially on fast UFS
+    let read_chunk_len = match channel.fh_config().read_chunk_sectors {
+        Some(n) => n * sector_size,
+        None => max(
+            channel.fh_config().recv_buffer_size,
+            channel.fh_config().send_buffer_size / sector_size * sector_size,
+        ),
+    };
 
     let mut last_read_was_zero_len = false;
     while bytes_left > 0 {
-        let chunk_size_bytes = min(bytes_left, channel.fh_config().recv_buffer_size);
+        let chunk_size_bytes = min(bytes_left, read_chunk_len);
         let mut buf = vec![0; chunk_size_bytes];
 
-        let n = channel.read(&mut buf).expect("Error receiving data");
+        let n = channel.read(&mut buf)?;
         if n == 0 {
             // TODO: need more robustness here
             /* Every 2 or 3 packets should be empty? */
@@ -675,11 +1431,35 @@ pub fn firehose_read_storage(
         }
 
         last_read_was_zero_len = false;
-        let _ = out.write(&buf[..n])?;
+        out.write_all(&buf[..n])?;
+
+        if validate {
+            range_hasher.update(&buf[..n]);
+
+            let mut data = &buf[..n];
+            while !data.is_empty() {
+                let len = min(data.len(), chunk_len - chunk_filled);
+                chunk_hasher.update(&data[..len]);
+                chunk_filled += len;
+                data = &data[len..];
+
+                if chunk_filled == chunk_len {
+                    chunk_digests.push(chunk_hasher.finalize_reset().into());
+                    chunk_filled = 0;
+                }
+            }
+        }
 
         bytes_left -= n;
-        pb.add(n as u64);
+        firehose_report_progress(
+            channel,
+            ProgressEvent::Advance {
+                bytes_done: total_bytes - bytes_left as u64,
+                total_bytes,
+            },
+        );
     }
+    firehose_report_progress(channel, ProgressEvent::Finish);
 
     if !last_read_was_zero_len && channel.fh_config().backend == QdlBackend::Usb {
         // Issue a dummy read to drain the queue
@@ -687,10 +1467,45 @@ pub fn firehose_read_storage(
     }
 
     if firehose_read(channel, firehose_parser_ack_nak)? != FirehoseStatus::Ack {
-        bail!("Failed to complete 'read' op");
+        return Err(firehose_nak_error(channel, "Completion of <read>"));
     }
 
-    Ok(())
+    if validate {
+        if chunk_filled > 0 {
+            chunk_digests.push(chunk_hasher.finalize().into());
+        }
+
+        let digest =
+            firehose_checksum_storage(channel, num_sectors, slot, phys_part_idx, start_sector)?;
+        if digest != <[u8; 32]>::from(range_hasher.finalize()) {
+            // Narrow the corruption down to a single chunk
+            let chunk_sectors = chunk_len / sector_size;
+            for (i, expected) in chunk_digests.iter().enumerate() {
+                let sector = start_sector as usize + i * chunk_sectors;
+                let len = min(chunk_sectors, num_sectors - i * chunk_sectors);
+                if firehose_checksum_storage(channel, len, slot, phys_part_idx, sector as u32)?
+                    != *expected
+                {
+                    return Err(FirehoseError::MalformedData {
+                        sector: sector as u64,
+                    }
+                    .into());
+                }
+            }
+
+            // Every chunk matches on its own, so the range as a whole is to blame
+            return Err(FirehoseError::MalformedData {
+                sector: start_sector as u64,
+            }
+            .into());
+        }
+    }
+
+    Ok(TransferStats {
+        bytes: (num_sectors * channel.fh_config().storage_sector_size) as u64,
+        sectors: num_sectors as u64,
+        elapsed: stats_start.elapsed(),
+    })
 }
 
 /// Reboot or power off the Device
@@ -708,6 +1523,8 @@ pub fn firehose_reset<T: QdlChan>(
                     FirehoseResetMode::ResetToEdl => "reset_to_edl",
                     FirehoseResetMode::Reset => "reset",
                     FirehoseResetMode::Off => "off",
+                    // The bootloader takes it from here
+                    FirehoseResetMode::Fastboot | FirehoseResetMode::Recovery => "reset",
                 },
             ),
             ("DelayInSeconds", &delay_in_sec.to_string()),
@@ -715,6 +1532,7 @@ pub fn firehose_reset<T: QdlChan>(
     )?;
 
     firehose_write_getack(channel, &mut xml, "reset the Device".to_owned())?;
+    channel.set_idle(false);
 
     // Drain the incoming LOG packets to actually restart the device
     let _ = channel.skip_until(0);
@@ -722,6 +1540,59 @@ pub fn firehose_reset<T: QdlChan>(
     Ok(())
 }
 
+/// Size of AOSP's `struct bootloader_message`, at the very start of the misc partition
+const BOOTLOADER_MESSAGE_SIZE: usize = 2048;
+
+/// Leave a bootloader message (as per AOSP's bootloader_message.h) in the misc partition,
+/// starting at `start_sector`, for the bootloader to act upon during the next boot.
+/// `command` is e.g. "bootonce-bootloader" or "boot-recovery". Whatever follows the
+/// message (such as A/B metadata) is preserved.
+pub fn firehose_write_bootloader_message<T: QdlChan>(
+    channel: &mut T,
+    command: &str,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: u32,
+) -> anyhow::Result<()> {
+    let sector_size = channel.fh_config().storage_sector_size;
+    let num_sectors = BOOTLOADER_MESSAGE_SIZE.div_ceil(sector_size);
+
+    let mut buf = Vec::new();
+    firehose_read_storage(
+        channel,
+        &mut buf,
+        num_sectors,
+        slot,
+        phys_part_idx,
+        start_sector,
+    )?;
+
+    if buf.len() < BOOTLOADER_MESSAGE_SIZE {
+        bail!("Short read of the misc partition ({} bytes)", buf.len());
+    }
+
+    // char command[32], status[32], recovery[768], ...
+    let command = &command.as_bytes()[..command.len().min(31)];
+    buf[..BOOTLOADER_MESSAGE_SIZE].fill(0);
+    buf[..command.len()].copy_from_slice(command);
+    if command == b"boot-recovery" {
+        let args = b"recovery\n";
+        buf[64..64 + args.len()].copy_from_slice(args);
+    }
+
+    firehose_program_storage(
+        channel,
+        &mut Cursor::new(buf),
+        "misc",
+        num_sectors,
+        slot,
+        phys_part_idx,
+        &start_sector.to_string(),
+    )?;
+
+    Ok(())
+}
+
 /// Mark a physical storage partition as bootable
 pub fn firehose_set_bootable<T: QdlChan>(channel: &mut T, drive_idx: u8) -> anyhow::Result<()> {
     let mut xml = firehose_xml_setup(
@@ -736,8 +1607,21 @@ pub fn firehose_set_bootable<T: QdlChan>(channel: &mut T, drive_idx: u8) -> anyh
     )
 }
 
+/// Mark an A/B slot as active (e.g. "a" or "b")
+pub fn firehose_set_active_slot<T: QdlChan>(channel: &mut T, slot: &str) -> anyhow::Result<()> {
+    if !["a", "b"].contains(&slot) {
+        bail!("Invalid slot \"{}\", expected \"a\" or \"b\"", slot);
+    }
+
+    let mut xml = firehose_xml_setup("setactiveslot", &[("slot", slot)])?;
+
+    firehose_write_getack(channel, &mut xml, format!("set slot {slot} as active"))
+}
+
+/// Typical sector size of a given storage type. For NAND, that's the page size
+/// (usually 4 KiB, with 2 KiB pages only found on older parts).
 pub fn firehose_get_default_sector_size(t: &str) -> Option<usize> {
-    match FirehoseStorageType::from_str(t).unwrap() {
+    match FirehoseStorageType::from_str(t).ok()? {
         FirehoseStorageType::Emmc => Some(512),
         FirehoseStorageType::Nand => Some(4096),
         FirehoseStorageType::Nvme => Some(512),
@@ -745,3 +1629,294 @@ pub fn firehose_get_default_sector_size(t: &str) -> Option<usize> {
         FirehoseStorageType::Spinor => Some(4096),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::QdlMockDevice;
+    use crate::parsers::firehose_parser_configure_response;
+    use anstream::println;
+
+    fn configure(mock: QdlMockDevice) -> (types::QdlDevice<QdlMockDevice>, Result<FirehoseStatus>) {
+        let mut dev = mock.into_device();
+        firehose_configure(&mut dev, false).unwrap();
+        let status = firehose_read(&mut dev, firehose_parser_configure_response);
+
+        (dev, status)
+    }
+
+    #[test]
+    fn configure_ack() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_configure_ack(1);
+
+        let (dev, status) = configure(mock);
+        assert_eq!(status.unwrap(), FirehoseStatus::Ack);
+        assert!(dev.rw.is_drained());
+        assert_eq!(dev.fh_cfg.protocol_version, Some(1));
+        assert_eq!(dev.fh_cfg.target_name.as_deref(), Some("mock"));
+        assert_eq!(dev.fh_cfg.send_buffer_size, 1024 * 1024);
+
+        let written = dev.rw.written_xml();
+        assert_eq!(written.len(), 1);
+        assert!(written[0].contains("<configure "));
+        assert!(written[0].contains("MemoryName=\"emmc\""));
+    }
+
+    #[test]
+    fn configure_nak_with_smaller_buffer() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_xml(
+            "response",
+            &[
+                ("value", "NAK"),
+                ("MaxPayloadSizeToTargetInBytes", "65536"),
+                ("MaxPayloadSizeToTargetInBytesSupported", "65536"),
+            ],
+        );
+
+        let (dev, status) = configure(mock);
+        assert_eq!(status.unwrap(), FirehoseStatus::Ack);
+        assert_eq!(dev.fh_cfg.send_buffer_size, 65536);
+    }
+
+    #[test]
+    fn configure_nak() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_nak().push_ack();
+
+        let (dev, status) = configure(mock);
+        assert!(status.is_err());
+        // The Device is sent back to EDL to start over
+        let written = dev.rw.written_xml();
+        assert!(written.last().unwrap().contains("value=\"reset_to_edl\""));
+    }
+
+    #[test]
+    fn program_ack() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_rawmode_ack().push_ack();
+        let mut dev = mock.into_device();
+
+        let data = vec![0xa5; 1024];
+        let stats =
+            firehose_program_storage(&mut dev, &mut Cursor::new(&data), "test", 2, 0, 1, "34")
+                .unwrap();
+        assert_eq!(stats.sectors, 2);
+        assert!(dev.rw.is_drained());
+
+        let xml = dev.rw.written_xml();
+        assert_eq!(xml.len(), 1);
+        assert!(xml[0].contains("num_partition_sectors=\"2\""));
+        assert!(xml[0].contains("physical_partition_number=\"1\""));
+        assert!(xml[0].contains("start_sector=\"34\""));
+        let raw = dev.rw.written.iter().skip(1).flatten().copied();
+        assert!(raw.eq(data));
+    }
+
+    #[test]
+    fn program_nak() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_log("ERROR: Write protected").push_nak();
+        let mut dev = mock.into_device();
+
+        let err =
+            firehose_program_storage(&mut dev, &mut Cursor::new([0; 512]), "test", 1, 0, 0, "0")
+                .unwrap_err();
+        match err.downcast_ref::<FirehoseError>() {
+            Some(FirehoseError::Nak { reason, .. }) => {
+                assert_eq!(reason.as_deref(), Some("ERROR: Write protected"))
+            }
+            _ => panic!("Unexpected error: {err}"),
+        }
+        // No data may follow a NAKed <program>
+        assert_eq!(dev.rw.written.len(), 1);
+    }
+
+    #[test]
+    fn program_nak_retried() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_nak().push_rawmode_ack().push_ack();
+        let mut dev = mock.into_device();
+        dev.fh_cfg.program_retries = 1;
+
+        let data = (0..512).map(|i| i as u8).collect::<Vec<_>>();
+        firehose_program_storage(&mut dev, &mut Cursor::new(&data), "test", 1, 0, 0, "0").unwrap();
+        assert_eq!(dev.rw.written_xml().len(), 2);
+        // Followed by a ZLP
+        let raw = dev.rw.written.iter().rev().find(|w| !w.is_empty());
+        assert_eq!(raw, Some(&data));
+    }
+
+    /// The \<log\> + ACK a Device responds to \<getsha256digest\> with
+    fn push_digest(mock: &mut QdlMockDevice, data: &[u8]) {
+        let digest = Sha256::digest(data)
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect::<String>();
+        mock.push_log(&format!("Digest {digest}")).push_ack();
+    }
+
+    #[test]
+    fn read_storage_pinpoints_corruption() {
+        let chunk_len = READ_VALIDATION_CHUNK_SIZE;
+        let stored = (0..3 * chunk_len)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut received = stored.clone();
+        received[chunk_len + 1234] ^= 0xff;
+
+        let mut mock = QdlMockDevice::default();
+        mock.push_rawmode_ack().push_raw(&received).push_ack();
+        // The whole range, then chunk by chunk until the culprit is found
+        push_digest(&mut mock, &stored);
+        push_digest(&mut mock, &stored[..chunk_len]);
+        push_digest(&mut mock, &stored[chunk_len..2 * chunk_len]);
+        let mut dev = mock.into_device();
+        dev.fh_cfg.hash_packets = true;
+
+        let num_sectors = stored.len() / 512;
+        let err =
+            firehose_read_storage(&mut dev, &mut Vec::new(), num_sectors, 0, 0, 100).unwrap_err();
+        match err.downcast_ref::<FirehoseError>() {
+            Some(FirehoseError::MalformedData { sector }) => {
+                assert_eq!(*sector, 100 + (chunk_len / 512) as u64)
+            }
+            _ => panic!("Unexpected error: {err}"),
+        }
+        assert!(dev.rw.is_drained());
+
+        let xml = dev.rw.written_xml();
+        assert!(xml[3].contains("start_sector=\"2148\""));
+        assert!(xml[3].contains("num_partition_sectors=\"2048\""));
+    }
+
+    #[test]
+    fn program_pads_last_sector() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_rawmode_ack().push_ack();
+        let mut dev = mock.into_device();
+        dev.fh_cfg.fill_byte = 0xff;
+
+        let data = vec![0xa5; 700];
+        firehose_program_storage(&mut dev, &mut Cursor::new(&data), "test", 3, 0, 0, "0").unwrap();
+
+        let raw = &dev.rw.written[1];
+        assert_eq!(raw.len(), 3 * 512);
+        assert_eq!(&raw[..700], &data[..]);
+        assert!(raw[700..1024].iter().all(|b| *b == 0xff));
+        assert!(raw[1024..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn program_zero_fill_ignores_fill_byte() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_rawmode_ack().push_ack();
+        let mut dev = mock.into_device();
+        dev.fh_cfg.fill_byte = 0xff;
+
+        firehose_program_storage(&mut dev, &mut std::io::empty(), "erase", 2, 0, 0, "0").unwrap();
+        assert_eq!(dev.rw.written[1], vec![0; 2 * 512]);
+    }
+
+    #[test]
+    fn default_sector_sizes() {
+        for (t, size) in [
+            ("emmc", 512),
+            ("nand", 4096),
+            ("nvme", 512),
+            ("ufs", 4096),
+            ("spinor", 4096),
+        ] {
+            assert_eq!(firehose_get_default_sector_size(t), Some(size), "{t}");
+        }
+        assert_eq!(firehose_get_default_sector_size("floppy"), None);
+        assert_eq!(firehose_get_default_sector_size(""), None);
+    }
+
+    #[test]
+    fn read_fragmented_packet() {
+        let log =
+            r#"<?xml version="1.0" encoding="UTF-8" ?><data><log value="INFO: hello" /></data>"#;
+        let ack = r#"<?xml version="1.0" encoding="UTF-8" ?><data><response value="ACK" rawmode="false" /></data>"#;
+        // Split with no regard for packet boundaries. This only covers the reassembly, which
+        // is the same for every backend, see the serial module for its own buffering
+        let stream = format!("{log}{ack}");
+        let mut mock = QdlMockDevice::default();
+        for chunk in stream.as_bytes().chunks(7) {
+            mock.push_raw(chunk);
+        }
+        let mut dev = mock.into_device();
+
+        let (status, logs) = firehose_read_logs(&mut dev, firehose_parser_ack_nak).unwrap();
+        assert_eq!(status, FirehoseStatus::Ack);
+        assert_eq!(logs, ["INFO: hello"]);
+        assert!(dev.rw.is_drained());
+    }
+
+    #[test]
+    fn read_storage_slot() {
+        let data = vec![0x5a; 2 * 512];
+        let mut mock = QdlMockDevice::default();
+        mock.push_rawmode_ack().push_raw(&data).push_ack();
+        let mut dev = mock.into_device();
+        // Only the primary storage device is ever bounds checked
+        dev.fh_cfg.bypass_storage = false;
+
+        let mut out = Vec::new();
+        firehose_read_storage(&mut dev, &mut out, 2, 1, 3, 40).unwrap();
+        assert_eq!(out, data);
+        assert!(dev.rw.is_drained());
+
+        let xml = dev.rw.written_xml();
+        assert_eq!(xml.len(), 1);
+        assert!(xml[0].contains("<read "));
+        assert!(xml[0].contains("slot=\"1\""));
+        assert!(xml[0].contains("physical_partition_number=\"3\""));
+        assert!(xml[0].contains("start_sector=\"40\""));
+    }
+
+    /// Throughput of \<read\>s at various chunk sizes, with the Device side (and thus the
+    /// storage) taken out of the picture. The per-request overhead of real hardware is what
+    /// the number of reads stands for. See the results with `cargo test -- --nocapture`.
+    #[test]
+    fn read_chunk_sectors_benchmark() {
+        let data = vec![0x3c; 16 * 1024 * 1024];
+        let num_sectors = data.len() / 512;
+
+        // The default follows the negotiated (1 MiB) payload size
+        for (chunk_sectors, expected_reads) in [(Some(8), 4096), (Some(256), 128), (None, 16)] {
+            let mut mock = QdlMockDevice::default();
+            mock.push_rawmode_ack().push_raw(&data).push_ack();
+            let mut dev = mock.into_device();
+            dev.fh_cfg.read_chunk_sectors = chunk_sectors;
+
+            let mut out = Vec::with_capacity(data.len());
+            let stats = firehose_read_storage(&mut dev, &mut out, num_sectors, 0, 0, 0).unwrap();
+            assert!(out == data);
+            assert_eq!(dev.rw.reads, expected_reads);
+
+            println!(
+                "{:>7} sectors per read: {} reads, {}",
+                chunk_sectors.map_or("default".to_owned(), |n| n.to_string()),
+                dev.rw.reads,
+                stats
+            );
+        }
+    }
+
+    #[test]
+    fn verify_patch_out_of_range() {
+        let mut mock = QdlMockDevice::default();
+        mock.push_ack();
+        let mut dev = mock.into_device();
+        dev.fh_cfg.bypass_storage = false;
+        dev.fh_cfg.read_back_verify = true;
+
+        // Past u32::MAX once the byte offset is accounted for
+        let start_sector = u32::MAX.to_string();
+        assert!(firehose_patch(&mut dev, 512, 0, 0, 4, &start_sector, "0").is_err());
+        // Rather than reading back some other sector
+        assert_eq!(dev.rw.written_xml().len(), 1);
+    }
+}
@@ -0,0 +1,186 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+//! Scriptable stand-in for a Device, so that the protocol logic can be exercised without
+//! any hardware attached
+use std::collections::VecDeque;
+use std::io::{BufRead, ErrorKind, Read, Write};
+
+use crate::types::{FirehoseConfiguration, QdlDevice, QdlReadWrite};
+
+/// A loopback channel that replays canned Device responses and records everything
+/// the Host sends. Each response is handed out as a separate transfer, as would be
+/// the case over USB. Once the script runs out, reads time out.
+#[derive(Debug, Default)]
+pub struct QdlMockDevice {
+    responses: VecDeque<Vec<u8>>,
+    /// How much of the front response has been read already
+    pos: usize,
+    /// Every write issued by the Host, in order
+    pub written: Vec<Vec<u8>>,
+    /// How many (non-empty) reads the Host issued, i.e. the number of transfers it'd take
+    pub reads: usize,
+}
+
+impl QdlMockDevice {
+    /// Queue up raw bytes (e.g. a Sahara packet or \<read\> data)
+    pub fn push_raw(&mut self, data: &[u8]) -> &mut Self {
+        self.responses.push_back(data.to_vec());
+        self
+    }
+
+    /// Queue up a Firehose packet with a single `tag` node
+    pub fn push_xml(&mut self, tag: &str, attrs: &[(&str, &str)]) -> &mut Self {
+        let attrs = attrs
+            .iter()
+            .map(|(k, v)| format!(" {k}=\"{v}\""))
+            .collect::<String>();
+        let xml =
+            format!("<?xml version=\"1.0\" encoding=\"UTF-8\" ?><data><{tag}{attrs} /></data>");
+
+        self.push_raw(xml.as_bytes())
+    }
+
+    pub fn push_ack(&mut self) -> &mut Self {
+        self.push_xml("response", &[("value", "ACK"), ("rawmode", "false")])
+    }
+
+    /// An ACK to a command followed by raw data, e.g. \<read\> or \<program\>
+    pub fn push_rawmode_ack(&mut self) -> &mut Self {
+        self.push_xml("response", &[("value", "ACK"), ("rawmode", "true")])
+    }
+
+    pub fn push_nak(&mut self) -> &mut Self {
+        self.push_xml("response", &[("value", "NAK"), ("rawmode", "false")])
+    }
+
+    pub fn push_log(&mut self, msg: &str) -> &mut Self {
+        self.push_xml("log", &[("value", msg)])
+    }
+
+    /// A successful \<configure\> response, with typical buffer sizes
+    pub fn push_configure_ack(&mut self, version: u32) -> &mut Self {
+        self.push_xml(
+            "response",
+            &[
+                ("value", "ACK"),
+                ("MinVersionSupported", "1"),
+                ("Version", &version.to_string()),
+                ("MemoryName", "UFS"),
+                ("TargetName", "mock"),
+                ("MaxPayloadSizeFromTargetInBytes", "4096"),
+                ("MaxPayloadSizeToTargetInBytes", "1048576"),
+                ("MaxPayloadSizeToTargetInBytesSupported", "1048576"),
+                ("MaxXMLSizeInBytes", "4096"),
+            ],
+        )
+    }
+
+    /// Script the Device to respond exactly like it did in a log saved through
+    /// [crate::types::QdlDevice::fh_log], for reproducing failures offline. Only the
+    /// XML is captured, so the replay diverges at the first raw data transfer.
+    pub fn from_firehose_log(log: &str) -> Self {
+        let mut dev = Self::default();
+        let data_end_marker = "</data>";
+
+        // Every packet is prefixed by a timestamp, e.g. "[1700000000.123] <?xml ..."
+        let mut rest = log;
+        while let Some(start) = rest.find("] <") {
+            rest = &rest[start + "] ".len()..];
+            let Some(end) = rest.find(data_end_marker) else {
+                break;
+            };
+            let end = end + data_end_marker.len();
+            dev.push_raw(&rest.as_bytes()[..end]);
+            rest = &rest[end..];
+        }
+
+        dev
+    }
+
+    /// Hook the script up to a (quiet) [QdlDevice], as the Firehose functions expect
+    pub fn into_device(self) -> QdlDevice<Self> {
+        QdlDevice {
+            rw: Box::new(self),
+            fh_cfg: FirehoseConfiguration {
+                quiet: true,
+                ..Default::default()
+            },
+            fh_log: None,
+            progress: None,
+            bypassed: Default::default(),
+            interrupt: None,
+            activity: None,
+            reset_on_drop: false,
+        }
+    }
+
+    /// Whether the whole script has been consumed
+    pub fn is_drained(&self) -> bool {
+        self.responses
+            .iter()
+            .enumerate()
+            .all(|(i, r)| r.len() <= if i == 0 { self.pos } else { 0 })
+    }
+
+    /// The Host's writes that were Firehose packets, as strings
+    pub fn written_xml(&self) -> Vec<String> {
+        self.written
+            .iter()
+            .filter(|w| w.starts_with(b"<?xml"))
+            .map(|w| String::from_utf8_lossy(w).into_owned())
+            .collect()
+    }
+}
+
+impl Read for QdlMockDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Zero-length reads are used to drain ZLPs, which aren't scripted
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.reads += 1;
+
+        let data = self.fill_buf()?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.consume(n);
+
+        Ok(n)
+    }
+}
+
+impl BufRead for QdlMockDevice {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        while self.responses.front().is_some_and(|r| r.len() <= self.pos) {
+            self.responses.pop_front();
+            self.pos = 0;
+        }
+
+        match self.responses.front() {
+            Some(r) => Ok(&r[self.pos..]),
+            None => Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "The mock Device ran out of responses",
+            )),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some(r) = self.responses.front() {
+            self.pos = (self.pos + amt).min(r.len());
+        }
+    }
+}
+
+impl Write for QdlMockDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.written.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl QdlReadWrite for QdlMockDevice {}
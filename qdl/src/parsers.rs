@@ -2,17 +2,24 @@
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
 
 use indexmap::IndexMap;
+use std::fmt::Display;
+use std::str::FromStr;
 
 use anyhow::bail;
 use owo_colors::OwoColorize;
+use xmltree::{Element, XMLNode};
 
 use crate::{
     FirehoseResetMode, FirehoseStatus, QdlChan, firehose_configure, firehose_read, firehose_reset,
+    types::{FirehoseError, StorageInfo},
 };
 
 /// The highest protocol version currently supported by the library
 const FH_PROTO_VERSION_SUPPORTED: u32 = 1;
 
+/// What programmers that don't state their XML buffer size can be expected to handle
+const FH_DEFAULT_XML_SIZE: usize = 4096;
+
 // Parsers are kept separate for more flexibility (e.g. log replay analysis)
 
 /// Check "value" for ack/nak (generic)
@@ -28,6 +35,205 @@ pub fn firehose_parser_ack_nak<T: QdlChan>(
     }
 }
 
+/// Print out the whole response, then check "value" for ack/nak
+pub fn firehose_parser_print_response<T: QdlChan>(
+    channel: &mut T,
+    attrs: &IndexMap<String, String>,
+) -> Result<FirehoseStatus, anyhow::Error> {
+    println!(
+        "RESPONSE: {}",
+        attrs
+            .iter()
+            .map(|(k, v)| format!("{k}=\"{v}\""))
+            .collect::<Vec<_>>()
+            .join(" ")
+            .bright_cyan()
+    );
+
+    firehose_parser_ack_nak(channel, attrs)
+}
+
+/// Extract the \<log\> messages out of a raw blob of (possibly concatenated) Firehose XML documents
+pub fn firehose_parser_log_lines(buf: &[u8]) -> Vec<String> {
+    let data_end_marker = b"</data>";
+    let mut logs = Vec::new();
+    let mut rest = buf;
+
+    while let Some(pos) = rest
+        .windows(data_end_marker.len())
+        .position(|window| window == data_end_marker)
+    {
+        let xml_end = pos + data_end_marker.len();
+        if let Ok(xml) = Element::parse(&rest[..xml_end]) {
+            for node in xml.children.iter() {
+                if let XMLNode::Element(e) = node
+                    && e.name == "log"
+                    && let Some(val) = e.attributes.get("value")
+                {
+                    logs.push(val.to_owned());
+                }
+            }
+        }
+        rest = &rest[xml_end..];
+    }
+
+    logs
+}
+
+/// Look up `key` in a JSON-ish `"key": value` list, as printed by some programmers
+fn json_ish_value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let pat = format!("\"{key}\"");
+    let start = line.find(&pat)? + pat.len();
+    let rest = line[start..].trim_start().strip_prefix(':')?.trim_start();
+
+    let val = match rest.strip_prefix('"') {
+        Some(quoted) => &quoted[..quoted.find('"')?],
+        None => rest[..rest.find([',', '}']).unwrap_or(rest.len())].trim(),
+    };
+
+    Some(val)
+}
+
+/// Look up `key` in a plain `INFO: key: value` log line, as printed by older programmers
+fn plain_log_value<'a>(logs: &'a [String], key: &str) -> Option<&'a str> {
+    logs.iter().find_map(|l| {
+        l.trim_start_matches("INFO:")
+            .trim()
+            .strip_prefix(key)
+            .and_then(|v| v.trim_start().strip_prefix(':'))
+            .map(|v| v.trim())
+    })
+}
+
+fn parse_int<T: TryFrom<u64>>(val: &str) -> Option<T> {
+    let val = val.trim();
+    let num = match val.strip_prefix("0x").or(val.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => val.parse::<u64>().ok()?,
+    };
+
+    T::try_from(num).ok()
+}
+
+/// Parse the \<log\> messages following a \<getstorageinfo\> request
+pub fn firehose_parser_storage_info(logs: &[String]) -> Result<StorageInfo, anyhow::Error> {
+    if let Some(line) = logs.iter().find(|l| l.contains("\"storage_info\"")) {
+        let get = |key: &str| json_ish_value(line, key);
+
+        let (Some(total_blocks), Some(block_size)) = (
+            get("total_blocks").and_then(parse_int),
+            get("block_size").and_then(parse_int),
+        ) else {
+            bail!("Got malformed storage info: {}", line);
+        };
+
+        return Ok(StorageInfo {
+            total_blocks,
+            block_size,
+            page_size: get("page_size").and_then(parse_int),
+            num_physical: get("num_physical").and_then(parse_int),
+            manufacturer_id: get("manufacturer_id").and_then(parse_int),
+            serial_num: get("serial_num").and_then(parse_int),
+            fw_version: get("fw_version").map(|s| s.to_owned()),
+            mem_type: get("mem_type").map(|s| s.to_owned()),
+            prod_name: get("prod_name").map(|s| s.to_owned()),
+        });
+    }
+
+    let get = |key: &str| plain_log_value(logs, key);
+    let (Some(total_blocks), Some(block_size)) = (
+        get("Device Total Logical Blocks").and_then(parse_int),
+        get("Device Block Size in Bytes").and_then(parse_int),
+    ) else {
+        bail!("The device didn't report its storage info");
+    };
+
+    Ok(StorageInfo {
+        total_blocks,
+        block_size,
+        num_physical: get("Device Total Physical Partitions").and_then(parse_int),
+        manufacturer_id: get("Device Manufacturer ID").and_then(parse_int),
+        serial_num: get("Device Serial Number").and_then(parse_int),
+        fw_version: get("Device FW Version").map(|s| s.to_owned()),
+        prod_name: get("Device Product Name").map(|s| s.to_owned()),
+        ..Default::default()
+    })
+}
+
+/// Extract the list of functions the loader advertises within its "welcome" \<log\> messages
+pub fn firehose_parser_supported_functions(logs: &[String]) -> Vec<String> {
+    logs.iter()
+        .map(|l| l.trim_start_matches("INFO:").trim())
+        .skip_while(|l| !l.starts_with("Supported Functions"))
+        .skip(1)
+        .take_while(|l| !l.starts_with("End of supported functions"))
+        .map(|l| l.to_owned())
+        .collect()
+}
+
+/// Parse the \<log\> messages following a \<getsha256digest\> request
+pub fn firehose_parser_sha256_digest(logs: &[String]) -> Result<[u8; 32], anyhow::Error> {
+    for line in logs.iter() {
+        for word in line.split(|c: char| !c.is_ascii_alphanumeric()) {
+            let word = word.strip_prefix("0x").unwrap_or(word);
+            if word.len() != 64 || !word.chars().all(|c| c.is_ascii_hexdigit()) {
+                continue;
+            }
+
+            let mut digest = [0u8; 32];
+            for (i, b) in digest.iter_mut().enumerate() {
+                *b = u8::from_str_radix(&word[2 * i..2 * i + 2], 16)?;
+            }
+
+            return Ok(digest);
+        }
+    }
+
+    bail!("The device didn't report a SHA256 digest")
+}
+
+/// Find the CRC32 digest in the logs printed in response to \<getcrc32digest\>
+pub fn firehose_parser_crc32_digest(logs: &[String]) -> Result<u32, anyhow::Error> {
+    for line in logs.iter().filter(|l| l.to_lowercase().contains("crc")) {
+        for word in line.split(|c: char| !c.is_ascii_alphanumeric()) {
+            let Some(word) = word.strip_prefix("0x") else {
+                continue;
+            };
+            if word.is_empty() || word.len() > 8 || !word.chars().all(|c| c.is_ascii_hexdigit()) {
+                continue;
+            }
+
+            return Ok(u32::from_str_radix(word, 16)?);
+        }
+    }
+
+    bail!("The device didn't report a CRC32 digest")
+}
+
+/// Numeric attribute of the \<configure\> response. Programmers vary wildly in what they
+/// report, so a missing (or garbled) value is assumed to be `default`, with a warning.
+fn configure_attr<T: QdlChan, N: FromStr + Display>(
+    channel: &T,
+    attrs: &IndexMap<String, String>,
+    name: &str,
+    default: N,
+) -> N {
+    let problem = match attrs.get(name).map(|v| v.parse::<N>()) {
+        Some(Ok(n)) => return n,
+        Some(Err(_)) => "an invalid",
+        None => "no",
+    };
+
+    if !channel.fh_config().quiet {
+        println!(
+            "{}",
+            format!("The programmer reported {problem} {name}, assuming {default}").bright_yellow()
+        );
+    }
+
+    default
+}
+
 /// Parse the \<configure\> response
 pub fn firehose_parser_configure_response<T: QdlChan>(
     channel: &mut T,
@@ -36,35 +242,78 @@ pub fn firehose_parser_configure_response<T: QdlChan>(
     if let Ok(status) = firehose_parser_ack_nak(channel, attrs) {
         // The device can't handle that big of a buffer and it auto-reconfigures to the max it can
         if status == FirehoseStatus::Nak {
-            if let Some(val) = attrs.get("MaxPayloadSizeToTargetInBytes").to_owned() {
-                channel.mut_fh_config().send_buffer_size = val.parse::<usize>().unwrap();
+            if let Some(val) = attrs
+                .get("MaxPayloadSizeToTargetInBytes")
+                .and_then(|v| v.parse::<usize>().ok())
+            {
+                let max_payload_size = channel.fh_config().max_payload_size.unwrap_or(usize::MAX);
+                channel.mut_fh_config().send_buffer_size = val.min(max_payload_size);
             } else {
-                firehose_reset(channel, &FirehoseResetMode::ResetToEdl, 0)?;
+                let delay = channel.fh_config().reset_delay;
+                firehose_reset(channel, &FirehoseResetMode::ResetToEdl, delay)?;
                 bail!("firehose <configure> failed, try again with  --verbose-firehose")
             }
         }
     }
 
+    // Whatever was asked for is what an ACK stands for, unless stated otherwise
+    let requested_payload_size = channel.fh_config().send_buffer_size;
+    let payload_size = configure_attr(
+        channel,
+        attrs,
+        "MaxPayloadSizeToTargetInBytes",
+        requested_payload_size,
+    );
+    // Never go above the user-requested cap, even if the device could handle it
+    let max_payload_size = channel.fh_config().max_payload_size.unwrap_or(usize::MAX);
     let device_max_write_payload_size = attrs
         .get("MaxPayloadSizeToTargetInBytesSupported")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-
-    // TODO: define version of the spec we support and validate it
-    let version = attrs.get("Version").unwrap();
-    let min_version_supported = attrs
-        .get("MinVersionSupported")
-        .unwrap()
-        .parse::<u32>()
-        .unwrap();
-
-    println!("Found protocol version {}", version.bright_blue());
-
-    if min_version_supported < FH_PROTO_VERSION_SUPPORTED {
-        bail!(
-            "Device requires protocol version >= {}, the library only supports up to v{}",
-            min_version_supported.bright_red(),
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(payload_size)
+        .min(max_payload_size);
+
+    let version = attrs.get("Version").map_or("unknown", |v| v.as_str());
+    let min_version_supported = configure_attr(channel, attrs, "MinVersionSupported", 1u32);
+
+    if !channel.fh_config().quiet {
+        println!("Found protocol version {}", version.bright_blue());
+    }
+    channel.mut_fh_config().protocol_version = version.parse().ok();
+
+    // Not every programmer reports these, and some leave them empty
+    let non_empty = |name| attrs.get(name).filter(|v| !v.is_empty()).cloned();
+    channel.mut_fh_config().target_name = non_empty("TargetName");
+    channel.mut_fh_config().memory_name = non_empty("MemoryName");
+    let cfg = channel.fh_config();
+    if !cfg.quiet && (cfg.target_name.is_some() || cfg.memory_name.is_some()) {
+        println!(
+            "Programmer reports SoC {} with {} storage",
+            cfg.target_name
+                .as_deref()
+                .unwrap_or("unknown")
+                .bright_blue(),
+            cfg.memory_name
+                .as_deref()
+                .unwrap_or("unknown")
+                .bright_blue()
+        );
+    }
+
+    if min_version_supported > FH_PROTO_VERSION_SUPPORTED {
+        return Err(FirehoseError::ProtocolVersionIncompatibility {
+            device_min: min_version_supported,
+            host_max: FH_PROTO_VERSION_SUPPORTED,
+        }
+        .into());
+    }
+    if !channel.fh_config().quiet
+        && version
+            .parse::<u32>()
+            .is_ok_and(|v| v > FH_PROTO_VERSION_SUPPORTED)
+    {
+        println!(
+            "Device supports protocol version {}, negotiating down to v{}",
+            version,
             FH_PROTO_VERSION_SUPPORTED.bright_blue()
         );
     }
@@ -72,23 +321,18 @@ pub fn firehose_parser_configure_response<T: QdlChan>(
     // TODO: MaxPayloadSizeFromTargetInBytes seems useless when xfers are abstracted through libusb
     // TODO: ^ is usually 1kiB (reaaally small), newer (citation needed) devices don't advertise it
 
-    channel.mut_fh_config().xml_buf_size = attrs
-        .get("MaxXMLSizeInBytes")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-    channel.mut_fh_config().send_buffer_size = attrs
-        .get("MaxPayloadSizeToTargetInBytes")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
+    channel.mut_fh_config().xml_buf_size =
+        configure_attr(channel, attrs, "MaxXMLSizeInBytes", FH_DEFAULT_XML_SIZE);
+    channel.mut_fh_config().send_buffer_size = payload_size.min(max_payload_size);
 
     // If the device can take a larger buffer, reconfigure it.
     if channel.fh_config().send_buffer_size < device_max_write_payload_size {
-        println!(
-            "Reconfiguring the device to use a larger ({}kB) send buffer",
-            device_max_write_payload_size / 1024
-        );
+        if !channel.fh_config().quiet {
+            println!(
+                "Reconfiguring the device to use a larger ({}kB) send buffer",
+                device_max_write_payload_size / 1024
+            );
+        }
 
         channel.mut_fh_config().send_buffer_size = device_max_write_payload_size;
         firehose_configure(channel, true)?;
@@ -97,3 +341,45 @@ pub fn firehose_parser_configure_response<T: QdlChan>(
 
     Ok(FirehoseStatus::Ack)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::QdlMockDevice;
+
+    fn configure_response(
+        version: &str,
+        min_version: &str,
+    ) -> Result<FirehoseStatus, anyhow::Error> {
+        let mut mock = QdlMockDevice::default();
+        mock.push_xml(
+            "response",
+            &[
+                ("value", "ACK"),
+                ("Version", version),
+                ("MinVersionSupported", min_version),
+                ("MaxPayloadSizeToTargetInBytes", "1048576"),
+            ],
+        );
+        let mut dev = mock.into_device();
+
+        firehose_read(&mut dev, firehose_parser_configure_response)
+    }
+
+    #[test]
+    fn configure_device_too_new() {
+        let err = configure_response("3", "2").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FirehoseError>(),
+            Some(FirehoseError::ProtocolVersionIncompatibility {
+                device_min: 2,
+                host_max: FH_PROTO_VERSION_SUPPORTED,
+            })
+        ));
+    }
+
+    #[test]
+    fn configure_negotiates_down() {
+        assert_eq!(configure_response("2", "1").unwrap(), FirehoseStatus::Ack);
+    }
+}
@@ -6,9 +6,11 @@ use pbr::{ProgressBar, Units};
 use std::{
     cmp::min,
     ffi::CStr,
+    fmt::Display,
     fs::File,
     io::{Read, Write},
     mem::{self, size_of_val},
+    path::PathBuf,
 };
 
 use anyhow::{Result, anyhow, bail};
@@ -17,10 +19,32 @@ use bincode::serialize;
 use serde::{self, Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
+use crate::parsers::firehose_parser_log_lines;
 use crate::types::{QdlBackend, QdlChan};
 
 const SAHARA_STATUS_SUCCESS: u32 = 0;
 
+/// Sahara failures that the caller may want to recover from
+#[derive(Debug)]
+pub enum SaharaError {
+    /// The Device sent Firehose XML (with the contained log messages) while a Sahara
+    /// packet was expected, usually because a previous run left it in Firehose mode
+    UnexpectedFirehoseData(Vec<String>),
+}
+
+impl Display for SaharaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaharaError::UnexpectedFirehoseData(_) => write!(
+                f,
+                "Got Firehose data while expecting a Sahara packet, the Device is already running the loader"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SaharaError {}
+
 #[derive(Copy, Clone, Debug, PartialEq, Deserialize_repr, Serialize_repr)]
 #[repr(u32)]
 pub enum SaharaMode {
@@ -29,13 +53,43 @@ pub enum SaharaMode {
     Command = 0x3,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize_repr, Serialize_repr)]
+/// Client commands, executed in [SaharaMode::Command]. Not every Device implements all of
+/// them, [SaharaCmdModeCmd::GetCommandIdList] reports which ones are.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize_repr, Serialize_repr)]
 #[repr(u32)]
 pub enum SaharaCmdModeCmd {
     Nop = 0x0,
     ReadSerialNum = 0x1,
+    /// MSM HW ID, i.e. the JTAG ID along with the OEM and model IDs
     ReadHwId = 0x2,
     ReadOemKeyHash = 0x3,
+    SwitchToDmssDload = 0x4,
+    SwitchToStreamDload = 0x5,
+    /// Log buffer of the boot ROM/PBL
+    ReadDebugData = 0x6,
+    GetSblSwVersion = 0x7,
+    GetCommandIdList = 0x8,
+    GetTrainingData = 0x9,
+}
+
+impl SaharaCmdModeCmd {
+    /// Look a command up by its ID, as reported through [SaharaCmdModeCmd::GetCommandIdList]
+    pub fn from_id(id: u32) -> Option<Self> {
+        [
+            Self::Nop,
+            Self::ReadSerialNum,
+            Self::ReadHwId,
+            Self::ReadOemKeyHash,
+            Self::SwitchToDmssDload,
+            Self::SwitchToStreamDload,
+            Self::ReadDebugData,
+            Self::GetSblSwVersion,
+            Self::GetCommandIdList,
+            Self::GetTrainingData,
+        ]
+        .into_iter()
+        .find(|c| *c as u32 == id)
+    }
 }
 
 // (De)serialize_repr works on C-like enums (match by value instead of entry index)
@@ -217,6 +271,65 @@ pub struct RamdumpTable64 {
     filename: [u8; 20],
 }
 
+impl RamdumpTable64 {
+    /// File name proposed by the Device, or one derived from the base address if there's none
+    pub fn file_name(&self) -> String {
+        match CStr::from_bytes_until_nul(&self.filename)
+            .ok()
+            .and_then(|s| s.to_str().ok())
+        {
+            Some(s) if !s.is_empty() => s.to_owned(),
+            _ => format!("{:x}.bin", self.base),
+        }
+    }
+}
+
+/// Print the regions offered by the Device in [SaharaMode::MemoryDebug]
+pub fn sahara_print_ramdump_tbl(dump_tbl: &[RamdumpTable64]) {
+    println!(
+        "{:<20} {:<18} {:<18} {:<4} DESCRIPTION",
+        "NAME", "BASE", "SIZE", "SAVE"
+    );
+    for entry in dump_tbl {
+        println!(
+            "{:<20} {:<18} {:<18} {:<4} {}",
+            entry.file_name(),
+            format!("0x{:x}", entry.base),
+            format!("0x{:x}", entry.len),
+            match entry.save_pref {
+                0 => "no",
+                _ => "yes",
+            },
+            CStr::from_bytes_until_nul(&entry.description)
+                .ok()
+                .and_then(|s| s.to_str().ok())
+                .unwrap_or("")
+        );
+    }
+}
+
+/// Knobs for dumping memory in [SaharaMode::MemoryDebug]
+#[derive(Clone, Debug)]
+pub struct RamdumpOptions {
+    /// Regions to dump (case-insensitive, file extensions ignored). If empty, all
+    /// regions the Device suggests saving are dumped
+    pub regions: Vec<String>,
+    /// Where to store the dumped regions, one file each
+    pub output_dir: PathBuf,
+    /// Only print the table of available regions, without dumping any
+    pub list_only: bool,
+}
+
+impl Default for RamdumpOptions {
+    fn default() -> Self {
+        Self {
+            regions: Vec::new(),
+            output_dir: PathBuf::from("ramdump"),
+            list_only: false,
+        }
+    }
+}
+
 pub fn sahara_send_img_to_device<T: Read + Write>(
     channel: &mut T,
     img_arr: &mut [Option<Vec<u8>>],
@@ -228,17 +341,22 @@ pub fn sahara_send_img_to_device<T: Read + Write>(
     let Some(Some(buf)) = img_arr.get(image as usize) else {
         bail!("Sahara requested missing image ID {}", image_idx,);
     };
-    if (image_offset + image_len) as usize > buf.len() {
+    let Some(image_end) = image_offset
+        .checked_add(image_len)
+        .filter(|&end| end <= buf.len() as u64)
+    else {
         bail!(
-            "Attempted OOB read {} > {}",
-            image_offset + image_len,
+            "Attempted OOB read {}+{} > {}",
+            image_offset,
+            image_len,
             buf.len()
         );
-    }
+    };
 
-    channel
-        .write(&buf[image_offset as usize..(image_offset + image_len) as usize])
-        .map_err(|e| e.into())
+    // Requests can be large, especially 64-bit ones, make sure all of it goes out
+    channel.write_all(&buf[image_offset as usize..image_end as usize])?;
+
+    Ok(image_len as usize)
 }
 
 fn sahara_send_generic<T: Read + Write>(
@@ -433,15 +551,16 @@ fn sahara_dump_region<T: QdlChan>(
 pub fn sahara_dump_regions<T: QdlChan>(
     channel: &mut T,
     dump_tbl: Vec<RamdumpTable64>,
-    regions_to_dump: Vec<String>,
+    opts: &RamdumpOptions,
 ) -> Result<()> {
     // Make all of them lowercase for better UX
-    let regions_to_dump = regions_to_dump
+    let regions_to_dump = opts
+        .regions
         .iter()
         .map(|rname| rname.to_ascii_lowercase())
         .collect::<Vec<String>>();
 
-    std::fs::create_dir_all("ramdump/")?;
+    std::fs::create_dir_all(&opts.output_dir)?;
     let filtered_list: Vec<RamdumpTable64> = match regions_to_dump.len() {
         // Dump everything with save_pref == true if no argument was provided
         0 => dump_tbl
@@ -466,31 +585,85 @@ pub fn sahara_dump_regions<T: QdlChan>(
             .collect(),
     };
     for entry in filtered_list {
-        let fname = CStr::from_bytes_until_nul(&entry.filename)
-            .unwrap()
-            .to_str()?
-            .to_owned();
+        let path = opts.output_dir.join(entry.file_name());
 
-        let mut f = File::create(std::path::Path::new(&format!("ramdump/{fname}")))?;
+        let mut f = File::create(&path)?;
         sahara_dump_region(channel, entry, &mut f)?;
+        println!(
+            "\nDumped {} (0x{:x} bytes @ 0x{:x}) to {}",
+            entry.file_name(),
+            entry.len,
+            entry.base,
+            path.display()
+        );
     }
 
     Ok(())
 }
 
+/// Map the image ID requested by the Device onto a slot of `images`
+///
+/// See [crate::load_programmer_images] for the layout of `images`. `order` keeps track of
+/// the image IDs requested so far.
+fn sahara_image_slot(images: &[Option<Vec<u8>>], order: &mut Vec<u64>, image_id: u64) -> u64 {
+    if images.len() == 1 || !matches!(images.first(), Some(Some(_))) {
+        return image_id;
+    }
+
+    match order.iter().position(|id| *id == image_id) {
+        Some(slot) => slot as u64,
+        None => {
+            order.push(image_id);
+            (order.len() - 1) as u64
+        }
+    }
+}
+
+/// Answer a (32- or 64-bit, widened to the latter) image read request. Devices pick the
+/// variant on their own, some newer ones go with 64-bit requests while advertising Sahara v2.
+/// `variant` is only used for logging, which happens once per image.
+fn sahara_serve_read<T: Read + Write>(
+    channel: &mut T,
+    images: &mut [Option<Vec<u8>>],
+    image_order: &mut Vec<u64>,
+    req: ReadData64Req,
+    variant: Option<&str>,
+) -> Result<usize> {
+    let known_ids = image_order.len();
+    let slot = sahara_image_slot(images, image_order, req.image);
+    if image_order.len() > known_ids {
+        if slot as usize >= images.len() {
+            bail!(
+                "Device requested image ID {}, but only {} loader images were provided",
+                req.image,
+                images.len()
+            );
+        }
+        if let Some(variant) = variant {
+            println!(
+                "Sahara: Sending loader image #{} as image ID {} ({} requests)",
+                slot, req.image, variant
+            );
+        }
+    }
+
+    sahara_send_img_to_device(channel, images, slot, req.offset, req.len)
+}
+
 pub fn sahara_run<T: QdlChan>(
     channel: &mut T,
     sahara_mode: SaharaMode,
     sahara_command: Option<SaharaCmdModeCmd>,
     images: &mut [Option<Vec<u8>>],
-    filenames: Vec<String>,
+    ramdump: &RamdumpOptions,
     verbose: bool,
 ) -> Result<Vec<u8>> {
     let mut buf = vec![0; 4096];
+    let mut image_order = Vec::new();
 
     loop {
         let bytes_read = channel.read(&mut buf[..])?;
-        let pkt = sahara_parse_packet(&buf[..bytes_read], verbose)?;
+        let pkt = sahara_parse_packet(&buf[..bytes_read], verbose, channel.fh_config().quiet)?;
         let pktsize = size_of_val(&pkt.cmd) + size_of_val(&pkt.len);
 
         match pkt.cmd {
@@ -506,18 +679,40 @@ pub fn sahara_run<T: QdlChan>(
                     } else {
                         sahara_mode
                     };
+
+                    if verbose {
+                        println!(
+                            "Sahara: Device speaks protocol v{} (compatible down to v{}) in {:?} mode, requesting {:?} mode",
+                            req.ver, req.compatible, req.mode, mode
+                        );
+                    }
+                    if req.compatible > SAHARA_VERSION {
+                        println!(
+                            "{} v{} {} v{}{}",
+                            "Warning: Device requires Sahara".bright_yellow(),
+                            req.compatible,
+                            "but only".bright_yellow(),
+                            SAHARA_VERSION,
+                            " is supported, proceeding anyway".bright_yellow()
+                        );
+                    }
+
                     sahara_send_hello_rsp(channel, mode)?;
                 }
             }
             SaharaCmd::SaharaReadData => {
                 if let SaharaPacketBody::ReadReq(rr) = pkt.body {
                     assert_eq!(pkt.len as usize, pktsize + mem::size_of::<ReadReq>());
-                    sahara_send_img_to_device(
+                    sahara_serve_read(
                         channel,
                         images,
-                        rr.image as u64,
-                        rr.offset as u64,
-                        rr.len as u64,
+                        &mut image_order,
+                        ReadData64Req {
+                            image: rr.image as u64,
+                            offset: rr.offset as u64,
+                            len: rr.len as u64,
+                        },
+                        verbose.then_some("32-bit"),
                     )?;
                 }
             }
@@ -537,7 +732,12 @@ pub fn sahara_run<T: QdlChan>(
                     && (req.status == 1 /* COMPLETE */ /* 8916 bug */ ||
                      images.len() == 1)
                 {
-                    println!("{}", "Loader sent. Hack away!".green());
+                    if verbose {
+                        println!("Sahara: {:?} mode done", SaharaMode::WaitingForImage);
+                    }
+                    if !channel.fh_config().quiet {
+                        println!("{}", "Loader sent. Hack away!".green());
+                    }
                     return Ok(vec![]);
                 }
             }
@@ -559,6 +759,13 @@ pub fn sahara_run<T: QdlChan>(
                     assert_eq!(resp_len, resp.len as usize);
 
                     // Got everything we want, exit command mode
+                    if verbose {
+                        println!(
+                            "Sahara: {:?} mode done, switching to {:?} mode",
+                            SaharaMode::Command,
+                            SaharaMode::WaitingForImage
+                        );
+                    }
                     sahara_switch_mode(channel, SaharaMode::WaitingForImage)?;
 
                     return Ok(resp_buf);
@@ -572,31 +779,39 @@ pub fn sahara_run<T: QdlChan>(
                     let dump_tbl = sahara_get_ramdump_tbl(channel, req.addr, req.len, verbose)?;
 
                     // Grab some (possibly all) of the available regions
-                    sahara_dump_regions(channel, dump_tbl, filenames)?;
+                    if ramdump.list_only {
+                        sahara_print_ramdump_tbl(&dump_tbl);
+                    } else {
+                        sahara_dump_regions(channel, dump_tbl, ramdump)?;
+                    }
 
+                    if verbose {
+                        println!("Sahara: {:?} mode done", SaharaMode::MemoryDebug);
+                    }
                     return Ok(vec![]);
                 }
             }
             SaharaCmd::SaharaReadData64 => {
                 if let SaharaPacketBody::ReadData64Req(rr) = pkt.body {
                     assert_eq!(pkt.len as usize, pktsize + mem::size_of::<ReadData64Req>());
-                    sahara_send_img_to_device(channel, images, rr.image, rr.offset, rr.len)?;
+                    sahara_serve_read(
+                        channel,
+                        images,
+                        &mut image_order,
+                        rr,
+                        verbose.then_some("64-bit"),
+                    )?;
                 }
             }
             SaharaCmd::SaharaResetResp => {
                 assert_eq!(pkt.len as usize, pktsize);
             }
-            SaharaCmd::SaharaXML => {
-                // Todo: make this optionally "fine"
-                println!("Device booted into the loader already");
-                return Ok(vec![]);
-            }
-            _ => todo!("Got packet {:?}", pkt),
+            _ => bail!("Got unexpected packet {:?}", pkt),
         }
     }
 }
 
-fn sahara_parse_packet(buf: &[u8], verbose: bool) -> Result<SaharaPacket> {
+fn sahara_parse_packet(buf: &[u8], verbose: bool, quiet: bool) -> Result<SaharaPacket> {
     let (cmd, rest) = buf
         .split_first_chunk::<4>()
         .ok_or_else(|| anyhow!("Malformed packet, too short: {buf:?}"))?;
@@ -604,8 +819,19 @@ fn sahara_parse_packet(buf: &[u8], verbose: bool) -> Result<SaharaPacket> {
         .split_first_chunk::<4>()
         .ok_or_else(|| anyhow!("Malformed packet, too short: {buf:?}"))?;
 
-    let cmd = bincode::deserialize::<SaharaCmd>(cmd)
-        .unwrap_or_else(|_| panic!("Got unknown command {}", u32::from_le_bytes(*cmd)));
+    let Ok(cmd) = bincode::deserialize::<SaharaCmd>(cmd) else {
+        bail!("Got unknown command {}", u32::from_le_bytes(*cmd));
+    };
+
+    // A Device left in Firehose mode will keep talking XML, surface its logs
+    if cmd == SaharaCmd::SaharaXML {
+        let logs = firehose_parser_log_lines(buf);
+        for log in logs.iter().filter(|_| !quiet) {
+            println!("LOG: {}", log.bright_black());
+        }
+
+        return Err(SaharaError::UnexpectedFirehoseData(logs).into());
+    }
 
     let ret = SaharaPacket {
         cmd,
@@ -644,10 +870,6 @@ fn sahara_parse_packet(buf: &[u8], verbose: bool) -> Result<SaharaPacket> {
             SaharaCmd::SaharaReadData64 => SaharaPacketBody::ReadData64Req(
                 bincode::deserialize::<ReadData64Req>(args).unwrap(),
             ),
-            SaharaCmd::SaharaXML => bail!(
-                "Got Firehose command while expecting Sahara command: {:?}",
-                String::from_utf8_lossy(buf)
-            ),
             _ => bail!("Got unimplemented command: {:?}", buf),
         },
     };
@@ -658,3 +880,85 @@ fn sahara_parse_packet(buf: &[u8], verbose: bool) -> Result<SaharaPacket> {
 
     Ok(ret)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::QdlMockDevice;
+
+    fn packet(cmd: SaharaCmd, body: SaharaPacketBody, body_len: usize) -> Vec<u8> {
+        serialize(&SaharaPacket {
+            cmd,
+            len: (2 * size_of::<u32>() + body_len) as u32,
+            body,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn serve_read_both_layouts() {
+        let images = [(0..64).collect::<Vec<u8>>(), (64..128).collect()];
+        let mut mock = QdlMockDevice::default();
+        mock.push_raw(&packet(
+            SaharaCmd::SaharaReadData,
+            SaharaPacketBody::ReadReq(ReadReq {
+                image: 13,
+                offset: 8,
+                len: 16,
+            }),
+            size_of::<ReadReq>(),
+        ))
+        .push_raw(&packet(
+            SaharaCmd::SaharaReadData64,
+            SaharaPacketBody::ReadData64Req(ReadData64Req {
+                image: 13,
+                offset: 8,
+                len: 16,
+            }),
+            size_of::<ReadData64Req>(),
+        ))
+        // A newly requested image ID maps onto the next loader image
+        .push_raw(&packet(
+            SaharaCmd::SaharaReadData64,
+            SaharaPacketBody::ReadData64Req(ReadData64Req {
+                image: 7,
+                offset: 0,
+                len: 4,
+            }),
+            size_of::<ReadData64Req>(),
+        ))
+        .push_raw(&packet(
+            SaharaCmd::SaharaEndOfImage,
+            SaharaPacketBody::Eoi(Eoi {
+                image: 7,
+                status: 0,
+            }),
+            size_of::<Eoi>(),
+        ))
+        .push_raw(&packet(
+            SaharaCmd::SaharaDoneResp,
+            SaharaPacketBody::DoneResp(DoneResp { status: 1 }),
+            size_of::<DoneResp>(),
+        ));
+        let mut dev = mock.into_device();
+
+        let mut loader = images.iter().cloned().map(Some).collect::<Vec<_>>();
+        sahara_run(
+            &mut dev,
+            SaharaMode::WaitingForImage,
+            None,
+            &mut loader,
+            &RamdumpOptions::default(),
+            false,
+        )
+        .unwrap();
+        assert!(dev.rw.is_drained());
+
+        let written = &dev.rw.written;
+        assert_eq!(written[0], &images[0][8..24]);
+        assert_eq!(written[1], written[0]);
+        assert_eq!(written[2], &images[1][..4]);
+        // Followed by SaharaDone
+        assert_eq!(written.len(), 4);
+    }
+}
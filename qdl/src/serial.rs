@@ -1,8 +1,12 @@
 // SPDX-License-Identifier: BSD-3-Clause
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
 use serial2::{self, SerialPort};
-use std::io::{BufRead, Read, Write};
+use std::{
+    io::{BufRead, Read, Write},
+    path::Path,
+    time::Duration,
+};
 
 use crate::types::QdlReadWrite;
 
@@ -13,7 +17,6 @@ pub struct QdlSerialConfig {
     cap: usize,
 }
 
-// TODO: timeouts?
 impl Write for QdlSerialConfig {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
         self.serport.write(buf)
@@ -62,16 +65,39 @@ impl BufRead for QdlSerialConfig {
 
 impl QdlReadWrite for QdlSerialConfig {}
 
-pub fn setup_serial_device(dev_path: Option<String>) -> Result<QdlSerialConfig> {
+const SERIAL_DEFAULT_BAUD_RATE: u32 = 115200;
+
+pub fn setup_serial_device(
+    dev_path: Option<String>,
+    timeout: Option<Duration>,
+    baud_rate: Option<u32>,
+) -> Result<QdlSerialConfig> {
     if dev_path.is_none() {
         bail!("Serial port path unspecified");
     }
 
-    let serport = SerialPort::open(dev_path.unwrap(), |mut settings: serial2::Settings| {
+    let baud_rate = baud_rate.unwrap_or(SERIAL_DEFAULT_BAUD_RATE);
+    let mut serport = SerialPort::open(dev_path.unwrap(), |mut settings: serial2::Settings| {
         settings.set_raw();
-        settings.set_baud_rate(115200)?;
+        settings.set_baud_rate(baud_rate)?;
         Ok(settings)
-    })?;
+    })
+    .with_context(|| format!("Couldn't open the serial port at {baud_rate} baud"))?;
+
+    // Some platforms silently round unsupported rates to the closest one they can do
+    let actual_rate = serport.get_configuration()?.get_baud_rate()?;
+    if actual_rate != baud_rate {
+        bail!(
+            "Baud rate {} is not supported by the serial port (closest match: {})",
+            baud_rate,
+            actual_rate
+        );
+    }
+
+    if let Some(t) = timeout {
+        serport.set_read_timeout(t)?;
+        serport.set_write_timeout(t)?;
+    }
 
     Ok(QdlSerialConfig {
         serport,
@@ -80,3 +106,112 @@ pub fn setup_serial_device(dev_path: Option<String>) -> Result<QdlSerialConfig>
         cap: 0,
     })
 }
+
+/// Find a serial port exposed by a Qualcomm device (e.g. the QDLoader 9008 CDC interface)
+pub fn find_edl_serial_port() -> Option<String> {
+    SerialPort::available_ports()
+        .ok()?
+        .into_iter()
+        .find(|p| is_qcom_serial_port(p))
+        .map(|p| p.to_string_lossy().into_owned())
+}
+
+#[cfg(target_os = "linux")]
+fn is_qcom_serial_port(path: &Path) -> bool {
+    let Some(name) = path.file_name() else {
+        return false;
+    };
+
+    // For USB serial adapters, device/ is the interface and its parent is the USB device
+    std::fs::read_to_string(
+        Path::new("/sys/class/tty")
+            .join(name)
+            .join("device/../idVendor"),
+    )
+    .is_ok_and(|vid| vid.trim() == "05c6")
+}
+
+// TODO: look up the VID of the port on other platforms
+#[cfg(not(target_os = "linux"))]
+fn is_qcom_serial_port(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::firehose_read_logs;
+    use crate::parsers::firehose_parser_ack_nak;
+    use crate::types::{FirehoseStatus, QdlDevice};
+    use std::thread;
+
+    /// The Device end of a pseudo-terminal pair, along with the Host end as the backend
+    fn serial_pair() -> (SerialPort, QdlSerialConfig) {
+        let (mut device, mut host) = SerialPort::pair().unwrap();
+        for port in [&mut device, &mut host] {
+            let mut settings = port.get_configuration().unwrap();
+            settings.set_raw();
+            port.set_configuration(&settings).unwrap();
+        }
+        host.set_read_timeout(Duration::from_secs(5)).unwrap();
+
+        (
+            device,
+            QdlSerialConfig {
+                serport: host,
+                buf: Vec::new(),
+                pos: 0,
+                cap: 0,
+            },
+        )
+    }
+
+    #[test]
+    fn buffered_reads() {
+        let (device, mut host) = serial_pair();
+
+        device.write_all(b"0123456789").unwrap();
+        assert_eq!(host.fill_buf().unwrap(), b"0123456789");
+        host.consume(4);
+        // Served from the buffer, without touching the port
+        assert_eq!(host.fill_buf().unwrap(), b"456789");
+        let mut out = [0; 3];
+        assert_eq!(host.read(&mut out).unwrap(), 3);
+        assert_eq!(&out, b"456");
+        host.consume(100);
+
+        device.write_all(b"ab").unwrap();
+        assert_eq!(host.fill_buf().unwrap(), b"ab");
+    }
+
+    #[test]
+    fn read_fragmented_packet() {
+        let (device, host) = serial_pair();
+        let log =
+            r#"<?xml version="1.0" encoding="UTF-8" ?><data><log value="INFO: hello" /></data>"#;
+        let ack = r#"<?xml version="1.0" encoding="UTF-8" ?><data><response value="ACK" rawmode="false" /></data>"#;
+        let stream = format!("{log}{ack}");
+        let writer = thread::spawn(move || {
+            for chunk in stream.as_bytes().chunks(7) {
+                device.write_all(chunk).unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+            device
+        });
+
+        let mut dev = QdlDevice {
+            rw: Box::new(host),
+            fh_cfg: Default::default(),
+            fh_log: None,
+            progress: None,
+            bypassed: Default::default(),
+            interrupt: None,
+            activity: None,
+            reset_on_drop: false,
+        };
+        let (status, logs) = firehose_read_logs(&mut dev, firehose_parser_ack_nak).unwrap();
+        assert_eq!(status, FirehoseStatus::Ack);
+        assert_eq!(logs, ["INFO: hello"]);
+        writer.join().unwrap();
+    }
+}
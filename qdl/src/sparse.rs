@@ -0,0 +1,288 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use std::{
+    cmp::min,
+    io::{Read, Seek, SeekFrom},
+};
+
+pub const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[repr(C)]
+struct SparseHeader {
+    magic: u32,
+    major_version: u16,
+    minor_version: u16,
+    file_hdr_sz: u16,
+    chunk_hdr_sz: u16,
+    blk_sz: u32,
+    total_blks: u32,
+    total_chunks: u32,
+    image_checksum: u32,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[repr(C)]
+struct ChunkHeader {
+    chunk_type: u16,
+    reserved1: u16,
+    chunk_sz: u32,
+    total_sz: u32,
+}
+
+/// Where the data of a [SparseChunk] comes from
+#[derive(Copy, Clone, Debug)]
+pub enum SparseChunkData {
+    /// Verbatim data, located at the given offset of the sparse file
+    Raw(u64),
+    /// A 4-byte pattern repeated over the entire chunk
+    Fill([u8; 4]),
+}
+
+/// A region of the expanded image that carries data (i.e. is not "don't care")
+#[derive(Copy, Clone, Debug)]
+pub struct SparseChunk {
+    /// Offset into the expanded image, in bytes
+    pub offset: u64,
+    /// Length, in bytes
+    pub len: u64,
+    pub data: SparseChunkData,
+}
+
+/// Parsed Android sparse image
+pub struct SparseImage {
+    /// Size of the expanded image, in bytes
+    pub expanded_len: u64,
+    pub chunks: Vec<SparseChunk>,
+}
+
+impl SparseImage {
+    /// Group the chunks into runs that are contiguous within the expanded image
+    pub fn runs(&self) -> Vec<&[SparseChunk]> {
+        self.chunks
+            .chunk_by(|a, b| a.offset + a.len == b.offset)
+            .collect()
+    }
+}
+
+/// Check whether the data at the current position starts with the sparse magic.
+/// The position is left unchanged.
+pub fn is_sparse_image(data: &mut (impl Read + Seek)) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let pos = data.stream_position()?;
+    let ret = match data.read_exact(&mut magic) {
+        Ok(_) => u32::from_le_bytes(magic) == SPARSE_HEADER_MAGIC,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e.into()),
+    };
+    data.seek(SeekFrom::Start(pos))?;
+
+    Ok(ret)
+}
+
+/// Walk the chunk headers of a sparse image starting at the current position,
+/// without reading the payloads
+pub fn parse_sparse_image(data: &mut (impl Read + Seek)) -> Result<SparseImage> {
+    let base = data.stream_position()?;
+
+    let mut buf = [0u8; size_of::<SparseHeader>()];
+    data.read_exact(&mut buf)?;
+    let hdr = bincode::deserialize::<SparseHeader>(&buf)?;
+    if hdr.magic != SPARSE_HEADER_MAGIC {
+        bail!("Not a sparse image");
+    }
+    if hdr.major_version != 1 {
+        bail!("Unsupported sparse image version {}", hdr.major_version);
+    }
+    if (hdr.chunk_hdr_sz as usize) < size_of::<ChunkHeader>() {
+        bail!("Malformed sparse image header");
+    }
+    data.seek(SeekFrom::Start(base + hdr.file_hdr_sz as u64))?;
+
+    let blk_sz = hdr.blk_sz as u64;
+    let mut offset = 0u64;
+    let mut chunks = Vec::new();
+    for i in 0..hdr.total_chunks {
+        let mut buf = [0u8; size_of::<ChunkHeader>()];
+        data.read_exact(&mut buf)?;
+        let chdr = bincode::deserialize::<ChunkHeader>(&buf)?;
+        data.seek(SeekFrom::Current(
+            hdr.chunk_hdr_sz as i64 - size_of::<ChunkHeader>() as i64,
+        ))?;
+
+        let Some(payload_len) = chdr.total_sz.checked_sub(hdr.chunk_hdr_sz as u32) else {
+            bail!("Sparse chunk {} has a bogus size", i);
+        };
+        let payload_len = payload_len as u64;
+        let len = chdr.chunk_sz as u64 * blk_sz;
+        match chdr.chunk_type {
+            CHUNK_TYPE_RAW => {
+                if payload_len != len {
+                    bail!("Sparse chunk {} has a bogus size", i);
+                }
+                chunks.push(SparseChunk {
+                    offset,
+                    len,
+                    data: SparseChunkData::Raw(data.stream_position()?),
+                });
+            }
+            CHUNK_TYPE_FILL => {
+                let mut pattern = [0u8; 4];
+                if payload_len != pattern.len() as u64 {
+                    bail!("Sparse chunk {} has a bogus size", i);
+                }
+                data.read_exact(&mut pattern)?;
+                chunks.push(SparseChunk {
+                    offset,
+                    len,
+                    data: SparseChunkData::Fill(pattern),
+                });
+                offset += len;
+                continue;
+            }
+            CHUNK_TYPE_DONT_CARE | CHUNK_TYPE_CRC32 => (),
+            t => bail!("Unknown sparse chunk type {:#x}", t),
+        }
+
+        data.seek(SeekFrom::Current(payload_len as i64))?;
+        offset += len;
+    }
+
+    if offset != hdr.total_blks as u64 * blk_sz {
+        bail!("Sparse image chunks don't add up to the declared image size");
+    }
+
+    Ok(SparseImage {
+        expanded_len: offset,
+        chunks,
+    })
+}
+
+/// Expands a run of contiguous [SparseChunk]s back into a plain byte stream
+pub struct SparseRunReader<'a, R: Read + Seek> {
+    src: &'a mut R,
+    chunks: &'a [SparseChunk],
+    idx: usize,
+    pos: u64,
+}
+
+impl<'a, R: Read + Seek> SparseRunReader<'a, R> {
+    pub fn new(src: &'a mut R, chunks: &'a [SparseChunk]) -> Self {
+        Self {
+            src,
+            chunks,
+            idx: 0,
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for SparseRunReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while let Some(chunk) = self.chunks.get(self.idx) {
+            if self.pos == chunk.len {
+                self.idx += 1;
+                self.pos = 0;
+                continue;
+            }
+
+            let n = min(buf.len() as u64, chunk.len - self.pos) as usize;
+            let n = match chunk.data {
+                SparseChunkData::Raw(file_offset) => {
+                    self.src.seek(SeekFrom::Start(file_offset + self.pos))?;
+                    match self.src.read(&mut buf[..n])? {
+                        0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                        n => n,
+                    }
+                }
+                SparseChunkData::Fill(pattern) => {
+                    for (i, b) in buf[..n].iter_mut().enumerate() {
+                        *b = pattern[(self.pos as usize + i) % pattern.len()];
+                    }
+                    n
+                }
+            };
+            self.pos += n as u64;
+
+            return Ok(n);
+        }
+
+        Ok(0)
+    }
+}
+
+impl<R: Read + Seek> Seek for SparseRunReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let total = self.chunks.iter().map(|c| c.len).sum::<u64>();
+        let cur = self.chunks[..self.idx].iter().map(|c| c.len).sum::<u64>() + self.pos;
+        let new = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => cur.checked_add_signed(n),
+            SeekFrom::End(n) => total.checked_add_signed(n),
+        }
+        .ok_or(std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+
+        // Find the chunk the new position lands in
+        let mut left = new;
+        self.idx = 0;
+        while let Some(chunk) = self.chunks.get(self.idx)
+            && left >= chunk.len
+        {
+            left -= chunk.len;
+            self.idx += 1;
+        }
+        self.pos = left;
+
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A sparse image with 4-byte blocks, made up of a single chunk
+    fn single_chunk_image(chunk_type: u16, total_sz: u32, payload: &[u8]) -> Vec<u8> {
+        let mut img = Vec::new();
+        for field in [SPARSE_HEADER_MAGIC, 1, 28 | (12 << 16), 4, 1, 1, 0] {
+            img.extend_from_slice(&field.to_le_bytes());
+        }
+        img.extend_from_slice(&(chunk_type as u32).to_le_bytes());
+        img.extend_from_slice(&1u32.to_le_bytes());
+        img.extend_from_slice(&total_sz.to_le_bytes());
+        img.extend_from_slice(payload);
+
+        img
+    }
+
+    fn parse(img: Vec<u8>) -> Result<SparseImage> {
+        parse_sparse_image(&mut Cursor::new(img))
+    }
+
+    #[test]
+    fn fill_chunk() {
+        let img = parse(single_chunk_image(CHUNK_TYPE_FILL, 16, &[1, 2, 3, 4])).unwrap();
+        assert_eq!(img.expanded_len, 4);
+        assert!(matches!(
+            img.chunks[0].data,
+            SparseChunkData::Fill([1, 2, 3, 4])
+        ));
+    }
+
+    #[test]
+    fn bogus_chunk_sizes() {
+        // Smaller than the chunk header itself
+        assert!(parse(single_chunk_image(CHUNK_TYPE_RAW, 8, &[0; 4])).is_err());
+        // Fill chunks carry exactly one 4-byte pattern
+        assert!(parse(single_chunk_image(CHUNK_TYPE_FILL, 14, &[0; 4])).is_err());
+        assert!(parse(single_chunk_image(CHUNK_TYPE_FILL, 20, &[0; 8])).is_err());
+    }
+}
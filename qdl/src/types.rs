@@ -3,10 +3,17 @@
 use std::{
     fmt::Display,
     io::{BufRead, ErrorKind, Read, Write},
+    ops::AddAssign,
     str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{Error, bail};
+use indexmap::IndexMap;
 use owo_colors::OwoColorize;
 
 use crate::firehose_reset;
@@ -19,6 +26,48 @@ pub enum FirehoseStatus {
     Nak = 1,
 }
 
+/// Firehose failures that the caller may want to handle specifically
+#[derive(Debug)]
+pub enum FirehoseError {
+    /// Data read back from the Device doesn't match the Device's own digest of it.
+    /// `sector` is where the first mismatching chunk of the read starts.
+    MalformedData { sector: u64 },
+    /// The Device's oldest supported Firehose protocol version is newer than what we speak
+    ProtocolVersionIncompatibility { device_min: u32, host_max: u32 },
+    /// The user asked to stop, see [QdlChan::take_interrupt]
+    Interrupted,
+    /// The Device refused to carry out `what`, possibly explaining itself beforehand
+    Nak {
+        what: String,
+        reason: Option<String>,
+    },
+}
+
+impl Display for FirehoseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FirehoseError::MalformedData { sector } => write!(
+                f,
+                "Data read from the Device got corrupted (chunk starting at sector {sector})"
+            ),
+            FirehoseError::ProtocolVersionIncompatibility {
+                device_min,
+                host_max,
+            } => write!(
+                f,
+                "Device requires protocol version >= {device_min}, the library only supports up to v{host_max}"
+            ),
+            FirehoseError::Interrupted => write!(f, "Interrupted by the user"),
+            FirehoseError::Nak { what, reason } => match reason {
+                Some(reason) => write!(f, "{what} was NAKed: {reason}"),
+                None => write!(f, "{what} was NAKed"),
+            },
+        }
+    }
+}
+
+impl std::error::Error for FirehoseError {}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum QdlBackend {
     Serial,
@@ -37,6 +86,15 @@ impl FromStr for QdlBackend {
     }
 }
 
+impl Display for QdlBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QdlBackend::Serial => write!(f, "serial"),
+            QdlBackend::Usb => write!(f, "usb"),
+        }
+    }
+}
+
 impl Default for QdlBackend {
     fn default() -> Self {
         match cfg!(target_os = "windows") {
@@ -46,12 +104,56 @@ impl Default for QdlBackend {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Override of the VID/PID pairs used to find a Device on the USB bus
+///
+/// Unset fields fall back to the built-in Qualcomm EDL/Ramdump IDs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UsbIdFilter {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+}
+
+/// Knobs of the USB backend
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UsbOptions {
+    pub ids: UsbIdFilter,
+    /// Submit the whole max-size packets of every write separately from the short tail,
+    /// for controllers that stall on transfers that aren't a multiple of wMaxPacketSize
+    pub align_writes: bool,
+    /// Size of the transfers queued up with the OS, in each direction. Larger ones make for
+    /// better throughput at the cost of memory (the default is 1 MiB)
+    pub buffer_size: Option<usize>,
+    /// Claim this interface number rather than the first one that looks like EDL
+    pub interface: Option<u8>,
+    /// Log the interfaces on offer and the endpoints picked
+    pub verbose: bool,
+    /// Don't announce waiting for a Device to show up
+    pub quiet: bool,
+}
+
+#[derive(Clone, Debug)]
 pub struct FirehoseConfiguration {
     // send/recv are from Host PoV
     pub send_buffer_size: usize,
+    /// Upper bound on [Self::send_buffer_size], regardless of what the Device supports
+    pub max_payload_size: Option<usize>,
     pub recv_buffer_size: usize,
+    /// Sectors pulled from the channel at a time while receiving \<read\> data.
+    /// Unless set, this follows the (negotiated) [Self::send_buffer_size].
+    pub read_chunk_sectors: Option<usize>,
     pub xml_buf_size: usize,
+    /// Protocol version reported by the Device in its \<configure\> response
+    pub protocol_version: Option<u32>,
+    /// Size (in sectors) of each physical partition, as learned through \<getstorageinfo\>
+    /// (or `None` if the Device wouldn't tell), for bounds checking reads
+    pub storage_sectors: IndexMap<u8, Option<u64>>,
+    /// Explanation accompanying the most recent NAK, taken from the error \<log\>s
+    /// preceding it (or the last \<log\>, lacking those)
+    pub last_nak_reason: Option<String>,
+    /// SoC the programmer was built for (e.g. "sdm845"), as reported in \<configure\>
+    pub target_name: Option<String>,
+    /// Storage medium as reported by the Device in \<configure\> (e.g. "UFS")
+    pub memory_name: Option<String>,
 
     pub storage_sector_size: usize,
     pub storage_type: FirehoseStorageType,
@@ -59,32 +161,105 @@ pub struct FirehoseConfiguration {
     pub bypass_storage: bool,
     pub hash_packets: bool,
     pub read_back_verify: bool,
+    /// How many times a NAKed \<program\> is re-attempted
+    pub program_retries: u32,
+    /// Compare the Device's SHA256 digest of every \<program\>ed range against the Host's
+    pub verify_sha256: bool,
+    /// Pads the last sector of a \<program\> when the data ends partway through it.
+    /// Any whole sectors past the end of the data (e.g. all of them, for zero-fills
+    /// without any data) are zeroed regardless.
+    pub fill_byte: u8,
 
     pub backend: QdlBackend,
+    /// Delay (in seconds) before a requested reset takes effect
+    pub reset_delay: u32,
     pub skip_firehose_log: bool,
+    /// Least severe \<log\> messages that are still printed (unless [Self::skip_firehose_log])
+    pub log_level: FirehoseLogLevel,
     pub verbose_firehose: bool,
+    /// Suppress non-essential (decorative) output
+    pub quiet: bool,
 }
 
 impl Default for FirehoseConfiguration {
     fn default() -> Self {
         Self {
             send_buffer_size: 1024 * 1024,
+            max_payload_size: None,
             recv_buffer_size: 4096,
+            read_chunk_sectors: None,
             xml_buf_size: 4096,
+            protocol_version: None,
+            last_nak_reason: None,
+            storage_sectors: IndexMap::new(),
+            target_name: None,
+            memory_name: None,
             storage_sector_size: 512,
             storage_type: FirehoseStorageType::Emmc,
             bypass_storage: true,
             hash_packets: false,
             read_back_verify: false,
+            program_retries: 0,
+            verify_sha256: false,
+            fill_byte: 0,
             backend: QdlBackend::default(),
+            reset_delay: 0,
             skip_firehose_log: true,
+            log_level: FirehoseLogLevel::Info,
             verbose_firehose: false,
+            quiet: false,
         }
     }
 }
 pub trait QdlChan: BufRead + Write {
     fn fh_config(&self) -> &FirehoseConfiguration;
     fn mut_fh_config(&mut self) -> &mut FirehoseConfiguration;
+
+    /// Where to save a copy of every XML packet received from the Device, if anywhere
+    fn fh_log_sink(&mut self) -> Option<&mut (dyn Write + Send)> {
+        None
+    }
+
+    /// Where to report the progress of storage transfers, if anywhere
+    fn progress_sink(&mut self) -> Option<&mut ProgressCallback> {
+        None
+    }
+
+    /// Per-partition tally of the writes skipped due to [FirehoseConfiguration::bypass_storage], if kept
+    fn bypassed_writes(&mut self) -> Option<&mut IndexMap<String, TransferStats>> {
+        None
+    }
+
+    /// Whether the user asked to stop (e.g. with Ctrl-C), clearing the request.
+    /// Checked before sending every Firehose command, so that the Device is never
+    /// abandoned halfway through a transfer and can still be reset.
+    fn take_interrupt(&mut self) -> bool {
+        false
+    }
+
+    /// Told whether the Device is done with the last command and waiting for the next
+    /// one, which is when it's safe for someone else (e.g. a keepalive) to step in.
+    /// A command is in flight from the moment it's sent until a response that doesn't
+    /// switch to raw mode comes back. Once reset, the Device is never idle again.
+    fn set_idle(&mut self, _idle: bool) {}
+}
+
+/// State of a Firehose channel, shared with whoever wants to use it in between commands
+#[derive(Debug)]
+pub struct ChannelActivity {
+    /// See [QdlChan::set_idle]
+    pub idle: bool,
+    /// When `idle` last changed
+    pub since: Instant,
+}
+
+impl Default for ChannelActivity {
+    fn default() -> Self {
+        ChannelActivity {
+            idle: false,
+            since: Instant::now(),
+        }
+    }
 }
 
 pub trait QdlReadWrite: BufRead + Write + Send + Sync {}
@@ -96,6 +271,16 @@ where
 {
     pub rw: Box<T>,
     pub fh_cfg: FirehoseConfiguration,
+    /// See [QdlChan::fh_log_sink]
+    pub fh_log: Option<Box<dyn Write + Send>>,
+    /// See [QdlChan::progress_sink]
+    pub progress: Option<Box<ProgressCallback>>,
+    /// See [QdlChan::bypassed_writes]
+    pub bypassed: IndexMap<String, TransferStats>,
+    /// See [QdlChan::take_interrupt]
+    pub interrupt: Option<Arc<AtomicBool>>,
+    /// See [QdlChan::set_idle]
+    pub activity: Option<Arc<Mutex<ChannelActivity>>>,
     pub reset_on_drop: bool,
 }
 
@@ -145,6 +330,39 @@ where
     fn mut_fh_config(&mut self) -> &mut FirehoseConfiguration {
         &mut self.fh_cfg
     }
+
+    fn fh_log_sink(&mut self) -> Option<&mut (dyn Write + Send)> {
+        match &mut self.fh_log {
+            Some(f) => Some(f.as_mut()),
+            None => None,
+        }
+    }
+
+    fn progress_sink(&mut self) -> Option<&mut ProgressCallback> {
+        match &mut self.progress {
+            Some(f) => Some(f.as_mut()),
+            None => None,
+        }
+    }
+
+    fn bypassed_writes(&mut self) -> Option<&mut IndexMap<String, TransferStats>> {
+        Some(&mut self.bypassed)
+    }
+
+    fn take_interrupt(&mut self) -> bool {
+        self.interrupt
+            .as_ref()
+            .is_some_and(|i| i.swap(false, Ordering::Relaxed))
+    }
+
+    fn set_idle(&mut self, idle: bool) {
+        if let Some(activity) = &self.activity {
+            // Blocks while someone else is using the channel
+            let mut a = activity.lock().unwrap();
+            a.idle = idle;
+            a.since = Instant::now();
+        }
+    }
 }
 
 impl<T> Drop for QdlDevice<T>
@@ -160,7 +378,8 @@ where
                 "failed".bright_red(),
                 "edl".bright_yellow()
             );
-            let _ = firehose_reset(self, &FirehoseResetMode::ResetToEdl, 0);
+            let delay = self.fh_cfg.reset_delay;
+            let _ = firehose_reset(self, &FirehoseResetMode::ResetToEdl, delay);
         }
     }
 }
@@ -185,7 +404,10 @@ impl FromStr for FirehoseStorageType {
             "nand" => Ok(FirehoseStorageType::Nand),
             "nvme" => Ok(FirehoseStorageType::Nvme),
             "spinor" => Ok(FirehoseStorageType::Spinor),
-            _ => bail!("Unknown storage type"),
+            _ => bail!(
+                "Unknown storage type \"{}\", expected one of emmc/ufs/nand/nvme/spinor",
+                input
+            ),
         }
     }
 }
@@ -202,11 +424,155 @@ impl Display for FirehoseStorageType {
     }
 }
 
+/// Storage medium parameters, as reported by \<getstorageinfo\>
+#[derive(Clone, Debug, Default)]
+pub struct StorageInfo {
+    pub total_blocks: u64,
+    pub block_size: usize,
+    pub page_size: Option<usize>,
+    pub num_physical: Option<u8>,
+    pub manufacturer_id: Option<u32>,
+    pub serial_num: Option<u64>,
+    pub fw_version: Option<String>,
+    pub mem_type: Option<String>,
+    pub prod_name: Option<String>,
+}
+
+impl StorageInfo {
+    /// Size of the unit storage is addressed in. NAND is accessed page by page,
+    /// with `block_size` describing the (much larger) erase block.
+    pub fn sector_size(&self, storage_type: FirehoseStorageType) -> Option<usize> {
+        match storage_type {
+            FirehoseStorageType::Nand => self.page_size,
+            _ => Some(self.block_size),
+        }
+        .filter(|n| *n > 0)
+    }
+
+    /// Size of the storage in units of `sector_size` bytes
+    pub fn total_sectors(&self, sector_size: usize) -> u64 {
+        self.total_blocks * self.block_size as u64 / sector_size.max(1) as u64
+    }
+
+    /// Number of pages within an erase block (NAND only)
+    pub fn pages_per_block(&self) -> Option<usize> {
+        self.page_size
+            .filter(|p| *p > 0 && self.block_size > *p && self.block_size.is_multiple_of(*p))
+            .map(|p| self.block_size / p)
+    }
+}
+
+impl Display for StorageInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn or_unknown<T: Display>(v: &Option<T>) -> String {
+            v.as_ref().map_or("unknown".to_owned(), |v| v.to_string())
+        }
+
+        writeln!(f, "Total blocks: {}", self.total_blocks)?;
+        writeln!(f, "Block size: {} bytes", self.block_size)?;
+        writeln!(
+            f,
+            "Capacity: {} MiB",
+            self.total_blocks * self.block_size as u64 / 1024 / 1024
+        )?;
+        writeln!(f, "Page size: {}", or_unknown(&self.page_size))?;
+        if let Some(n) = self.pages_per_block() {
+            writeln!(f, "Pages per block: {n}")?;
+        }
+        writeln!(f, "Physical partitions: {}", or_unknown(&self.num_physical))?;
+        writeln!(
+            f,
+            "Manufacturer ID: {}",
+            self.manufacturer_id
+                .map_or("unknown".to_owned(), |v| format!("{v:#x}"))
+        )?;
+        writeln!(
+            f,
+            "Serial number: {}",
+            self.serial_num
+                .map_or("unknown".to_owned(), |v| format!("{v:#x}"))
+        )?;
+        writeln!(f, "Firmware version: {}", or_unknown(&self.fw_version))?;
+        writeln!(f, "Memory type: {}", or_unknown(&self.mem_type))?;
+        write!(f, "Product name: {}", or_unknown(&self.prod_name))
+    }
+}
+
+/// Statistics of a completed storage transfer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransferStats {
+    pub bytes: u64,
+    pub sectors: u64,
+    pub elapsed: Duration,
+}
+
+impl TransferStats {
+    /// Average throughput, in bytes per second
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl AddAssign for TransferStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.bytes += rhs.bytes;
+        self.sectors += rhs.sectors;
+        self.elapsed += rhs.elapsed;
+    }
+}
+
+impl Display for TransferStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes ({} sectors) in {:.2}s, {:.2} MiB/s",
+            self.bytes,
+            self.sectors,
+            self.elapsed.as_secs_f64(),
+            self.throughput() / 1024.0 / 1024.0
+        )
+    }
+}
+
+/// Progress update of a storage transfer, see [QdlChan::progress_sink]
+#[derive(Clone, Copy, Debug)]
+pub enum ProgressEvent<'a> {
+    /// A transfer of `total_bytes` (to/from partition `label`, if known) has begun
+    Start {
+        label: Option<&'a str>,
+        total_bytes: u64,
+    },
+    /// `bytes_done` out of `total_bytes` have been transferred so far
+    Advance { bytes_done: u64, total_bytes: u64 },
+    /// The transfer is complete
+    Finish,
+}
+
+/// Receiver of [ProgressEvent]s
+pub type ProgressCallback = dyn FnMut(ProgressEvent) + Send;
+
 /// List of supported reboot modes, supplied to the \<reset\> command
 pub enum FirehoseResetMode {
     ResetToEdl,
     Reset,
     Off,
+    /// Reboot into fastboot. Firehose can't do this by itself, it's up to an Android
+    /// bootloader (e.g. ABL or LK) honoring [Self::bootloader_command], left in misc.
+    Fastboot,
+    /// Reboot into recovery, see [Self::Fastboot]
+    Recovery,
+}
+
+impl FirehoseResetMode {
+    /// Command to leave in the misc partition's bootloader message for the mode to take
+    /// effect, see [crate::firehose_write_bootloader_message]
+    pub fn bootloader_command(&self) -> Option<&'static str> {
+        match self {
+            FirehoseResetMode::Fastboot => Some("bootonce-bootloader"),
+            FirehoseResetMode::Recovery => Some("boot-recovery"),
+            _ => None,
+        }
+    }
 }
 
 impl FromStr for FirehoseResetMode {
@@ -217,6 +583,8 @@ impl FromStr for FirehoseResetMode {
             "edl" => Ok(FirehoseResetMode::ResetToEdl),
             "system" => Ok(FirehoseResetMode::Reset),
             "off" => Ok(FirehoseResetMode::Off),
+            "fastboot" => Ok(FirehoseResetMode::Fastboot),
+            "recovery" => Ok(FirehoseResetMode::Recovery),
             _ => Err(std::io::Error::from(ErrorKind::InvalidInput).into()),
         }
     }
@@ -228,6 +596,44 @@ impl Display for FirehoseResetMode {
             FirehoseResetMode::ResetToEdl => write!(f, "edl"),
             FirehoseResetMode::Reset => write!(f, "system"),
             FirehoseResetMode::Off => write!(f, "off"),
+            FirehoseResetMode::Fastboot => write!(f, "fastboot"),
+            FirehoseResetMode::Recovery => write!(f, "recovery"),
+        }
+    }
+}
+
+/// Severity of a \<log\> message, as denoted by the prefix of its value (e.g. "ERROR: ")
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub enum FirehoseLogLevel {
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl FirehoseLogLevel {
+    /// Classify a \<log\> message, anything not explicitly a warning/error counts as info
+    pub fn of_message(msg: &str) -> Self {
+        let msg = msg.trim_start();
+        if msg.starts_with("ERROR") {
+            FirehoseLogLevel::Error
+        } else if msg.starts_with("WARN") {
+            FirehoseLogLevel::Warn
+        } else {
+            FirehoseLogLevel::Info
+        }
+    }
+}
+
+impl FromStr for FirehoseLogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "info" => Ok(FirehoseLogLevel::Info),
+            "warn" => Ok(FirehoseLogLevel::Warn),
+            "error" => Ok(FirehoseLogLevel::Error),
+            _ => Err(std::io::Error::from(ErrorKind::InvalidInput).into()),
         }
     }
 }
@@ -1,36 +1,114 @@
 // SPDX-License-Identifier: BSD-3-Clause
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
-use anyhow::{Context, Result, bail};
+use anyhow::{Result, anyhow, bail};
 use nusb::{
-    self, Device, DeviceInfo, MaybeFuture,
+    self, DeviceInfo, MaybeFuture,
     io::{EndpointRead, EndpointWrite},
 };
+#[cfg(target_os = "linux")]
+use std::{fs, path::PathBuf};
 use std::{
     io::{BufRead, Error, ErrorKind, Read, Write},
-    time::Duration,
+    thread::sleep,
+    time::{Duration, Instant},
 };
 
-use crate::types::QdlReadWrite;
+use crate::types::{QdlReadWrite, UsbIdFilter, UsbOptions};
 
 pub struct QdlUsbConfig {
     _dev: nusb::Device,
     reader: EndpointRead<nusb::transfer::Bulk>,
     writer: EndpointWrite<nusb::transfer::Bulk>,
+    /// wMaxPacketSize of the OUT endpoint
+    max_packet_size: usize,
+    align_writes: bool,
+    buffer_size: usize,
+    /// Interface number to claim instead of the autodetected one
+    interface: Option<u8>,
+    verbose: bool,
     buf: Vec<u8>,
     pos: usize,
     cap: usize,
+    // Used to find the Device again, should it re-enumerate
+    serial_no: Option<String>,
+    ids: UsbIdFilter,
+    timeout: Duration,
+}
+
+impl QdlUsbConfig {
+    /// Re-open the Device after it dropped off the bus (e.g. re-enumerated after
+    /// switching from Sahara to Firehose), restoring the endpoints
+    fn reconnect(&mut self) -> Result<(), std::io::Error> {
+        let deadline = Instant::now() + USB_REENUMERATION_TIMEOUT;
+
+        loop {
+            if let Ok(Some(dev_info)) = find_edl_device(self.serial_no.as_deref(), self.ids)
+                && let Ok((dev, rd, wr, mps)) = open_edl_device(
+                    &dev_info,
+                    self.timeout,
+                    self.buffer_size,
+                    self.interface,
+                    self.verbose,
+                )
+            {
+                self._dev = dev;
+                self.reader = rd;
+                self.writer = wr;
+                self.max_packet_size = mps;
+                self.pos = 0;
+                self.cap = 0;
+                return Ok(());
+            }
+
+            if Instant::now() >= deadline {
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    "Device disconnected and didn't come back",
+                ));
+            }
+            sleep(USB_WAIT_POLL_INTERVAL);
+        }
+    }
+
+    /// Run `op`, retrying it once on a freshly re-opened Device if the old one went away
+    fn with_reconnect<R>(
+        &mut self,
+        mut op: impl FnMut(&mut Self) -> Result<R, std::io::Error>,
+    ) -> Result<R, std::io::Error> {
+        match op(self) {
+            Err(e) if e.kind() == ErrorKind::ConnectionAborted => {
+                self.reconnect()?;
+                op(self)
+            }
+            r => r,
+        }
+    }
 }
 
-// TODO: timeouts?
 impl Write for QdlUsbConfig {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
-        let n = self.writer.write(buf);
-        self.writer.submit_end();
-        n
+        self.with_reconnect(|s| {
+            // Send the whole packets as a transfer of their own, followed by the short tail
+            let whole = match s.align_writes {
+                true => buf.len() - buf.len() % s.max_packet_size,
+                false => 0,
+            };
+            if whole > 0 && whole < buf.len() {
+                s.writer.write_all(&buf[..whole])?;
+                s.writer.submit();
+                s.writer.write_all(&buf[whole..])?;
+                s.writer.submit_end();
+                return Ok(buf.len());
+            }
+
+            let n = s.writer.write(buf);
+            s.writer.submit_end();
+            n
+        })
     }
 
     fn flush(&mut self) -> Result<(), std::io::Error> {
-        self.writer.flush()
+        self.with_reconnect(|s| s.writer.flush())
     }
 }
 impl Read for QdlUsbConfig {
@@ -43,7 +121,7 @@ impl Read for QdlUsbConfig {
             return Ok(n);
         }
         // Otherwise, read directly from USB
-        self.reader.read(out)
+        self.with_reconnect(|s| s.reader.read(out))
     }
 }
 
@@ -55,12 +133,10 @@ impl BufRead for QdlUsbConfig {
             if self.buf.is_empty() {
                 self.buf.resize(4096, 0);
             }
-            match self.reader.read(&mut self.buf) {
-                Ok(n) => {
-                    self.cap = n;
-                }
-                Err(e) => return Err(e),
-            }
+            let mut buf = std::mem::take(&mut self.buf);
+            let ret = self.with_reconnect(|s| s.reader.read(&mut buf));
+            self.buf = buf;
+            self.cap = ret?;
         }
         Ok(&self.buf[self.pos..self.cap])
     }
@@ -72,99 +148,334 @@ impl BufRead for QdlUsbConfig {
 
 impl QdlReadWrite for QdlUsbConfig {}
 
+const USB_DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const USB_DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+/// Keep Firehose XML packets (4 kiB max, usually) within a single transfer
+const USB_MIN_BUFFER_SIZE: usize = 4096;
+const USB_WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const USB_REENUMERATION_TIMEOUT: Duration = Duration::from_secs(10);
+const USB_CLAIM_RETRIES: u32 = 4;
+const USB_CLAIM_RETRY_DELAY: Duration = Duration::from_millis(200);
 const USB_VID_QCOM: u16 = 0x05c6;
 const USB_PID_EDL: [u16; 2] = [0x9008 /* EDL */, 0x900e /* Ramdump */];
 const INTF_DESC_PROTO_CODES: [u8; 3] = [0x10, 0x11, 0xFF];
 
+/// Basic information about a Device exposed in EDL or Ramdump mode
+#[derive(Clone, Debug)]
+pub struct EdlDeviceInfo {
+    pub bus_id: String,
+    pub device_address: u8,
+    pub product: String,
+    pub serial_no: Option<String>,
+    pub pid: u16,
+}
+
+/// Extract the serial number from a product string like "QUSB__BULK_CID:0402_SN:1A2B3C4D"
+fn parse_serial_no(prod_str: &str) -> Option<&str> {
+    prod_str
+        .find("_SN:")
+        .map(|idx| &prod_str[idx + "_SN:".len()..])
+}
+
+fn list_edl_device_infos(ids: UsbIdFilter) -> Result<impl Iterator<Item = DeviceInfo>> {
+    Ok(nusb::list_devices().wait()?.filter(move |d| {
+        d.vendor_id() == ids.vid.unwrap_or(USB_VID_QCOM)
+            && match ids.pid {
+                Some(pid) => d.product_id() == pid,
+                None => USB_PID_EDL.contains(&d.product_id()),
+            }
+    }))
+}
+
+/// Enumerate all Devices in EDL/Ramdump mode, without claiming them
+pub fn list_edl_devices(ids: UsbIdFilter) -> Result<Vec<EdlDeviceInfo>> {
+    Ok(list_edl_device_infos(ids)?
+        .map(|d| EdlDeviceInfo {
+            bus_id: d.bus_id().to_owned(),
+            device_address: d.device_address(),
+            product: d.product_string().unwrap_or("").to_owned(),
+            serial_no: d
+                .product_string()
+                .and_then(parse_serial_no)
+                .map(|sn| sn.to_owned()),
+            pid: d.product_id(),
+        })
+        .collect())
+}
+
+/// Look a Device up by its serial number. An exact (case-insensitive) match wins,
+/// otherwise `serial_no` may be any part of it, as long as only one Device matches.
 fn find_usb_handle_by_sn(
-    devices: &mut dyn Iterator<Item = DeviceInfo>,
-    serial_no: String,
-) -> Result<Device> {
-    let mut dev: Option<DeviceInfo> = None;
+    devices: impl Iterator<Item = DeviceInfo>,
+    serial_no: &str,
+) -> Result<Option<DeviceInfo>> {
+    let needle = serial_no.to_ascii_uppercase();
+    let mut partial = Vec::new();
 
     for d in devices {
-        // let prod_str = dh.read_product_string_ascii(&d.device_descriptor().unwrap())?;
-        if let Some(prod_str) = d.product_string() {
-            let sn = &prod_str[prod_str.find("_SN:").unwrap() + "_SN:".len()..];
-            if sn.eq_ignore_ascii_case(&serial_no) {
-                dev = Some(d);
-                break;
-            }
+        let Some(sn) = d.product_string().and_then(parse_serial_no) else {
+            continue;
+        };
+        if sn.eq_ignore_ascii_case(serial_no) {
+            return Ok(Some(d));
         }
+        if sn.to_ascii_uppercase().contains(&needle) {
+            partial.push(d);
+        }
+    }
+
+    if partial.len() > 1 {
+        bail!(
+            "Serial number {} matches multiple devices ({}), please be more specific",
+            serial_no,
+            partial
+                .iter()
+                .filter_map(|d| d.product_string().and_then(parse_serial_no))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
     }
 
-    match dev {
-        Some(h) => Ok(h.open().wait()?),
-        None => bail!(
-            "Found no devices in EDL mode with serial number {}",
-            serial_no
-        ),
+    Ok(partial.pop())
+}
+
+fn find_edl_device(serial_no: Option<&str>, ids: UsbIdFilter) -> Result<Option<DeviceInfo>> {
+    let mut devices = list_edl_device_infos(ids)?;
+
+    match serial_no {
+        Some(s) => find_usb_handle_by_sn(devices, s),
+        None => Ok(devices.next()),
     }
 }
 
-pub fn setup_usb_device(serial_no: Option<String>) -> Result<QdlUsbConfig> {
-    let mut devices = nusb::list_devices()
-        .wait()
-        .unwrap()
-        .filter(|d| d.vendor_id() == USB_VID_QCOM && USB_PID_EDL.contains(&d.product_id()));
+/// Open a Device in EDL/Ramdump mode
+///
+/// If `wait` is specified and no matching Device is present yet, the bus is polled
+/// until one shows up or the deadline passes.
+pub fn setup_usb_device(
+    serial_no: Option<String>,
+    timeout: Option<Duration>,
+    wait: Option<Duration>,
+    opts: UsbOptions,
+) -> Result<QdlUsbConfig> {
+    let ids = opts.ids;
+    let buffer_size = opts.buffer_size.unwrap_or(USB_DEFAULT_BUFFER_SIZE);
+    if buffer_size < USB_MIN_BUFFER_SIZE {
+        bail!("The USB buffer size must be at least {USB_MIN_BUFFER_SIZE} bytes");
+    }
+
+    let deadline = wait.map(|w| Instant::now() + w);
+    let mut polls = 0u32;
 
-    let dev = match serial_no {
-        Some(s) => find_usb_handle_by_sn(&mut devices, s)?,
-        None => {
-            let Some(d) = devices.next() else {
-                bail!("Found no devices in EDL mode")
-            };
-            d.open().wait()?
+    let dev_info = loop {
+        if let Some(d) = find_edl_device(serial_no.as_deref(), ids)? {
+            break d;
+        }
+
+        match deadline {
+            Some(d) if Instant::now() < d => {
+                // Let the user know we're alive every couple of seconds
+                if polls.is_multiple_of(10) && !opts.quiet {
+                    println!("Waiting for device...");
+                }
+                polls += 1;
+                sleep(USB_WAIT_POLL_INTERVAL);
+            }
+            _ => match serial_no {
+                Some(s) => bail!("Found no devices in EDL mode with serial number {}", s),
+                None => bail!("Found no devices in EDL mode"),
+            },
         }
     };
+    let timeout = timeout.unwrap_or(USB_DEFAULT_TIMEOUT);
+    let (dev, reader, writer, max_packet_size) = open_edl_device(
+        &dev_info,
+        timeout,
+        buffer_size,
+        opts.interface,
+        opts.verbose,
+    )?;
+
+    Ok(QdlUsbConfig {
+        _dev: dev,
+        reader,
+        writer,
+        max_packet_size,
+        align_writes: opts.align_writes,
+        buffer_size,
+        interface: opts.interface,
+        verbose: opts.verbose,
+        buf: Vec::new(),
+        pos: 0,
+        cap: 0,
+        // Reconnect to this very Device, even if only a part of its S/N was given
+        serial_no: dev_info
+            .product_string()
+            .and_then(parse_serial_no)
+            .map(|sn| sn.to_owned())
+            .or(serial_no),
+        ids,
+        timeout,
+    })
+}
+
+/// Whether an endpoint is a bulk one going in the given direction
+fn is_bulk_ep(e: &nusb::descriptors::EndpointDescriptor, dir: nusb::transfer::Direction) -> bool {
+    e.direction() == dir && e.transfer_type() == nusb::descriptors::TransferType::Bulk
+}
+
+/// Claim the EDL interface of a Device and set up its bulk endpoints.
+/// The OUT endpoint's wMaxPacketSize is returned alongside.
+///
+/// Unless `interface` picks one explicitly, the first vendor-specific interface with the
+/// protocol code of an EDL/Ramdump one is used.
+fn open_edl_device(
+    dev_info: &DeviceInfo,
+    timeout: Duration,
+    buffer_size: usize,
+    interface: Option<u8>,
+    verbose: bool,
+) -> Result<(
+    nusb::Device,
+    EndpointRead<nusb::transfer::Bulk>,
+    EndpointWrite<nusb::transfer::Bulk>,
+    usize,
+)> {
+    use nusb::transfer::Direction;
+
+    let dev = dev_info.open().wait()?;
 
-    // TODO: is there always precisely one interface like this?
     let cfg_desc = dev.active_configuration()?;
-    let intf_desc = cfg_desc
-        .interface_alt_settings()
-        .find(|d| {
-            d.class() == 0xFF
-                && d.subclass() == 0xFF
-                && INTF_DESC_PROTO_CODES.contains(&d.protocol())
-                && d.num_endpoints() >= 2
-        })
-        .ok_or::<anyhow::Error>(Error::from(ErrorKind::NotFound).into())?;
+    if verbose {
+        for d in cfg_desc.interface_alt_settings() {
+            println!(
+                "USB: Interface {} alt {}: class {:02x}/{:02x}/{:02x}, endpoints [{}]",
+                d.interface_number(),
+                d.alternate_setting(),
+                d.class(),
+                d.subclass(),
+                d.protocol(),
+                d.endpoints()
+                    .map(|e| format!("0x{:02x} {:?}", e.address(), e.transfer_type()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+
+    let intf_desc = match interface {
+        Some(n) => cfg_desc
+            .interface_alt_settings()
+            .find(|d| {
+                d.interface_number() == n
+                    && d.endpoints().any(|e| is_bulk_ep(&e, Direction::In))
+                    && d.endpoints().any(|e| is_bulk_ep(&e, Direction::Out))
+            })
+            .ok_or(anyhow!(
+                "Interface {n} doesn't exist or lacks a pair of bulk endpoints"
+            ))?,
+        // TODO: is there always precisely one interface like this?
+        None => cfg_desc
+            .interface_alt_settings()
+            .find(|d| {
+                d.class() == 0xFF
+                    && d.subclass() == 0xFF
+                    && INTF_DESC_PROTO_CODES.contains(&d.protocol())
+                    && d.num_endpoints() >= 2
+            })
+            .ok_or::<anyhow::Error>(Error::from(ErrorKind::NotFound).into())?,
+    };
 
     let in_ep = intf_desc
         .endpoints()
-        .find(|e| {
-            e.direction() == nusb::transfer::Direction::In
-                && e.transfer_type() == nusb::descriptors::TransferType::Bulk
-        })
-        .unwrap()
+        .find(|e| is_bulk_ep(e, Direction::In))
+        .ok_or(anyhow!("The EDL interface has no bulk IN endpoint"))?
         .address();
-    let out_ep = intf_desc
+    let out_ep_desc = intf_desc
         .endpoints()
-        .find(|e| {
-            e.direction() == nusb::transfer::Direction::Out
-                && e.transfer_type() == nusb::descriptors::TransferType::Bulk
-        })
-        .unwrap()
-        .address();
+        .find(|e| is_bulk_ep(e, Direction::Out))
+        .ok_or(anyhow!("The EDL interface has no bulk OUT endpoint"))?;
+    let (out_ep, max_packet_size) = (out_ep_desc.address(), out_ep_desc.max_packet_size());
 
-    // Make sure we can actually poke at the device
-    let intf = dev
-        .detach_and_claim_interface(intf_desc.interface_number())
-        .wait()
-        .with_context(|| format!("Couldn't claim interface{}", intf_desc.interface_number()))?;
+    if verbose {
+        println!(
+            "USB: Using interface {} alt {}, IN endpoint 0x{:02x}, OUT endpoint 0x{:02x} (wMaxPacketSize {})",
+            intf_desc.interface_number(),
+            intf_desc.alternate_setting(),
+            in_ep,
+            out_ep,
+            max_packet_size
+        );
+    }
 
-    let mut rd = intf.endpoint(in_ep)?.reader(1024 * 1024);
-    let mut wr = intf.endpoint(out_ep)?.writer(1024 * 1024);
+    // Make sure we can actually poke at the device. Other programs (e.g. ModemManager probing
+    // what looks like a new modem) tend to hold onto it for a brief moment, give them a chance
+    let intf_num = intf_desc.interface_number();
+    let mut attempt = 0;
+    let intf = loop {
+        match dev.detach_and_claim_interface(intf_num).wait() {
+            Ok(intf) => break intf,
+            Err(_) if attempt < USB_CLAIM_RETRIES => {
+                sleep(USB_CLAIM_RETRY_DELAY * 2u32.pow(attempt));
+                attempt += 1;
+            }
+            Err(e) => {
+                let holders = usb_device_holders(dev_info);
+                bail!(
+                    "Couldn't claim interface {} ({}), it's likely held by {}. \
+                    Stop it, unbind the kernel driver from the interface, or try --backend serial",
+                    intf_num,
+                    e,
+                    match holders.is_empty() {
+                        true => "another program".to_owned(),
+                        false => holders.join(", "),
+                    }
+                );
+            }
+        }
+    };
 
-    rd.set_read_timeout(Duration::from_secs(10));
-    wr.set_write_timeout(Duration::from_secs(10));
+    let mut rd = intf.endpoint(in_ep)?.reader(buffer_size);
+    let mut wr = intf.endpoint(out_ep)?.writer(buffer_size);
 
-    Ok(QdlUsbConfig {
-        _dev: dev,
-        reader: rd,
-        writer: wr,
-        buf: Vec::new(),
-        pos: 0,
-        cap: 0,
-    })
+    rd.set_read_timeout(timeout);
+    wr.set_write_timeout(timeout);
+
+    Ok((dev, rd, wr, max_packet_size))
+}
+
+/// Programs (other than this one) with the Device node open, as "name (PID n)"
+#[cfg(target_os = "linux")]
+fn usb_device_holders(dev_info: &DeviceInfo) -> Vec<String> {
+    let node = PathBuf::from(format!(
+        "/dev/bus/usb/{:03}/{:03}",
+        dev_info.busnum(),
+        dev_info.device_address()
+    ));
+    let Ok(procs) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    procs
+        .flatten()
+        .filter_map(|p| {
+            let pid = p.file_name().to_str()?.parse::<u32>().ok()?;
+            if pid == std::process::id() {
+                return None;
+            }
+            fs::read_dir(p.path().join("fd"))
+                .ok()?
+                .flatten()
+                .any(|fd| fs::read_link(fd.path()).is_ok_and(|l| l == node))
+                .then(|| {
+                    let name = fs::read_to_string(p.path().join("comm")).unwrap_or_default();
+                    format!("{} (PID {})", name.trim(), pid)
+                })
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn usb_device_holders(_dev_info: &DeviceInfo) -> Vec<String> {
+    Vec::new()
 }
@@ -1,15 +1,15 @@
 // SPDX-License-Identifier: BSD-3-Clause
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
 use anyhow::{Result, bail};
 
 use clap::Parser;
 use qdl::{
     self,
-    sahara::{SaharaMode, sahara_reset, sahara_run},
+    sahara::{RamdumpOptions, SaharaMode, sahara_reset, sahara_run},
     setup_target_device,
-    types::{FirehoseConfiguration, QdlBackend, QdlDevice},
+    types::{FirehoseConfiguration, QdlBackend, QdlDevice, UsbOptions},
 };
 
 #[derive(Parser, Debug)]
@@ -24,10 +24,38 @@ struct Args {
     #[arg()]
     regions_to_dump: Vec<String>,
 
+    #[arg(
+        short,
+        long,
+        value_name = "DIR",
+        default_value = "ramdump",
+        help = "Where to store the dumped regions"
+    )]
+    output_dir: String,
+
+    #[arg(short, long, help = "Only list the available regions, don't dump any")]
+    list: bool,
+
     // Only applies to the USB backend
-    #[arg(long)]
+    #[arg(long, help = "Serial number of the device, or a unique part of it")]
     serial_no: Option<String>,
 
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Read/write timeout of the device channel [default: backend-specific]"
+    )]
+    timeout: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        num_args = 0..=1,
+        default_missing_value = "60",
+        help = "Wait for the device to show up (USB only) [default deadline: 60]"
+    )]
+    wait: Option<u64>,
+
     #[arg(long, default_value = "false")]
     verbose_sahara: bool,
 }
@@ -39,7 +67,15 @@ pub fn main() -> Result<()> {
         None => QdlBackend::default(),
     };
 
-    let rw_channel = match setup_target_device(backend, args.serial_no, args.dev_path) {
+    let rw_channel = match setup_target_device(
+        backend,
+        args.serial_no,
+        args.dev_path,
+        args.timeout.map(Duration::from_secs),
+        args.wait.map(Duration::from_secs),
+        None,
+        UsbOptions::default(),
+    ) {
         Ok(c) => c,
         Err(e) => bail!("Couldn't set up device: {}", e.to_string()),
     };
@@ -47,6 +83,11 @@ pub fn main() -> Result<()> {
     let mut qdl_dev = QdlDevice {
         rw: rw_channel,
         fh_cfg: FirehoseConfiguration::default(),
+        fh_log: None,
+        progress: None,
+        bypassed: Default::default(),
+        interrupt: None,
+        activity: None,
         reset_on_drop: false,
     };
 
@@ -55,7 +96,11 @@ pub fn main() -> Result<()> {
         SaharaMode::MemoryDebug,
         None,
         &mut [],
-        args.regions_to_dump,
+        &RamdumpOptions {
+            regions: args.regions_to_dump,
+            output_dir: PathBuf::from(args.output_dir),
+            list_only: args.list,
+        },
         args.verbose_sahara,
     )?;
 